@@ -0,0 +1,32 @@
+//! Feed arbitrary data to everything that parses the Signed Package Format.
+//! None of it may panic, and only untouched packages may decrypt.
+//!
+//! Run with `cargo fuzz run spf_parse` from libmangrove/
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use libmangrove::crypt::{debug_dump_package, decrypt_package, decrypt_package_stream, is_signed_package, package_fingerprint, PublicKey};
+use libmangrove::spf::parse_package;
+
+fuzz_target!(|data: &[u8]| {
+    // the public key used by the libmangrove tests
+    let key = PublicKey::from_anonymous(&"LQaHiMmaBwE76yVoLubxtl5RKgG2blBrQkREWmtmgT8=".to_string()).unwrap();
+
+    let parsed = parse_package(data);
+    assert_eq!(parsed.is_ok(), is_signed_package(data.to_vec()));
+    let _ = package_fingerprint(data);
+    let _ = debug_dump_package(data.to_vec(), Some(&key));
+    let decrypted = decrypt_package(&key, data);
+    if decrypted.is_ok() {
+        assert!(parsed.is_ok());
+    }
+    // streaming must accept exactly the same packages
+    let mut streamed: Vec<u8> = vec![];
+    let stream = decrypt_package_stream(&key, data, &mut streamed);
+    assert_eq!(stream.is_ok(), decrypted.is_ok());
+    if let Ok(decrypted) = decrypted {
+        assert_eq!(streamed, decrypted);
+    }
+});
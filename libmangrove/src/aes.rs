@@ -62,7 +62,8 @@ macro_rules! aes_cipher {
             pub fn decrypt(self: &mut $struct_name, data: &[u8]) -> Vec<u8> {
                 let cipher = <$crypto_backend>::new(&GenericArray::from(self.key));
                 let mut decrypted: Vec<u8> = vec![];
-                for block in data.chunks(self.bs) {
+                // a trailing partial block cannot have come from encrypt, and is ignored
+                for block in data.chunks_exact(self.bs) {
                     // Encrypt the raw block
                     let mut block_ga = GenericArray::from(array_ref![block, 0, 16].to_owned());
                     cipher.decrypt_block(&mut block_ga);
@@ -101,8 +102,12 @@ macro_rules! aes_cipher {
                 // The `pad` function above uses the amount of padding converted to a char
                 // for the padding, so simply convert the last element of the array to a usize,
                 // then remove that many elements from the end
-                let pad_amt = data[data.len() - 1] as usize;
-                if pad_amt > data.len() || data[data.len() - pad_amt] != pad_amt as u8 {
+                let pad_byte = match data.last() {
+                    Some(last) => *last,
+                    None => return data
+                };
+                let pad_amt = usize::from(pad_byte);
+                if pad_amt == 0 || pad_amt > data.len() || data[data.len() - pad_amt] != pad_byte {
                     // no padding present
                     return data;
                 }
@@ -0,0 +1,331 @@
+//! # Signing agent
+//! The signing agent holds decrypted private keys in memory, and signs with them on request over a Unix domain socket,
+//! so programs can sign with a key without ever seeing the key material. Only the owner of the socket can connect to it.
+//!
+//! Requests and responses are frames: a big-endian u32 length, followed by that many bytes. Requests start with their
+//! type, and responses with `AGENT_OK` and the response, or `AGENT_FAILURE` and an error message.
+
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fs;
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::Path;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+
+use ed25519_dalek::{PublicKey as VerifyingKey, Signature, Signer};
+
+use crate::crypt::{fingerprint_raw, KeySigner, PrivateKey, PublicKey};
+use crate::spf::{SPF_SIGNATURE_LEN, SPF_V2_MAX_CHUNK_SIZE};
+
+/// Request the public keys and names of the keys the agent holds
+pub const AGENT_LIST: u8 = 0x01;
+/// Request a signature: the 32 byte fingerprint of the key, followed by the message
+pub const AGENT_SIGN: u8 = 0x02;
+/// Request the agent to stop
+pub const AGENT_STOP: u8 = 0x03;
+/// The status of a successful response
+pub const AGENT_OK: u8 = 0x00;
+/// The status of a failed response, followed by an error message
+pub const AGENT_FAILURE: u8 = 0x01;
+/// The longest frame the agent reads, enough to sign a SPF v2 chunk of the largest size
+pub const AGENT_MAX_FRAME_LEN: u32 = SPF_V2_MAX_CHUNK_SIZE + 4096;
+
+// read_frame
+/// Read a frame, or nothing if the other side closed the connection before sending one
+/// # Errors
+/// This function will error if the frame is too long, or could not be read
+fn read_frame<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>, Box<dyn Error>> {
+    let mut len = [0u8; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e.into())
+    }
+    let len = u32::from_be_bytes(len);
+    if len > AGENT_MAX_FRAME_LEN {
+        return Err(format!("Agent frame of {len} bytes is above the maximum of {AGENT_MAX_FRAME_LEN}").into());
+    }
+    let mut frame = vec![0u8; len as usize];
+    reader.read_exact(&mut frame)?;
+    Ok(Some(frame))
+}
+
+// write_frame
+/// Write a frame
+/// # Errors
+/// This function will error if the frame is too long, or could not be written
+fn write_frame<W: Write>(writer: &mut W, frame: &[u8]) -> Result<(), Box<dyn Error>> {
+    let len = u32::try_from(frame.len()).unwrap_or(u32::MAX);
+    if len > AGENT_MAX_FRAME_LEN {
+        return Err(format!("Agent frame of {} bytes is above the maximum of {AGENT_MAX_FRAME_LEN}", frame.len()).into());
+    }
+    writer.write_all(&len.to_be_bytes())?;
+    writer.write_all(frame)?;
+    writer.flush()?;
+    Ok(())
+}
+
+// Agent
+/// The signing agent, holding the keys it signs with
+//
+pub struct Agent {
+    keys: Vec<PrivateKey>
+}
+
+impl Agent {
+    // new
+    /// Create an agent that signs with the provided keys
+    //
+    pub const fn new(keys: Vec<PrivateKey>) -> Self {
+        Self { keys }
+    }
+
+    // handle
+    /// Handle a single request, returning the response and whether the agent was asked to stop
+    //
+    pub fn handle(&self, request: &[u8]) -> (Vec<u8>, bool) {
+        let result = match request.split_first() {
+            Some((&AGENT_LIST, _)) => Ok(self.list()),
+            Some((&AGENT_SIGN, payload)) => self.sign(payload),
+            Some((&AGENT_STOP, _)) => return (vec![AGENT_OK], true),
+            Some((kind, _)) => Err(format!("unknown request type {kind:#04x}")),
+            None => Err("empty request".to_string())
+        };
+        match result {
+            Ok(mut payload) => {
+                payload.insert(0, AGENT_OK);
+                (payload, false)
+            },
+            Err(e) => {
+                let mut response = vec![AGENT_FAILURE];
+                response.extend_from_slice(e.as_bytes());
+                (response, false)
+            }
+        }
+    }
+
+    // list
+    /// Every key the agent holds: its public key, the length of its name as a big-endian u16, and its name
+    //
+    fn list(&self) -> Vec<u8> {
+        let mut response = vec![];
+        for key in &self.keys {
+            let name = if key.name == "__anonymous__" { "" } else { key.name.as_str() };
+            let len = u16::try_from(name.len()).unwrap_or(u16::MAX);
+            response.extend_from_slice(key.key_data.public.as_bytes());
+            response.extend_from_slice(&len.to_be_bytes());
+            response.extend_from_slice(&name.as_bytes()[..len as usize]);
+        }
+        response
+    }
+
+    // sign
+    /// Sign the message with the key with the fingerprint it starts with
+    //
+    fn sign(&self, payload: &[u8]) -> Result<Vec<u8>, String> {
+        if payload.len() < 32 {
+            return Err("the sign request is truncated".to_string());
+        }
+        let (fingerprint, message) = payload.split_at(32);
+        let Some(key) = self.keys.iter().find(|k| fingerprint_raw(&k.key_data.public) == fingerprint) else {
+            return Err(format!("the agent does not hold the key {}", hex::encode(fingerprint)));
+        };
+        Ok(key.key_data.sign(message).to_bytes().to_vec())
+    }
+
+    // serve
+    /// Handle connections on the listener until a client asks the agent to stop, and remove the socket.
+    /// Every connection is handled on its own thread, and may send any number of requests.
+    /// # Errors
+    /// This function will error if the address of the listener could not be determined.
+    pub fn serve(self, listener: &UnixListener) -> Result<(), Box<dyn Error>> {
+        let path = listener.local_addr()?.as_pathname().map(Path::to_path_buf);
+        let agent = Arc::new(self);
+        let stopping = Arc::new(AtomicBool::new(false));
+        for stream in listener.incoming() {
+            if stopping.load(Ordering::SeqCst) {
+                break;
+            }
+            let Ok(stream) = stream else { continue; };
+            let agent = Arc::clone(&agent);
+            let stopping = Arc::clone(&stopping);
+            let path = path.clone();
+            thread::spawn(move || {
+                if agent.serve_connection(&stream) {
+                    stopping.store(true, Ordering::SeqCst);
+                    // wake the listener up, so it notices
+                    if let Some(path) = path {
+                        let _ = UnixStream::connect(path);
+                    }
+                }
+            });
+        }
+        if let Some(path) = path {
+            let _ = fs::remove_file(path);
+        }
+        Ok(())
+    }
+
+    // serve_connection
+    /// Handle the requests of a connection until it is closed, returning whether the agent was asked to stop
+    //
+    fn serve_connection(&self, mut stream: &UnixStream) -> bool {
+        loop {
+            let Ok(Some(request)) = read_frame(&mut stream) else { return false; };
+            let (response, stop) = self.handle(&request);
+            if write_frame(&mut stream, &response).is_err() || stop {
+                return stop;
+            }
+        }
+    }
+}
+
+// agent_bind
+/// Create the socket of the signing agent, only accessible by its owner.
+///
+/// A directory that has to be created for it is only accessible by its owner as well. The socket of an agent that is
+/// no longer running is replaced.
+/// # Errors
+/// This function will error if another agent is listening on the socket, or the socket could not be created.
+pub fn agent_bind(path: &str) -> Result<UnixListener, Box<dyn Error>> {
+    let path = Path::new(path);
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty() && !p.exists()) {
+        fs::create_dir_all(parent)?;
+        fs::set_permissions(parent, fs::Permissions::from_mode(0o700))?;
+    }
+    if path.exists() {
+        if UnixStream::connect(path).is_ok() {
+            return Err(format!("A signing agent is already listening on {}", path.display()).into());
+        }
+        fs::remove_file(path)?;
+    }
+    let listener = UnixListener::bind(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+    Ok(listener)
+}
+
+// AgentClient
+/// A connection to the signing agent
+//
+pub struct AgentClient {
+    stream: UnixStream
+}
+
+impl AgentClient {
+    // connect
+    /// Connect to the signing agent listening on the socket
+    /// # Errors
+    /// This function will error if there is no agent listening on the socket.
+    pub fn connect(path: &str) -> Result<Self, Box<dyn Error>> {
+        match UnixStream::connect(path) {
+            Ok(stream) => Ok(Self { stream }),
+            Err(e) => Err(format!("Could not connect to the signing agent at {path} ({e}), is it running?").into())
+        }
+    }
+
+    // request
+    /// Send a request to the agent, and return the response if it was successful
+    /// # Errors
+    /// This function will error if the request could not be sent, the agent refused it, or the response is invalid.
+    fn request(&self, request: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let mut stream = &self.stream;
+        write_frame(&mut stream, request)?;
+        let Some(response) = read_frame(&mut stream)? else {
+            return Err("The signing agent closed the connection".into());
+        };
+        match response.split_first() {
+            Some((&AGENT_OK, payload)) => Ok(payload.to_vec()),
+            Some((&AGENT_FAILURE, message)) => Err(format!("The signing agent refused the request: {}", String::from_utf8_lossy(message)).into()),
+            _ => Err("The signing agent sent an invalid response".into())
+        }
+    }
+
+    // list
+    /// Get the public keys of the keys the agent holds, with their names
+    /// # Errors
+    /// This function will error if the request failed, or the agent sent an invalid list.
+    pub fn list(&self) -> Result<Vec<PublicKey>, Box<dyn Error>> {
+        let response = self.request(&[AGENT_LIST])?;
+        let mut data = &response[..];
+        let mut keys = vec![];
+        while !data.is_empty() {
+            if data.len() < 34 {
+                return Err("The signing agent sent a truncated key list".into());
+            }
+            let len = u16::from_be_bytes([data[32], data[33]]) as usize;
+            if data.len() < 34 + len {
+                return Err("The signing agent sent a truncated key list".into());
+            }
+            let name = String::from_utf8(data[34..34 + len].to_vec())?;
+            keys.push(PublicKey {
+                name: if name.is_empty() { String::from("__anonymous__") } else { name },
+                key_data: VerifyingKey::from_bytes(&data[..32])?
+            });
+            data = &data[34 + len..];
+        }
+        Ok(keys)
+    }
+
+    // sign
+    /// Ask the agent to sign a message with a key it holds
+    /// # Errors
+    /// This function will error if the agent does not hold the key, or the request failed.
+    pub fn sign(&self, key: &PublicKey, message: &[u8]) -> Result<Signature, Box<dyn Error>> {
+        let mut request = vec![AGENT_SIGN];
+        request.extend_from_slice(&fingerprint_raw(&key.key_data));
+        request.extend_from_slice(message);
+        let response = self.request(&request)?;
+        if response.len() != SPF_SIGNATURE_LEN {
+            return Err("The signing agent sent an invalid signature".into());
+        }
+        Ok(Signature::try_from(&response[..])?)
+    }
+
+    // stop
+    /// Ask the agent to stop
+    /// # Errors
+    /// This function will error if the request failed.
+    pub fn stop(&self) -> Result<(), Box<dyn Error>> {
+        self.request(&[AGENT_STOP])?;
+        Ok(())
+    }
+
+    // key
+    /// Sign with a key the agent holds, found by its name, or by a prefix of its fingerprint or public key.
+    /// Without a query, the first key the agent holds is used.
+    /// # Errors
+    /// This function will error if the agent does not hold a matching key, or the request failed.
+    pub fn key(self, query: Option<&str>) -> Result<AgentKey, Box<dyn Error>> {
+        let keys = self.list()?;
+        let key = query.map_or_else(|| keys.first(), |q| {
+            keys.iter().find(|k| k.name == q)
+                .or_else(|| keys.iter().find(|k| k.fingerprint().starts_with(q) || k.to_anonymous().starts_with(q)))
+        });
+        let Some(key) = key else {
+            return Err(format!("The signing agent does not hold {}", query.map_or_else(|| "any keys".to_string(), |q| format!("a key matching {q}"))).into());
+        };
+        Ok(AgentKey { client: self, key: key.clone() })
+    }
+}
+
+// AgentKey
+/// A key held by the signing agent, which signs by asking the agent
+//
+pub struct AgentKey {
+    client: AgentClient,
+    key: PublicKey
+}
+
+impl KeySigner for AgentKey {
+    fn public_key(&self) -> PublicKey {
+        self.key.clone()
+    }
+
+    fn sign_message(&self, message: &[u8]) -> Result<Signature, Box<dyn Error>> {
+        self.client.sign(&self.key, message)
+    }
+}
@@ -1,5 +1,6 @@
 //! Configuration files
 
+use std::env;
 use std::error::Error;
 use std::fs::create_dir_all;
 
@@ -8,6 +9,9 @@ use std::fs::create_dir_all;
 // /etc/mangrove/locks      - lockfiles
 // /etc/mangrove/repos      - repositories
 // /etc/mangrove/trust.toml - trust settings
+// /etc/mangrove/keys.toml  - encrypted private keys
+// /run/mangrove/agent.sock - signing agent socket
+// /var/cache/mangrove      - downloaded packages
 
 // ensure_config
 /// This function is used to create the expected configuration structure at the specified location.
@@ -32,6 +36,18 @@ pub fn get_trustcache_file(local: bool) -> String {
     }
 }
 
+// get_keystore_file
+/// This function is used to determine what file the key store should be stored in, depending if it is `local` or not.
+///
+/// If `local` is true, this will return "./keys.toml", otherwise "/etc/mangrove/keys.toml". Subject to change.
+pub fn get_keystore_file(local: bool) -> String {
+    if local {
+        "./keys.toml".to_string()
+    } else {
+        "/etc/mangrove/keys.toml".to_string()
+    }
+}
+
 // get_pkgdb_file
 /// This function is used to determine what file the pkgdb should be stored in, depending if it is `local` or not.
 /// If `local` is true, this will return "./db", otherwise "/etc/mangrove/db". Subject to change.
@@ -41,4 +57,27 @@ pub fn get_pkgdb_file(local: bool) -> String {
     } else {
         "/etc/mangrove/db".to_string()
     }
-}
\ No newline at end of file
+}
+
+// get_package_cache_dir
+/// This function is used to determine what directory packages downloaded from repositories are stored in, depending if it is `local` or not.
+///
+/// If `local` is true, this will return "./test/config/cache", otherwise "/var/cache/mangrove". Subject to change.
+pub fn get_package_cache_dir(local: bool) -> String {
+    if local {
+        "./test/config/cache".to_string()
+    } else {
+        "/var/cache/mangrove".to_string()
+    }
+}
+
+// get_agent_socket
+/// This function is used to determine where the socket of the signing agent is, depending if it is `local` or not.
+///
+/// The `MANGROVE_AGENT_SOCK` environment variable takes precedence. Otherwise, if `local` is true, this will return
+/// "./test/config/agent.sock", otherwise "/run/mangrove/agent.sock". Subject to change.
+pub fn get_agent_socket(local: bool) -> String {
+    env::var("MANGROVE_AGENT_SOCK").unwrap_or_else(|_| {
+        if local { "./test/config/agent.sock".to_string() } else { "/run/mangrove/agent.sock".to_string() }
+    })
+}
@@ -2,15 +2,23 @@
 
 use std::{fs::File, io};
 use std::error::Error;
+use std::io::{Cursor, Read, Write};
+use std::fmt::{Display, Formatter};
+use std::convert::TryFrom;
+use std::str::FromStr;
 
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use aes_gcm::aead::{Aead, Payload};
 use arrayref::array_ref;
 use ed25519_dalek::{Keypair, PublicKey as VerifyingKey, Signature, Signer, Verifier};
+use rand_dalek::RngCore;
 use rand_dalek::rngs::OsRng;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 
 use crate::aes::AES256Cipher;
-use crate::trustcache::Trustcache;
+use crate::spf::{LegacyPackage, parse_frame_v2, parse_header_v2, parse_package, parse_signature, SignedPackage, SPF_END_SENTINEL, SPF_SIGNATURE_LEN, SPF_V2_CHUNK_SIZE, SPF_V2_FRAME_LEN, SPF_V2_HEADER_LEN, SPF_V2_MAX_CHUNK_SIZE, SpfError, V2Chunk, V2Package};
+use crate::trustcache::{is_pk_trusted, known_keys, Trustcache};
 
 // mcrypt_sha256_file
 /// Get the sha256 hash of the given file
@@ -61,10 +69,37 @@ pub fn mcrypt_sha256_verify_file(filename: &String, expect: &String) -> Result<(
     Ok(())
 }
 
+// mcrypt_sha256_copy
+/// Copy everything from `reader` to `writer`, returning the hex-encoded sha256 hash of what was copied
+/// ```
+/// use std::io::sink;
+/// use libmangrove::crypt::mcrypt_sha256_copy;
+/// let string_hash: String = mcrypt_sha256_copy(&mut &b"hello"[..], &mut sink()).unwrap();
+/// assert_eq!(string_hash, "2cf24dba5fb0a30e26e83b2ac5b9e29e1b161e5c1fa7425e73043362938b9824");
+/// ```
+/// # Errors
+/// This function will error if reading from `reader` or writing to `writer` fails
+//
+pub fn mcrypt_sha256_copy<R: Read, W: Write>(reader: &mut R, writer: &mut W) -> Result<String, Box<dyn Error>> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        let n = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into())
+        };
+        hasher.update(&buffer[..n]);
+        writer.write_all(&buffer[..n])?;
+    }
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
 // PublicKey
 /// Represents a verifying key, with a name and the actual key data
 //
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 pub struct PublicKey {
     /// The key name, if loaded from a public key file. This is almost always unused, and will most of the time be \_\_anonymous\_\_
     pub name: String,
@@ -136,7 +171,49 @@ impl PrivateKey {
     }
 }
 
+// KeySigner
+/// Signs messages with a private key, without necessarily holding the key itself.
+///
+/// A `PrivateKey` signs with its own keypair, and an `AgentKey` asks the signing agent to sign, see `crate::agent`.
+/// Everything that signs packages, detached signatures or repository data takes a `KeySigner`.
+//
+pub trait KeySigner {
+    // public_key
+    /// The public key that verifies the signatures
+    //
+    fn public_key(&self) -> PublicKey;
+
+    // sign_message
+    /// Sign a message with the private key
+    /// # Errors
+    /// This function will error if the message could not be signed
+    fn sign_message(&self, message: &[u8]) -> Result<Signature, Box<dyn Error>>;
+}
+
+impl KeySigner for PrivateKey {
+    fn public_key(&self) -> PublicKey {
+        self.derive()
+    }
+
+    fn sign_message(&self, message: &[u8]) -> Result<Signature, Box<dyn Error>> {
+        Ok(self.key_data.sign(message))
+    }
+}
+
 impl PublicKey {
+    // fingerprint
+    /// Get the fingerprint of this `PublicKey`, the hex-encoded sha256 hash of the key data.
+    /// SPF v2 packages record the fingerprint of the key they were signed with.
+    /// ```
+    /// use libmangrove::crypt::PrivateKey;
+    /// let public_key = PrivateKey::generate(String::from("test_key")).derive();
+    /// assert_eq!(public_key.fingerprint().len(), 64);
+    /// ```
+    //
+    pub fn fingerprint(&self) -> String {
+        hex::encode(fingerprint_raw(&self.key_data))
+    }
+
     // to_anonymous
     /// Serialize this PublicKey into a base64-encoded anonymous public key.
     pub fn to_anonymous(&self) -> String {
@@ -158,6 +235,13 @@ impl PublicKey {
     }
 }
 
+// fingerprint_raw
+/// Get the fingerprint of the verifying key in bytes, see `PublicKey::fingerprint`
+//
+pub(crate) fn fingerprint_raw(vkey: &VerifyingKey) -> Vec<u8> {
+    mcrypt_sha256_raw(vkey.as_bytes())
+}
+
 // mcrypt_sha256_raw
 /// Given a raw byte array, get the sha256 hash of it and return its digest in bytes
 /// ```
@@ -174,8 +258,174 @@ pub fn mcrypt_sha256_raw(data: &[u8]) -> Vec<u8> {
     data.to_vec()
 }
 
+// SPF_MAGIC
+/// The magic at the start of every package in the Signed Package Format, 'MGVE' in ascii
+//
+pub const SPF_MAGIC: [u8; 4] = [0x4d, 0x47, 0x56, 0x45];
+
+// SpfVersion
+/// The revisions of the Signed Package Format
+//
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum SpfVersion {
+    /// The original format. The package data is encrypted block by block with a key derived from the signature, which sits in plaintext in the header.
+    /// Only use this for systems that cannot read SPF v2 yet.
+    Legacy,
+    #[default]
+    /// SPF v2. The package data is split into chunks that are each sealed with AES-256-GCM and signed along with the header,
+    /// so packages of any size can be written, verified and read one chunk at a time.
+    V2
+}
+
+impl SpfVersion {
+    // byte
+    /// The byte that follows the magic in packages of this version.
+    /// Legacy packages have no version byte, this is the length of their signature instead, which is always 64.
+    //
+    pub const fn byte(self) -> u8 {
+        match self {
+            Self::Legacy => 0x40,
+            Self::V2 => 0x02
+        }
+    }
+
+    // detect
+    /// Determine which version of the Signed Package Format the provided data claims to be, from its magic and version byte.
+    /// Does not check the rest of the structure, see `is_signed_package` for that.
+    //
+    pub fn detect(data: &[u8]) -> Option<Self> {
+        if data.len() < 5 || data[0..4] != SPF_MAGIC {
+            return None;
+        }
+        match data[4] {
+            0x02 => Some(Self::V2),
+            0x40 => Some(Self::Legacy),
+            _ => None
+        }
+    }
+}
+
+impl Display for SpfVersion {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Legacy => write!(f, "legacy"),
+            Self::V2 => write!(f, "v2")
+        }
+    }
+}
+
+impl FromStr for SpfVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "legacy" | "v1" => Ok(Self::Legacy),
+            "v2" => Ok(Self::V2),
+            _ => Err(format!("unknown signed package format {s}, expected legacy or v2"))
+        }
+    }
+}
+
+// SpfV2Cipher
+/// The AES-256-GCM cipher a SPF v2 package is sealed with, bound to the random id of the package.
+///
+/// The key is derived from the signer's public key and the package id, so every package is sealed with its own key, and the nonce
+/// of every chunk is derived from the package id, the chunk index and the chunk flag.
+/// Public keys are public, so this does not keep the data secret from anyone who trusts the signer: it binds the ciphertext to the signer and the package, and the signature does the rest.
+//
+struct SpfV2Cipher {
+    cipher: Aes256Gcm,
+    id: [u8; 12]
+}
+
+impl SpfV2Cipher {
+    // new
+    /// Derive the cipher of the package with the provided id, signed by `vkey`
+    //
+    fn new(vkey: &VerifyingKey, id: &[u8; 12]) -> Self {
+        let mut hasher = Sha256::new();
+        hasher.update(b"mangrove spf v2");
+        hasher.update(vkey.as_bytes());
+        hasher.update(id);
+        Self { cipher: Aes256Gcm::new(&hasher.finalize()), id: *id }
+    }
+
+    // chunk_nonce
+    /// The nonce of a chunk: the package id, with the chunk index xored into its first 8 bytes and the chunk flag into its last byte
+    //
+    fn chunk_nonce(&self, index: u64, last: bool) -> [u8; 12] {
+        let mut nonce = self.id;
+        for (n, i) in nonce.iter_mut().zip(index.to_be_bytes()) {
+            *n ^= i;
+        }
+        nonce[11] ^= u8::from(last);
+        nonce
+    }
+
+    // seal
+    /// Seal the data of a chunk, with the header as associated data
+    /// # Errors
+    /// This function will error if the data could not be sealed
+    //
+    fn seal(&self, header: &[u8], index: u64, last: bool, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+        let nonce = self.chunk_nonce(index, last);
+        self.cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: data, aad: header })
+            .map_err(|_| "Failed to seal package data".into())
+    }
+
+    // open
+    /// Open the sealed data of a chunk, with the header as associated data
+    /// # Errors
+    /// This function will error if the sealed data fails authentication
+    //
+    fn open(&self, header: &[u8], chunk: &V2Chunk) -> Result<Vec<u8>, Box<dyn Error>> {
+        let nonce = self.chunk_nonce(chunk.index, chunk.last);
+        self.cipher.decrypt(Nonce::from_slice(&nonce), Payload { msg: chunk.sealed, aad: header })
+            .map_err(|_| format!("The package data failed authentication (chunk {})", chunk.index).into())
+    }
+}
+
+// spf_v2_chunk_message
+/// Fill `message` with what the signature of a SPF v2 chunk covers: the header, the chunk index, the frame and the sealed chunk data.
+/// The header ties every chunk to its package, and the index and flag in the frame stop chunks from being reordered or dropped.
+//
+fn spf_v2_chunk_message(message: &mut Vec<u8>, header: &[u8], index: u64, frame: &[u8], sealed: &[u8]) {
+    message.clear();
+    message.extend_from_slice(header);
+    message.extend_from_slice(&index.to_be_bytes());
+    message.extend_from_slice(frame);
+    message.extend_from_slice(sealed);
+}
+
+// check_signer_v2
+/// Check that the fingerprint in the header of a SPF v2 package is that of the provided key
+/// # Errors
+/// This function will error if the package was signed by a different key
+//
+fn check_signer_v2(vkey: &PublicKey, fingerprint: &[u8]) -> Result<(), Box<dyn Error>> {
+    if fingerprint != fingerprint_raw(&vkey.key_data) {
+        return Err(format!("The package was signed by {}, not by {}", hex::encode(fingerprint), vkey.fingerprint()).into());
+    }
+    Ok(())
+}
+
+// open_chunk_v2
+/// Verify and decrypt a single parsed SPF v2 chunk, using `message` as scratch space
+/// # Errors
+/// This function will error if the chunk signature is invalid, or the sealed data fails authentication
+//
+fn open_chunk_v2(vkey: &VerifyingKey, cipher: &SpfV2Cipher, header: &[u8], chunk: &V2Chunk, message: &mut Vec<u8>) -> Result<Vec<u8>, Box<dyn Error>> {
+    spf_v2_chunk_message(message, header, chunk.index, chunk.frame, chunk.sealed);
+    if let Err(err) = vkey.verify(message, &chunk.signature) {
+        return Err(format!("The digital signature is invalid (chunk {}): {err}", chunk.index).into());
+    }
+    cipher.open(header, chunk)
+}
+
 // encrypt_package
-/// Given a PrivateKey and any arbitrary data array, encrypt it using the Signed Package format and return the result as a byte array
+/// Given a `PrivateKey` and any arbitrary data array, encrypt it using the Signed Package format and return the result as a byte array.
+///
+/// This produces SPF v2, see `encrypt_package_as` to pick the version, and `encrypt_package_stream` to avoid holding the package in memory.
 /// ```
 /// use libmangrove::crypt::{encrypt_package, PrivateKey};
 /// let private_key = PrivateKey::generate(String::from("test_key"));
@@ -183,9 +433,199 @@ pub fn mcrypt_sha256_raw(data: &[u8]) -> Vec<u8> {
 /// let encrypted_data = encrypt_package(&private_key, &data_to_encrypt).unwrap();
 /// ```
 /// # Errors
+/// This function may return an error if the signature fails sanity checks or the data could not be sealed
+//
+pub fn encrypt_package(key: &dyn KeySigner, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+    encrypt_package_as(key, data, SpfVersion::default())
+}
+
+// encrypt_package_as
+/// Given a `PrivateKey` and any arbitrary data array, encrypt it using the provided version of the Signed Package format and return the result as a byte array
+/// # Errors
+/// This function may return an error if:
+/// - the signature fails sanity checks
+/// - the data could not be sealed
+/// - the data is too long for the legacy format
+//
+pub fn encrypt_package_as(key: &dyn KeySigner, data: &[u8], version: SpfVersion) -> Result<Vec<u8>, Box<dyn Error>> {
+    match version {
+        SpfVersion::Legacy => encrypt_package_legacy(key, data),
+        SpfVersion::V2 => {
+            let mut package = PackageWriter::new(key, Vec::with_capacity(data.len() + SPF_V2_HEADER_LEN + 128))?;
+            package.write_all(data)?;
+            package.finish()
+        }
+    }
+}
+
+// encrypt_package_stream
+/// Encrypt everything read from `reader` into a SPF v2 package written to `writer`, returning the amount of package data encrypted.
+///
+/// Only a single chunk is held in memory at a time, so there is no limit on the package size. Legacy packages cannot be written this way.
+/// ```
+/// use libmangrove::crypt::{decrypt_package, encrypt_package_stream, PrivateKey};
+/// let private_key = PrivateKey::generate(String::from("test_key"));
+/// let mut package: Vec<u8> = vec![];
+/// assert_eq!(encrypt_package_stream(&private_key, &b"package data"[..], &mut package).unwrap(), 12);
+/// assert_eq!(decrypt_package(&private_key.derive(), &package).unwrap(), b"package data".to_vec());
+/// ```
+/// # Errors
+/// This function will error if reading or writing fails, a chunk could not be sealed, or a signature fails sanity checks
+//
+pub fn encrypt_package_stream<R: Read, W: Write>(key: &dyn KeySigner, mut reader: R, writer: W) -> Result<u64, Box<dyn Error>> {
+    let mut package = PackageWriter::new(key, writer)?;
+    let length = io::copy(&mut reader, &mut package)?;
+    package.finish()?;
+    Ok(length)
+}
+
+// PackageWriter
+/// Writes a SPF v2 package to the inner writer one chunk at a time, so only a single chunk is ever held in memory.
+///
+/// Everything written to it is package data. Once all of it has been written, `finish` must be called to write the last chunk and the end sentinel:
+/// a package that is dropped before that is incomplete, and will be rejected when it is read.
+/// ```
+/// use std::io::Write;
+/// use libmangrove::crypt::{decrypt_package, PackageWriter, PrivateKey};
+/// let private_key = PrivateKey::generate(String::from("test_key"));
+/// let mut writer = PackageWriter::with_chunk_size(&private_key, vec![], 4).unwrap();
+/// writer.write_all(b"package ").unwrap();
+/// writer.write_all(b"data").unwrap();
+/// let package = writer.finish().unwrap();
+/// assert_eq!(decrypt_package(&private_key.derive(), &package).unwrap(), b"package data".to_vec());
+/// ```
+//
+pub struct PackageWriter<'a, W: Write> {
+    key: &'a dyn KeySigner,
+    vkey: VerifyingKey,
+    inner: W,
+    cipher: SpfV2Cipher,
+    header: [u8; SPF_V2_HEADER_LEN],
+    chunk_size: usize,
+    index: u64,
+    chunk: Vec<u8>,
+    message: Vec<u8>
+}
+
+impl<'a, W: Write> PackageWriter<'a, W> {
+    // new
+    /// Start writing a SPF v2 package signed with `key` to `inner`, using the default chunk size
+    /// # Errors
+    /// This function will error if the header could not be written
+    //
+    pub fn new(key: &'a dyn KeySigner, inner: W) -> Result<Self, Box<dyn Error>> {
+        Self::with_chunk_size(key, inner, SPF_V2_CHUNK_SIZE)
+    }
+
+    // with_chunk_size
+    /// Start writing a SPF v2 package signed with `key` to `inner`, sealing and signing `chunk_size` bytes of package data at a time
+    /// # Errors
+    /// This function will error if the chunk size is 0 or above `SPF_V2_MAX_CHUNK_SIZE`, or the header could not be written
+    //
+    pub fn with_chunk_size(key: &'a dyn KeySigner, mut inner: W, chunk_size: u32) -> Result<Self, Box<dyn Error>> {
+        // SPF v2 format:
+        // field   value        description
+        //
+        // magic   0x4d475645   'MGVE' ascii, this is the magic
+        // s_ver   0x02         Format version
+        // s_key   0x??*32      Fingerprint of the signing key
+        // p_id    0x??*12      Random package id, which the package key and chunk nonces are derived from
+        // c_size  0x????????   Chunk size (in bytes)
+        // then, for every chunk:
+        // c_flag  0x??         0x01 for the last chunk, 0x00 for every other chunk
+        // c_len   0x????????   Sealed chunk length (in bytes), always c_size + 16 if this is not the last chunk
+        // c_dat   0x??*c_len   Sealed chunk data, including the 16 byte tag, with the header as associated data
+        // s_dat   0x??*64      ed25519 signature of the header, the chunk index (u64), c_flag, c_len and c_dat
+        // and after the last chunk:
+        // p_val   0x42         End sentinel
+        if chunk_size == 0 || chunk_size > SPF_V2_MAX_CHUNK_SIZE {
+            return Err(SpfError::BadChunkSize(chunk_size).into());
+        }
+        let vkey = key.public_key().key_data;
+        let mut id = [0u8; 12];
+        OsRng {}.fill_bytes(&mut id);
+        let mut header = [0u8; SPF_V2_HEADER_LEN];
+        header[..4].copy_from_slice(&SPF_MAGIC);
+        header[4] = SpfVersion::V2.byte();
+        header[5..37].copy_from_slice(&fingerprint_raw(&vkey));
+        header[37..49].copy_from_slice(&id);
+        header[49..53].copy_from_slice(&chunk_size.to_be_bytes());
+        inner.write_all(&header)?;
+        Ok(Self {
+            key,
+            vkey,
+            inner,
+            cipher: SpfV2Cipher::new(&vkey, &id),
+            header,
+            chunk_size: chunk_size as usize,
+            index: 0,
+            chunk: Vec::with_capacity(chunk_size as usize),
+            message: vec![]
+        })
+    }
+
+    // seal_chunk
+    /// Seal, sign and write the buffered chunk
+    /// # Errors
+    /// This function will error if the chunk could not be sealed or written, or the signature fails sanity checks
+    //
+    fn seal_chunk(&mut self, last: bool) -> Result<(), Box<dyn Error>> {
+        let sealed = self.cipher.seal(&self.header, self.index, last, &self.chunk)?;
+        let mut frame = [0u8; SPF_V2_FRAME_LEN];
+        frame[0] = u8::from(last);
+        frame[1..].copy_from_slice(&u32::try_from(sealed.len())?.to_be_bytes());
+
+        spf_v2_chunk_message(&mut self.message, &self.header, self.index, &frame, &sealed);
+        let signature = self.key.sign_message(&self.message)?;
+        if self.vkey.verify(&self.message, &signature).is_err() {
+            return Err("Signature failed basic sanity checks".into())
+        }
+        self.inner.write_all(&frame)?;
+        self.inner.write_all(&sealed)?;
+        self.inner.write_all(&signature.to_bytes())?;
+        self.chunk.clear();
+        self.index += 1;
+        Ok(())
+    }
+
+    // finish
+    /// Write the last chunk and the end sentinel, and return the inner writer
+    /// # Errors
+    /// This function will error if the last chunk could not be sealed or written, or the inner writer could not be flushed
+    //
+    pub fn finish(mut self) -> Result<W, Box<dyn Error>> {
+        self.seal_chunk(true)?;
+        self.inner.write_all(&[SPF_END_SENTINEL])?;
+        self.inner.flush()?;
+        Ok(self.inner)
+    }
+}
+
+impl<W: Write> Write for PackageWriter<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        // a full chunk is only sealed once more data arrives, as the last chunk has to be flagged as such
+        if self.chunk.len() == self.chunk_size {
+            self.seal_chunk(false).map_err(|e| io::Error::other(e.to_string()))?;
+        }
+        let n = buf.len().min(self.chunk_size - self.chunk.len());
+        self.chunk.extend_from_slice(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// encrypt_package_legacy
+/// Encrypt the data into a legacy SPF package
+/// # Errors
 /// This function may return an error if the signature fails sanity checks or the data length is over
 //
-pub fn encrypt_package(key: &PrivateKey, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
+fn encrypt_package_legacy(key: &dyn KeySigner, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
     // Encrypted package format:
     // field  value         description
     //
@@ -196,8 +636,8 @@ pub fn encrypt_package(key: &PrivateKey, data: &[u8]) -> Result<Vec<u8>, Box<dyn
     // d_len  0x????????    Data length (in bytes)
     // d_dat  0x??*d_len    Package data (d_len bytes)
     // p_val  0x42          End sentinel
-    let signature: Signature = key.key_data.sign(data);
-    if key.key_data.verify(data, &signature).is_err() {
+    let signature = key.sign_message(data)?;
+    if key.public_key().key_data.verify(data, &signature).is_err() {
         return Err("Signature failed basic sanity checks".into())
     }
     let mut signature_b = signature.to_bytes().to_vec();
@@ -206,7 +646,7 @@ pub fn encrypt_package(key: &PrivateKey, data: &[u8]) -> Result<Vec<u8>, Box<dyn
     let mut aes_cipher = AES256Cipher::new(*key);
     let data_arr: &[u8] = data;
     let mut enc_data = aes_cipher.encrypt(data_arr);
-    let mut header: Vec<u8> = vec![0x4d, 0x47, 0x56, 0x45];
+    let mut header: Vec<u8> = SPF_MAGIC.to_vec();
     #[allow(clippy::cast_possible_truncation)] // Signature length will always be 64, which is within the range of u8
     header.push(signature_b.len() as u8);
     header.append(&mut signature_b);
@@ -224,7 +664,9 @@ pub fn encrypt_package(key: &PrivateKey, data: &[u8]) -> Result<Vec<u8>, Box<dyn
 }
 
 // decrypt_package
-/// Validate and decrypt a package in the Signed Package format
+/// Validate and decrypt a package in the Signed Package format. Both SPF v2 and legacy packages are accepted.
+///
+/// The entire package is held in memory, see `decrypt_package_stream` and `PackageReader` to avoid that.
 /// ```
 /// use libmangrove::crypt::{decrypt_package, encrypt_package, PrivateKey};
 /// let private_key = PrivateKey::generate(String::from("test_key"));
@@ -238,44 +680,48 @@ pub fn encrypt_package(key: &PrivateKey, data: &[u8]) -> Result<Vec<u8>, Box<dyn
 /// ```
 /// # Errors
 /// This function will error if:
-/// - the magic is missing
-/// - the start/data sentinel is missing
-/// - end sentinel is missing
-/// - the signature could not be loaded
+/// - the data is not a structurally valid signed package, in which case the error is a `SpfError`
+/// - the package was signed by a different key, for SPF v2 packages
 /// - the digital signature was invalid
+/// - a sealed chunk of a SPF v2 package fails authentication
 //
 pub fn decrypt_package(vkey: &PublicKey, data: &[u8]) -> Result<Vec<u8>, Box<dyn Error>> {
-    // Check for the magic
-    if data[0] != 0x4d || data[1] != 0x47 || data[2] != 0x56 || data[3] != 0x45 {
-        return Err("Not an encrypted package (magic missing)".into());
-    }
-    // Get signature length
-    let s_len = data[4] as usize;
-    // Get signature bytes
-    let s_dat = &data[5..5 + s_len];
-    // Validate package data
-    if data[5 + s_len] != 0x0u8 {
-        return Err("Package has been corrupt (s/d sentinel missing)".into());
+    match parse_package(data)? {
+        SignedPackage::V2(package) => decrypt_package_v2(vkey, &package),
+        SignedPackage::Legacy(package) => decrypt_package_legacy(vkey, &package)
+    }
+}
+
+// decrypt_package_v2
+/// Validate and decrypt a parsed SPF v2 package
+/// # Errors
+/// This function will error if the package was signed by a different key, or any chunk has an invalid signature or fails authentication
+//
+fn decrypt_package_v2(vkey: &PublicKey, package: &V2Package) -> Result<Vec<u8>, Box<dyn Error>> {
+    check_signer_v2(vkey, package.header.fingerprint)?;
+    let cipher = SpfV2Cipher::new(&vkey.key_data, package.header.id);
+    let mut message: Vec<u8> = vec![];
+    let mut data: Vec<u8> = vec![];
+    for chunk in &package.chunks {
+        data.extend_from_slice(&open_chunk_v2(&vkey.key_data, &cipher, package.header.bytes, chunk, &mut message)?);
     }
+    Ok(data)
+}
+
+// decrypt_package_legacy
+/// Validate and decrypt a parsed legacy package
+/// # Errors
+/// This function will error if the digital signature was invalid
+//
+fn decrypt_package_legacy(vkey: &PublicKey, package: &LegacyPackage) -> Result<Vec<u8>, Box<dyn Error>> {
     // Derive key from signature
-    let raw_key = mcrypt_sha256_raw(s_dat);
+    let raw_key = mcrypt_sha256_raw(&package.signature.to_bytes());
     let key = array_ref!(raw_key, 0, 32);
     let mut cipher = AES256Cipher::new(*key);
-    let d_len = u32::from_be_bytes(*array_ref!(data[6 + s_len..10 + s_len], 0, 4)) as usize;
-    let d_dat = &data[10 + s_len..10 + s_len + d_len];
-    // Validate package data
-    if data[10 + s_len + d_len] != 0x42u8 || data[data.len() - 1] != 0x42u8 {
-        return Err("Package has been corrupt (end sentinel missing)".into());
-    }
-    // Package meets the proper structure
     // Decrypt package data
-    let d_dat_dec = cipher.decrypt(d_dat);
+    let d_dat_dec = cipher.decrypt(package.data);
     // Validate digital signature
-    let sig = match Signature::from_bytes(s_dat) {
-        Ok(sig) => sig,
-        Err(err) => return Err(format!("Failed to load signature data: {}", err).into()),
-    };
-    match vkey.key_data.verify(&d_dat_dec, &sig) {
+    match vkey.key_data.verify(&d_dat_dec, &package.signature) {
         Ok(_) => (),
         Err(err) => return Err(format!("The digital signature is invalid: {}", err).into()),
     }
@@ -283,77 +729,321 @@ pub fn decrypt_package(vkey: &PublicKey, data: &[u8]) -> Result<Vec<u8>, Box<dyn
     Ok(d_dat_dec)
 }
 
+// decrypt_package_stream
+/// Validate and decrypt a package in the Signed Package format read from `reader`, writing the package data to `writer`
+/// and returning the amount of package data written.
+///
+/// See `PackageReader` for how much of the package is held in memory.
+///
+/// Data is only written once the chunk it came from has been verified, but if this fails partway through,
+/// the package as a whole is not valid and everything written so far must be discarded.
+/// ```
+/// use libmangrove::crypt::{decrypt_package_stream, encrypt_package, PrivateKey};
+/// let private_key = PrivateKey::generate(String::from("test_key"));
+/// let package = encrypt_package(&private_key, b"package data").unwrap();
+/// let mut data: Vec<u8> = vec![];
+/// assert_eq!(decrypt_package_stream(&private_key.derive(), &package[..], &mut data).unwrap(), 12);
+/// assert_eq!(data, b"package data".to_vec());
+/// ```
+/// # Errors
+/// This function will error for the same reasons as `decrypt_package`, and if reading or writing fails
+//
+pub fn decrypt_package_stream<R: Read, W: Write>(vkey: &PublicKey, reader: R, mut writer: W) -> Result<u64, Box<dyn Error>> {
+    let mut package = PackageReader::new(vkey, reader)?;
+    Ok(io::copy(&mut package, &mut writer)?)
+}
+
+// read_up_to
+/// Read from `inner` until `buf` is full or the data ends, returning how much was read
+/// # Errors
+/// This function will error if reading fails
+//
+fn read_up_to<R: Read>(inner: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut found = 0;
+    while found < buf.len() {
+        match inner.read(&mut buf[found..]) {
+            Ok(0) => break,
+            Ok(n) => found += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err)
+        }
+    }
+    Ok(found)
+}
+
+// read_package_bytes
+/// Fill `buf` from a package that is being streamed, keeping track of how much of the package has been read in `offset`
+/// # Errors
+/// This function will error with `SpfError::Truncated` if the package ends first, or if reading fails
+//
+fn read_package_bytes<R: Read>(inner: &mut R, offset: &mut u64, buf: &mut [u8]) -> Result<(), Box<dyn Error>> {
+    let found = read_up_to(inner, buf)?;
+    *offset += found as u64;
+    if found < buf.len() {
+        return Err(SpfError::Truncated { needed: *offset + (buf.len() - found) as u64, found: usize::try_from(*offset).unwrap_or(usize::MAX) }.into());
+    }
+    Ok(())
+}
+
+// invalid_data
+/// Turn an error from reading a package into an `io::Error`, keeping `io::Error`s and `SpfError`s as they are
+//
+fn invalid_data(err: Box<dyn Error>) -> io::Error {
+    let err = match err.downcast::<io::Error>() {
+        Ok(err) => return *err,
+        Err(err) => err
+    };
+    match err.downcast::<SpfError>() {
+        Ok(err) => io::Error::new(io::ErrorKind::InvalidData, *err),
+        Err(err) => io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+    }
+}
+
+// PackageReader
+/// Reads the package data out of a package in the Signed Package Format, verifying it on the way.
+///
+/// SPF v2 packages are read one chunk at a time, and the data of a chunk is only handed out once its signature and tag have been checked,
+/// so memory use does not depend on the size of the package. Legacy packages are signed as a whole, so they are read and verified in full up front.
+///
+/// Reading fails with an `InvalidData` error as soon as anything is wrong with the package, which wraps a `SpfError` if the problem is structural.
+/// Everything read before that came from verified chunks, but the package as a whole is not valid, so it must be discarded.
+/// The package has only been verified in full once a read returns 0.
+/// ```
+/// use std::io::Read;
+/// use libmangrove::crypt::{encrypt_package, PackageReader, PrivateKey};
+/// let private_key = PrivateKey::generate(String::from("test_key"));
+/// let package = encrypt_package(&private_key, b"package data").unwrap();
+/// let mut reader = PackageReader::new(&private_key.derive(), &package[..]).unwrap();
+/// let mut data = String::new();
+/// reader.read_to_string(&mut data).unwrap();
+/// assert_eq!(data, "package data");
+/// ```
+//
+pub struct PackageReader<R: Read> {
+    source: PackageSource<R>
+}
+
+// PackageSource
+/// Where a `PackageReader` gets its package data from
+//
+enum PackageSource<R: Read> {
+    /// A legacy package, which has already been verified and decrypted
+    Legacy(Cursor<Vec<u8>>),
+    /// A SPF v2 package, which is verified and decrypted one chunk at a time
+    V2(Box<V2Reader<R>>)
+}
+
+// V2Reader
+/// The state of a `PackageReader` reading a SPF v2 package
+//
+struct V2Reader<R: Read> {
+    inner: R,
+    vkey: VerifyingKey,
+    cipher: SpfV2Cipher,
+    header: [u8; SPF_V2_HEADER_LEN],
+    chunk_size: u32,
+    /// The index of the next chunk
+    index: u64,
+    /// How much of the package has been read from `inner`
+    offset: u64,
+    /// The sealed data and signature of the last chunk that was read
+    chunk: Vec<u8>,
+    message: Vec<u8>,
+    /// The verified data of the last chunk that was read, and how much of it has been handed out
+    data: Vec<u8>,
+    position: usize,
+    /// Whether the last chunk and the end sentinel have been read
+    finished: bool,
+    /// Whether verification has failed, after which nothing more is read
+    failed: bool
+}
+
+impl<R: Read> PackageReader<R> {
+    // new
+    /// Start reading the package in `inner`, which must be signed by `vkey`.
+    /// This reads the header, and for legacy packages, the entire package.
+    /// # Errors
+    /// This function will error if:
+    /// - the header is not a valid SPF v2 or legacy header, in which case the error is a `SpfError`
+    /// - the package was signed by a different key, for SPF v2 packages
+    /// - the package is a legacy package, and `decrypt_package` fails
+    /// - reading fails
+    //
+    pub fn new(vkey: &PublicKey, mut inner: R) -> Result<Self, Box<dyn Error>> {
+        let mut header = [0u8; SPF_V2_HEADER_LEN];
+        let found = read_up_to(&mut inner, &mut header[..5])?;
+        if found < 4 || header[..4] != SPF_MAGIC {
+            return Err(SpfError::MissingMagic.into());
+        }
+        match SpfVersion::detect(&header[..found]) {
+            Some(SpfVersion::Legacy) => {
+                let mut data = header[..found].to_vec();
+                inner.read_to_end(&mut data)?;
+                Ok(Self { source: PackageSource::Legacy(Cursor::new(decrypt_package(vkey, &data)?)) })
+            },
+            Some(SpfVersion::V2) => {
+                let found = found + read_up_to(&mut inner, &mut header[5..])?;
+                let parsed = parse_header_v2(&header[..found])?;
+                check_signer_v2(vkey, parsed.fingerprint)?;
+                let cipher = SpfV2Cipher::new(&vkey.key_data, parsed.id);
+                let chunk_size = parsed.chunk_size;
+                Ok(Self {
+                    source: PackageSource::V2(Box::new(V2Reader {
+                        inner,
+                        vkey: vkey.key_data,
+                        cipher,
+                        header,
+                        chunk_size,
+                        index: 0,
+                        offset: SPF_V2_HEADER_LEN as u64,
+                        chunk: vec![],
+                        message: vec![],
+                        data: vec![],
+                        position: 0,
+                        finished: false,
+                        failed: false
+                    }))
+                })
+            },
+            None if found < 5 => Err(SpfError::ShortHeader { needed: 5, found }.into()),
+            None => Err(SpfError::UnknownVersion(header[4]).into())
+        }
+    }
+
+    // version
+    /// The format version of the package being read
+    //
+    pub const fn version(&self) -> SpfVersion {
+        match self.source {
+            PackageSource::Legacy(_) => SpfVersion::Legacy,
+            PackageSource::V2(_) => SpfVersion::V2
+        }
+    }
+}
+
+impl<R: Read> Read for PackageReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.source {
+            PackageSource::Legacy(data) => data.read(buf),
+            PackageSource::V2(reader) => reader.read(buf)
+        }
+    }
+}
+
+impl<R: Read> V2Reader<R> {
+    // next_chunk
+    /// Read, verify and decrypt the next chunk. After the last chunk, the end sentinel is checked and the package must end.
+    /// # Errors
+    /// This function will error if the chunk is malformed, has an invalid signature or fails authentication, or if reading fails
+    //
+    fn next_chunk(&mut self) -> Result<(), Box<dyn Error>> {
+        let mut frame = [0u8; SPF_V2_FRAME_LEN];
+        read_package_bytes(&mut self.inner, &mut self.offset, &mut frame)?;
+        let (last, sealed_len) = parse_frame_v2(self.chunk_size, frame)?;
+        self.chunk.resize(sealed_len + SPF_SIGNATURE_LEN, 0);
+        read_package_bytes(&mut self.inner, &mut self.offset, &mut self.chunk)?;
+        let chunk = V2Chunk {
+            index: self.index,
+            last,
+            frame: &frame,
+            sealed: &self.chunk[..sealed_len],
+            signature: parse_signature(&self.chunk[sealed_len..])?
+        };
+        let data = open_chunk_v2(&self.vkey, &self.cipher, &self.header, &chunk, &mut self.message)?;
+        if last {
+            let mut sentinel = [0u8; 1];
+            read_package_bytes(&mut self.inner, &mut self.offset, &mut sentinel)?;
+            if sentinel[0] != SPF_END_SENTINEL {
+                return Err(SpfError::MissingEndSentinel(sentinel[0]).into());
+            }
+            let trailing = io::copy(&mut self.inner, &mut io::sink())?;
+            if trailing > 0 {
+                return Err(SpfError::TrailingData(usize::try_from(trailing).unwrap_or(usize::MAX)).into());
+            }
+            self.finished = true;
+        }
+        self.data = data;
+        self.position = 0;
+        self.index += 1;
+        Ok(())
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.data.len() {
+            if self.failed {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "The package has already failed verification"));
+            }
+            if self.finished || buf.is_empty() {
+                return Ok(0);
+            }
+            if let Err(err) = self.next_chunk() {
+                self.failed = true;
+                return Err(invalid_data(err));
+            }
+        }
+        let n = buf.len().min(self.data.len() - self.position);
+        buf[..n].copy_from_slice(&self.data[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
 // debug_dump_package
 /// Dump the provided encrypted data in the Signed Package Format to a string.
 /// Optionally, decrypt the data if the correct public key is provided.
 //
 pub fn debug_dump_package(data: Vec<u8>, vkey: Option<&PublicKey>) -> String {
     let mut result = String::from("== Begin Package Dump ==\n");
-    // Check for the magic
-    if data[0] != 0x4d || data[1] != 0x47 || data[2] != 0x56 || data[3] != 0x45 {
-        result += "| Magic: Not Present\n";
-        result += "| Package State: INVALID\n";
-        result += "== End package Dump ==";
-        return result;
-    }
+    let package = match parse_package(&data) {
+        Ok(p) => p,
+        Err(SpfError::MissingMagic) => {
+            result += "| Magic: Not Present\n";
+            result += "| Package State: INVALID\n";
+            result += "== End Package Dump ==";
+            return result;
+        },
+        Err(e) => {
+            result += "| Magic: Present\n";
+            result += &*format!("| Package Structure: {e}\n");
+            result += "| Package State: INVALID\n";
+            result += "== End Package Dump ==";
+            return result;
+        }
+    };
     result += "| Magic: Present\n";
-    // Get signature length
-    let s_len = data[4] as usize;
-    result += &*format!("| Signature Length: {s_len}\n");
-    // Get signature bytes
-    let s_dat = &data[5..5 + s_len];
-    result += &*format!("| Signature Data: {:x?}\n", s_dat);
-    // Validate package data
-    result += "| Signature/Data Sentinel: ";
-    if data[5 + s_len] != 0x0u8 {
-        result += "Not Present\n";
-        result += "| Package State: INVALID\n";
-        result += "== End Package Dump ==";
+    result += &*format!("| Format: {}\n", package.version());
+    match package {
+        SignedPackage::V2(package) => debug_dump_package_v2(result, &package, vkey),
+        SignedPackage::Legacy(package) => debug_dump_package_legacy(result, &package, vkey)
     }
-    result += "Present\n";
+}
+
+// debug_dump_package_legacy
+/// Dump a parsed legacy package to a string, see `debug_dump_package`
+//
+fn debug_dump_package_legacy(mut result: String, package: &LegacyPackage, vkey: Option<&PublicKey>) -> String {
+    let s_dat = package.signature.to_bytes();
+    result += &*format!("| Signature Length: {}\n", s_dat.len());
+    result += &*format!("| Signature Data: {s_dat:x?}\n");
     // Derive key from signature
-    let raw_key = mcrypt_sha256_raw(s_dat);
+    let raw_key = mcrypt_sha256_raw(&s_dat);
     let key = array_ref!(raw_key, 0, 32);
-    result += &*format!("| Cipher Key: {:x?}\n", raw_key);
+    result += &*format!("| Cipher Key: {raw_key:x?}\n");
     let mut cipher = AES256Cipher::new(*key);
-    let d_len = u32::from_be_bytes(*array_ref!(data[6 + s_len..10 + s_len], 0, 4)) as usize;
-    result += &*format!("| Encrypted Data Length: {d_len}\n");
-    let d_dat = &data[10 + s_len..10 + s_len + d_len];
-    result += &*format!("| Encrypted Data: {:x?}\n", d_dat);
-    // Validate package data
-    result += "| End Sentinel: ";
-    if data[10 + s_len + d_len] != 0x42u8 || data[data.len() - 1] != 0x42u8 {
-        result += "Not Present\n";
-        result += "| Package State: INVALID\n";
-        result += "== End Package Dump ==";
-        return result;
-    }
-    result += "Present\n";
+    result += &*format!("| Encrypted Data Length: {}\n", package.data.len());
+    result += &*format!("| Encrypted Data: {:x?}\n", package.data);
     result += "| Package Structure: OK\n";
-    // Package meets the proper structure
     // Decrypt package data
-    let d_dat_dec = cipher.decrypt(d_dat);
-
-    result += &*format!("| Decrypted Data: {:x?}\n", d_dat_dec);
-    // Validate digital signature
-    let sig = if let Ok(sig) = Signature::from_bytes(s_dat) { sig } else {
-        result += "| Signature Load: Failure\n";
-        result += "| Package State: INVALID\n";
-        result += "== End Package Dump ==";
-        return result;
-    };
+    let d_dat_dec = cipher.decrypt(package.data);
+    result += &*format!("| Decrypted Data: {d_dat_dec:x?}\n");
     result += "| Signature Load: Success\n";
-    if vkey.is_none() {
+    let Some(vkeyv) = vkey else {
         result += "| Data Signature: Skipped (no public key)\n";
         result += "| Package State: OK\n";
         result += "== End Package Dump ==";
         return result;
-    }
-    let vkeyv = match vkey {
-        Some(k) => k,
-        None => return result
     };
-    if vkeyv.key_data.verify(&d_dat_dec, &sig).is_err() {
+    if vkeyv.key_data.verify(&d_dat_dec, &package.signature).is_err() {
         result += "| Data Signature: Failure\n";
         result += "| Package State: INVALID\n";
         result += "== End Package Dump ==";
@@ -365,65 +1055,103 @@ pub fn debug_dump_package(data: Vec<u8>, vkey: Option<&PublicKey>) -> String {
     result
 }
 
+// debug_dump_package_v2
+/// Dump a parsed SPF v2 package to a string, see `debug_dump_package`
+//
+fn debug_dump_package_v2(mut result: String, package: &V2Package, vkey: Option<&PublicKey>) -> String {
+    result += &*format!("| Signer Fingerprint: {}\n", hex::encode(package.header.fingerprint));
+    result += &*format!("| Package Id: {:x?}\n", package.header.id);
+    result += &*format!("| Chunk Size: {}\n", package.header.chunk_size);
+    result += &*format!("| Chunks: {}\n", package.chunks.len());
+    for chunk in &package.chunks {
+        result += &*format!("| Chunk {} Sealed Data Length: {}\n", chunk.index, chunk.sealed.len());
+        result += &*format!("| Chunk {} Sealed Data: {:x?}\n", chunk.index, chunk.sealed);
+        result += &*format!("| Chunk {} Signature Data: {:x?}\n", chunk.index, chunk.signature.to_bytes());
+    }
+    result += "| Package Structure: OK\n";
+    result += "| Signature Load: Success\n";
+    let Some(vkeyv) = vkey else {
+        result += "| Data Signature: Skipped (no public key)\n";
+        result += "| Package State: OK\n";
+        result += "== End Package Dump ==";
+        return result;
+    };
+    let cipher = SpfV2Cipher::new(&vkeyv.key_data, package.header.id);
+    let mut message: Vec<u8> = vec![];
+    let mut d_dat_dec: Vec<u8> = vec![];
+    for chunk in &package.chunks {
+        match open_chunk_v2(&vkeyv.key_data, &cipher, package.header.bytes, chunk, &mut message) {
+            Ok(data) => d_dat_dec.extend_from_slice(&data),
+            Err(err) => {
+                result += &*format!("| Data Verification: Failure ({err})\n");
+                result += "| Package State: INVALID\n";
+                result += "== End Package Dump ==";
+                return result;
+            }
+        }
+    }
+    result += "| Data Signature: OK\n";
+    result += "| Data Authentication: OK\n";
+    result += &*format!("| Decrypted Data: {d_dat_dec:x?}\n");
+    result += "| Package State: OK\n";
+    result += "== End Package Dump ==";
+    result
+}
+
 // is_signed_package
 /// Determine if the provided data array has the correct structure of a package in the Signed Package Format.
-/// Does not perform signature checks.
+/// Does not perform signature checks. See `spf::parse_package` to find out what is wrong with the data.
 //
 pub fn is_signed_package(data: Vec<u8>) -> bool {
-    // Check for the magic
-    if data[0] != 0x4d || data[1] != 0x47 || data[2] != 0x56 || data[3] != 0x45 {
-        return false;
-    }
-    let s_len = data[4] as usize;
-    // Get signature bytes
-    let s_dat = &data[5..5 + s_len];
+    parse_package(&data).is_ok()
+}
 
-    if data[5 + s_len] != 0x0u8 {
-        return false;
-    }
-    // Derive key from signature
-    let d_len = u32::from_be_bytes(*array_ref!(data[6 + s_len..10 + s_len], 0, 4)) as usize;
-    // Validate package data
-    if data[10 + s_len + d_len] != 0x42u8 || data[data.len() - 1] != 0x42u8 {
-        return false;
+// package_fingerprint
+/// Get the fingerprint of the key the provided SPF data claims to be signed with, without decrypting it.
+///
+/// Returns None for legacy packages, which do not record their signer, and for data that is not a valid SPF v2 package.
+/// The claim is only proven once the package is decrypted with that key.
+//
+pub fn package_fingerprint(data: &[u8]) -> Option<String> {
+    match parse_package(data) {
+        Ok(SignedPackage::V2(package)) => Some(hex::encode(package.header.fingerprint)),
+        _ => None
     }
-    // Package meets the proper structure
-    // Validate digital signature
-    match Signature::from_bytes(s_dat) {
-        Ok(sig) => sig,
-        Err(_) => {
-            return false;
-        },
-    };
-    true
 }
 
 // find_key
-/// Try every public key in the trustcache against the provided SPF data and try to find the key it is encrypted with.
+/// Find the key in the trustcache that the provided SPF data is signed with.
+///
+/// For SPF v2 packages this is a lookup by the fingerprint in the package header, and the package is not decrypted,
+/// so the key is only proven to be the signer once `decrypt_package` succeeds with it.
+/// Legacy packages do not record their signer, so every public key in the trustcache is tried against them instead.
+/// Keys that are not trusted, such as revoked or expired keys, are never returned, see `is_pk_trusted`.
 //
 pub fn find_key(data: &[u8], trustcache: &Trustcache) -> Option<PublicKey> {
-    // try known public keys
-    for key in &trustcache.keydb.known_pubkeys {
-        // load __anonymous__ key
-        let pk = match PublicKey::from_anonymous(&key.clone()) {
-            Ok(k) => k,
-            Err(_) => return None
-        };
-        if decrypt_package(&pk, data).is_ok() {
-            return Some(pk);
-        }
+    let mut trusted = known_keys(trustcache).into_iter().filter(|k| is_pk_trusted(trustcache, k).is_ok_and(|t| t.is_trusted()));
+    if let Some(fingerprint) = package_fingerprint(data) {
+        return trusted.find(|k| k.fingerprint() == fingerprint);
     }
-    // try known private keys
-    for key in &trustcache.keydb.known_privkeys {
-        // load __anonymous__ key
-        let sk = match PrivateKey::from_anonymous(&key.clone()) {
-            Ok(k) => k,
-            Err(_) => return None
-        };
-        let pk = sk.derive();
-        if decrypt_package(&pk, data).is_ok() {
-            return Some(pk);
-        }
+    // try every trusted key
+    trusted.find(|k| decrypt_package(k, data).is_ok())
+}
+
+// find_key_stream
+/// Find the key in the trustcache that the SPF package read from `reader` is signed with, see `find_key`.
+///
+/// Only the header of SPF v2 packages is read. Legacy packages do not record their signer, so they are read in full to try every trusted key against them.
+/// # Errors
+/// This function will error if reading fails, if the header is not a valid SPF v2 header, or if no trusted key signed the package
+//
+pub fn find_key_stream<R: Read>(mut reader: R, trustcache: &Trustcache) -> Result<PublicKey, Box<dyn Error>> {
+    let mut header = [0u8; SPF_V2_HEADER_LEN];
+    let found = read_up_to(&mut reader, &mut header)?;
+    if SpfVersion::detect(&header[..found]) == Some(SpfVersion::V2) {
+        let fingerprint = hex::encode(parse_header_v2(&header[..found])?.fingerprint);
+        let key = known_keys(trustcache).into_iter().find(|k| k.fingerprint() == fingerprint && is_pk_trusted(trustcache, k).is_ok_and(|t| t.is_trusted()));
+        return key.ok_or_else(|| format!("signed by {fingerprint}, which is not a trusted key").into());
     }
-    None
+    let mut data = header[..found].to_vec();
+    reader.read_to_end(&mut data)?;
+    find_key(&data, trustcache).ok_or_else(|| "no trusted key can decrypt it".into())
 }
\ No newline at end of file
@@ -2,11 +2,17 @@
 
 extern crate ed25519_dalek;
 
+use std::collections::HashMap;
+use std::error::Error;
+
+use log::warn;
 use serde::{Deserialize, Serialize};
 use url::Url;
 
+use crate::crypt::PublicKey;
 use crate::pkg::Package;
-use crate::repo::Repository;
+use crate::repo::{PoolLayout, Repository};
+use crate::trustcache::{is_pk_trusted, Trustcache};
 
 // Database
 /// Represents the package database on disk, contains a list of all installed packages and all configured repositories
@@ -17,15 +23,162 @@ pub struct Database {
     pub installed_packages: Vec<Package>,
     /// This is a list of the configured repositories
     pub repositories: Vec<ConfiguredRepository>,
+    /// This is a list of the names of held packages, which cannot be installed, upgraded, reinstalled or removed until they are unheld
+    #[serde(default)]
+    pub held_packages: Vec<String>,
 }
 
-/// Represents a configured repository. Just contains it's base URL and the synced data.
+impl Database {
+    // is_held
+    /// Determine if the package with the given name is held
+    //
+    pub fn is_held(&self, pkgname: &str) -> bool {
+        self.held_packages.iter().any(|x| x == pkgname)
+    }
+
+    // check_not_held
+    /// Make sure the package with the given name is not held before operating on it
+    /// # Errors
+    /// This function will error, naming the hold, if the package is held.
+    pub fn check_not_held(&self, pkgname: &str) -> Result<(), Box<dyn Error>> {
+        if self.is_held(pkgname) {
+            return Err(format!("Package {pkgname} is held, and cannot be changed until it is unheld").into());
+        }
+        Ok(())
+    }
+
+    // hold
+    /// Hold an installed package
+    /// # Errors
+    /// This function will error if the package is not installed or is already held.
+    pub fn hold(&mut self, pkgname: &str) -> Result<(), Box<dyn Error>> {
+        if !self.installed_packages.iter().any(|x| x.pkgname == pkgname) {
+            return Err(format!("Package {pkgname} is not installed").into());
+        }
+        if self.is_held(pkgname) {
+            return Err(format!("Package {pkgname} is already held").into());
+        }
+        self.held_packages.push(pkgname.to_string());
+        Ok(())
+    }
+
+    // unhold
+    /// Release the hold on a package
+    /// # Errors
+    /// This function will error if the package is not held.
+    pub fn unhold(&mut self, pkgname: &str) -> Result<(), Box<dyn Error>> {
+        if !self.is_held(pkgname) {
+            return Err(format!("Package {pkgname} is not held").into());
+        }
+        self.held_packages.retain(|x| x != pkgname);
+        Ok(())
+    }
+
+    // add_repository
+    /// Configure a new repository at `baseurl`, pinning `signing_key` as the key its repository data must be signed with.
+    /// The repository has no data until it is synced.
+    /// # Errors
+    /// This function will error if:
+    /// - a repository is already configured at `baseurl`
+    /// - the key is not trusted, see `is_pk_trusted`
+    /// - an invalid key is present in the trustcache
+    pub fn add_repository(&mut self, baseurl: Url, signing_key: PublicKey, trustcache: &Trustcache) -> Result<(), Box<dyn Error>> {
+        if self.repositories.iter().any(|x| x.baseurl == baseurl) {
+            return Err(format!("A repository is already configured at {baseurl}").into());
+        }
+        check_pinnable(&signing_key, trustcache)?;
+        self.repositories.push(ConfiguredRepository::new(baseurl, signing_key));
+        Ok(())
+    }
+
+    // pin_repository_key
+    /// Pin `signing_key` for the repository configured at `baseurl`, replacing the key pinned so far.
+    /// # Errors
+    /// This function will error if:
+    /// - no repository is configured at `baseurl`
+    /// - the key is not trusted, see `is_pk_trusted`
+    /// - an invalid key is present in the trustcache
+    pub fn pin_repository_key(&mut self, baseurl: &Url, signing_key: PublicKey, trustcache: &Trustcache) -> Result<(), Box<dyn Error>> {
+        let Some(configured) = self.repositories.iter_mut().find(|x| &x.baseurl == baseurl) else {
+            return Err(format!("No repository is configured at {baseurl}").into());
+        };
+        check_pinnable(&signing_key, trustcache)?;
+        configured.signing_key = Some(signing_key);
+        configured.pin_rotated = false;
+        Ok(())
+    }
+}
+
+// check_pinnable
+/// Check that a key is trusted, so it can be pinned for a repository
+/// # Errors
+/// This function will error if the key is not trusted, or if an invalid key is present in the trustcache.
+fn check_pinnable(signing_key: &PublicKey, trustcache: &Trustcache) -> Result<(), Box<dyn Error>> {
+    let trust = is_pk_trusted(trustcache, signing_key)?;
+    if !trust.is_trusted() {
+        return Err(format!("Signing key {} is not trusted, it is {trust}. Allow it in the trustcache first", signing_key.to_anonymous()).into());
+    }
+    Ok(())
+}
+
+/// Represents a configured repository. Contains it's base URL, the synced data and the pinned signing key.
 #[derive(Serialize, Deserialize, Debug)]
 pub struct ConfiguredRepository {
     /// Represents the repository sync baseurl.
     pub baseurl: Url,
     /// Represents the synced repository data.
-    pub repodata: Repository
+    pub repodata: Repository,
+    /// The public key the repository data must be signed with. This is configured along with the baseurl, and is never taken from the repository data.
+    /// Databases from before keys were pinned have none, and refuse to sync until a key is pinned, see `pin_stored_key`.
+    #[serde(default)]
+    pub signing_key: Option<PublicKey>,
+    /// Whether sync moved the pin along key rotations, see `sync_repository`. A key reached that way does not have to be in the trustcache,
+    /// as it was vouched for by the trusted key it was rotated from, but it must still not be blacklisted, revoked or outside its validity window.
+    #[serde(default)]
+    pub pin_rotated: bool
+}
+
+impl ConfiguredRepository {
+    // new
+    /// Configure the repository at `baseurl`, pinning `signing_key`. It has no repository data until it is synced.
+    /// See `Database::add_repository`, which also checks that the key is trusted.
+    //
+    pub fn new(baseurl: Url, signing_key: PublicKey) -> Self {
+        let repodata = Repository {
+            baseurl: baseurl.clone(),
+            signing_key: signing_key.clone(),
+            avaliable_architectures: vec![],
+            packages: HashMap::new(),
+            pool_layout: PoolLayout::default(),
+            serial: 0,
+            expires: None,
+            key_rotations: vec![]
+        };
+        Self { baseurl, repodata, signing_key: Some(signing_key), pin_rotated: false }
+    }
+
+    // pinned_key
+    /// The public key the repository data of this repository must be signed with, if one is pinned, see `signing_key`
+    //
+    pub const fn pinned_key(&self) -> Option<&PublicKey> {
+        self.signing_key.as_ref()
+    }
+
+    // pin_stored_key
+    /// Pin the signing key of the stored repository data, for repositories configured before keys were pinned, and return it.
+    ///
+    /// The stored key was taken from the repository data itself the last time it synced, so this trusts whoever served it then.
+    /// Only use it to migrate old databases, pinning a key obtained out of band with `Database::pin_repository_key` is always preferred.
+    /// # Errors
+    /// This function will error if a key is already pinned, if the stored key is not trusted, or if an invalid key is present in the trustcache.
+    pub fn pin_stored_key(&mut self, trustcache: &Trustcache) -> Result<&PublicKey, Box<dyn Error>> {
+        if let Some(pinned) = &self.signing_key {
+            return Err(format!("{} already has the key {} pinned", self.baseurl, pinned.fingerprint()).into());
+        }
+        check_pinnable(&self.repodata.signing_key, trustcache)?;
+        warn!("pinning {} for {}, taken from its stored repository data rather than configured out of band", self.repodata.signing_key.fingerprint(), self.baseurl);
+        Ok(self.signing_key.insert(self.repodata.signing_key.clone()))
+    }
 }
 
 // KeyDb
@@ -53,5 +206,51 @@ pub struct KeyDb {
     /// An immediate blacklist for keys. If a key is found in this list, it will be considered unknown and an error will be returned.
     pub deny_pubkeys: Vec<String>,
     /// An immediate blacklist for private keys. Keys in this list will not be used to sign packages, and if the associated public key is found in this list, it will be considered unknown and an error will be returned.
-    pub deny_privkeys: Vec<String>
+    pub deny_privkeys: Vec<String>,
+
+    /// Imported revocation certificates, base64-encoded. A key with a valid revocation certificate is never trusted, whichever list it is in.
+    #[serde(default)]
+    pub revocations: Vec<String>,
+    /// Validity windows of trusted keys. Keys without a validity window are trusted for as long as they are in the trustcache.
+    #[serde(default)]
+    pub validity: Vec<KeyValidity>,
+    /// Names, comments and other details of the keys in the trustcache, so it can be read by people. Keys do not need to have an entry.
+    #[serde(default)]
+    pub metadata: Vec<KeyMetadata>
+}
+
+// KeyMetadata
+/// Details of a key in the trustcache that are only there for people, and do not affect whether it is trusted. Private keys are given the details of their public key.
+//
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeyMetadata {
+    /// The base64-encoded anonymous public key these details are for
+    pub key: String,
+    /// The fingerprint of the key, see `PublicKey::fingerprint`. This is recorded for reference only, and is not checked.
+    #[serde(default)]
+    pub fingerprint: String,
+    /// A short name for the key, such as the name of its owner or the repository it signs
+    #[serde(default)]
+    pub name: String,
+    /// A free-form comment about the key
+    #[serde(default)]
+    pub comment: String,
+    /// The unix timestamp, in seconds, the key was added to the trustcache at. Keys added before this was recorded do not have one.
+    #[serde(default)]
+    pub added: Option<u64>
+}
+
+// KeyValidity
+/// The window of time in which a key in the trustcache is trusted. Private keys are given the window of their public key.
+//
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeyValidity {
+    /// The base64-encoded anonymous public key this window applies to
+    pub key: String,
+    /// The unix timestamp, in seconds, from which the key is trusted
+    #[serde(default)]
+    pub not_before: Option<u64>,
+    /// The unix timestamp, in seconds, from which the key is no longer trusted
+    #[serde(default)]
+    pub not_after: Option<u64>
 }
\ No newline at end of file
@@ -0,0 +1,285 @@
+//! # Install journaling
+//! Every path an installation or removal creates, overwrites or deletes is recorded in a `Journal` before it is touched.
+//! Files that get replaced or deleted are moved into a backup directory inside the target root first, so that a failure
+//! halfway through can put the target root back into the exact state it was in before.
+
+use std::error::Error;
+use std::fs::{self, create_dir, Permissions, remove_dir_all, remove_file, set_permissions};
+use std::os::unix::fs::{MetadataExt, PermissionsExt, symlink};
+use std::path::Path;
+
+use file_owner::PathExt;
+use log::debug;
+use uuid::Uuid;
+
+// JournalEntry
+/// Represents a single recorded change to the target root, and what is needed to undo it
+//
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum JournalEntry {
+    /// A path that did not exist before and was created
+    Created(String),
+    /// A path that existed before and was moved to `backup` before being replaced or removed
+    Backup {
+        /// The original path
+        path: String,
+        /// Where the original contents of `path` are kept until the journal is finished
+        backup: String,
+    },
+    /// A directory that was removed. Only empty directories are removed, so only its metadata needs to be kept.
+    RemovedDir {
+        /// The path of the directory
+        path: String,
+        /// The directory owner's user ID
+        owner: u32,
+        /// The directory owner's group ID
+        group: u32,
+        /// The directory permission bits
+        permissions: u32,
+    },
+    /// A path that already existed and only had its ownership and permissions changed
+    Metadata {
+        /// The path that was changed
+        path: String,
+        /// The original owner's user ID
+        owner: u32,
+        /// The original owner's group ID
+        group: u32,
+        /// The original permission bits
+        permissions: u32,
+    },
+}
+
+// Journal
+/// Records changes made to a target root so that they can be rolled back
+//
+#[derive(Debug)]
+pub struct Journal {
+    /// The directory backups are stored in. It lives inside the target root so backups can be renamed into place.
+    pub backup_dir: String,
+    /// The recorded changes, in the order they were made
+    pub entries: Vec<JournalEntry>,
+}
+
+impl Journal {
+    // new
+    /// Create a new, empty journal for the given target root. The backup directory is only created once it is needed.
+    //
+    pub fn new(target: &str) -> Self {
+        Self {
+            backup_dir: format!("{}/.mgve_journal_{}", target.trim_end_matches('/'), Uuid::new_v4()),
+            entries: vec![],
+        }
+    }
+
+    // next_backup
+    /// Get the path for the next backup, creating the backup directory if needed.
+    /// # Errors
+    /// This function will error if the backup directory could not be created.
+    fn next_backup(&self) -> Result<String, Box<dyn Error>> {
+        if !Path::new(&self.backup_dir).exists() {
+            create_dir(&self.backup_dir)?;
+        }
+        Ok(format!("{}/{}", self.backup_dir, self.entries.len()))
+    }
+
+    // scratch_path
+    /// Get a path in the backup directory to write a file to before it is known where it goes, creating the backup directory if needed.
+    /// Anything left there is thrown away with the backups, when the journal is committed or rolled back.
+    /// # Errors
+    /// This function will error if the backup directory could not be created.
+    pub fn scratch_path(&self) -> Result<String, Box<dyn Error>> {
+        if !Path::new(&self.backup_dir).exists() {
+            create_dir(&self.backup_dir)?;
+        }
+        Ok(format!("{}/scratch_{}", self.backup_dir, Uuid::new_v4()))
+    }
+
+    // backup
+    /// Move whatever is at `path` into the backup directory and record it.
+    /// # Errors
+    /// This function will error if the backup directory could not be created or the path could not be moved.
+    fn backup(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+        let backup = self.next_backup()?;
+        debug!("backing up {} to {}", path, backup);
+        move_path(path, &backup)?;
+        self.entries.push(JournalEntry::Backup { path: path.to_string(), backup });
+        Ok(())
+    }
+
+    // prepare_path
+    /// Record that `path` is about to be written. If something already exists there it is backed up and moved out of the way.
+    /// # Errors
+    /// This function will error if the existing path could not be backed up.
+    pub fn prepare_path(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+        if Path::new(path).symlink_metadata().is_ok() {
+            self.backup(path)
+        } else {
+            self.entries.push(JournalEntry::Created(path.to_string()));
+            Ok(())
+        }
+    }
+
+    // replace_path
+    /// Atomically rename the already written `staged` path over `path`, so that `path` never stops existing.
+    /// Files and links that are replaced are hard linked into the backup directory first, anything else is moved out of the way.
+    /// # Errors
+    /// This function will error if the existing path could not be backed up or the staged path could not be renamed.
+    pub fn replace_path(&mut self, path: &str, staged: &str) -> Result<(), Box<dyn Error>> {
+        match Path::new(path).symlink_metadata() {
+            Ok(meta) if !meta.is_dir() => {
+                let backup = self.next_backup()?;
+                debug!("backing up {} to {}", path, backup);
+                if fs::hard_link(path, &backup).is_err() {
+                    copy_path(path, &backup)?;
+                }
+                self.entries.push(JournalEntry::Backup { path: path.to_string(), backup });
+            },
+            _ => self.prepare_path(path)?
+        }
+        fs::rename(staged, path)?;
+        Ok(())
+    }
+
+    // remove_path
+    /// Remove the file or link at `path`, keeping a backup of it.
+    /// # Errors
+    /// This function will error if the path could not be backed up.
+    pub fn remove_path(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+        if Path::new(path).symlink_metadata().is_ok() {
+            self.backup(path)?;
+        }
+        Ok(())
+    }
+
+    // remove_dir
+    /// Remove the empty directory at `path`, recording its metadata.
+    /// # Errors
+    /// This function will error if the directory metadata could not be read or the directory could not be removed.
+    pub fn remove_dir(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+        let meta = fs::metadata(path)?;
+        fs::remove_dir(path)?;
+        self.entries.push(JournalEntry::RemovedDir { path: path.to_string(), owner: meta.uid(), group: meta.gid(), permissions: meta.mode() });
+        Ok(())
+    }
+
+    // create_dir_all
+    /// Create the directory at `path` and any missing parents, recording every directory that gets created.
+    /// If something that is not a directory is in the way, it is backed up and replaced.
+    /// # Errors
+    /// This function will error if a directory could not be created or something in the way could not be backed up.
+    pub fn create_dir_all(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+        let mut missing: Vec<&Path> = vec![];
+        for ancestor in Path::new(path).ancestors() {
+            if ancestor.is_dir() { break; }
+            missing.push(ancestor);
+        }
+        for dir in missing.iter().rev() {
+            let Some(dir_str) = dir.to_str() else {
+                return Err("Failed to convert string types".into());
+            };
+            self.prepare_path(dir_str)?;
+            create_dir(dir)?;
+        }
+        Ok(())
+    }
+
+    // record_metadata
+    /// Record the current ownership and permissions of `path` before they are changed.
+    /// # Errors
+    /// This function will error if the metadata could not be read.
+    pub fn record_metadata(&mut self, path: &str) -> Result<(), Box<dyn Error>> {
+        let meta = fs::metadata(path)?;
+        self.entries.push(JournalEntry::Metadata { path: path.to_string(), owner: meta.uid(), group: meta.gid(), permissions: meta.mode() });
+        Ok(())
+    }
+
+    // rollback
+    /// Undo every recorded change, newest first, then remove the backup directory.
+    /// Rolling back continues past individual failures so that as much as possible is restored.
+    /// # Errors
+    /// This function will error with every failure encountered if any change could not be undone.
+    pub fn rollback(self) -> Result<(), Box<dyn Error>> {
+        let mut failures: Vec<String> = vec![];
+        for entry in self.entries.iter().rev() {
+            debug!("rolling back {:?}", entry);
+            let res: Result<(), Box<dyn Error>> = match entry {
+                JournalEntry::Created(path) => remove_any(path),
+                JournalEntry::Backup { path, backup } => remove_any(path).and_then(|()| move_path(backup, path)),
+                JournalEntry::RemovedDir { path, owner, group, permissions } => {
+                    create_dir(path).map_err(Into::into).and_then(|()| restore_metadata(path, *owner, *group, *permissions))
+                },
+                JournalEntry::Metadata { path, owner, group, permissions } => restore_metadata(path, *owner, *group, *permissions)
+            };
+            if let Err(e) = res {
+                failures.push(format!("{entry:?}: {e}"));
+            }
+        }
+        if let Err(e) = remove_any(&self.backup_dir) {
+            failures.push(format!("failed to remove journal backups: {e}"));
+        }
+        if failures.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Rollback incomplete:\n{}", failures.join("\n")).into())
+        }
+    }
+
+    // commit
+    /// Keep every recorded change and throw away the backups.
+    /// # Errors
+    /// This function will error if the backup directory could not be removed.
+    pub fn commit(self) -> Result<(), Box<dyn Error>> {
+        remove_any(&self.backup_dir)
+    }
+}
+
+// remove_any
+/// Remove whatever is at `path`, if anything. Directories are removed recursively.
+//
+fn remove_any(path: &str) -> Result<(), Box<dyn Error>> {
+    match Path::new(path).symlink_metadata() {
+        Ok(meta) if meta.is_dir() => remove_dir_all(path)?,
+        Ok(_) => remove_file(path)?,
+        Err(_) => ()
+    }
+    Ok(())
+}
+
+// move_path
+/// Move `from` to `to`, falling back to copying files and links if they are on different filesystems.
+//
+fn move_path(from: &str, to: &str) -> Result<(), Box<dyn Error>> {
+    if fs::rename(from, to).is_ok() {
+        return Ok(());
+    }
+    copy_path(from, to)?;
+    remove_file(from)?;
+    Ok(())
+}
+
+// copy_path
+/// Copy the file or link at `from` to `to`, keeping its ownership and permissions.
+//
+fn copy_path(from: &str, to: &str) -> Result<(), Box<dyn Error>> {
+    let meta = Path::new(from).symlink_metadata()?;
+    if meta.file_type().is_symlink() {
+        symlink(fs::read_link(from)?, to)?;
+    } else if meta.is_file() {
+        fs::copy(from, to)?;
+        restore_metadata(to, meta.uid(), meta.gid(), meta.mode())?;
+    } else {
+        return Err(format!("Unable to copy directory {from} to {to}").into());
+    }
+    Ok(())
+}
+
+// restore_metadata
+/// Set the ownership and permissions of `path`.
+//
+fn restore_metadata(path: &str, owner: u32, group: u32, permissions: u32) -> Result<(), Box<dyn Error>> {
+    path.set_owner(owner)?;
+    path.set_group(group)?;
+    set_permissions(path, Permissions::from_mode(permissions))?;
+    Ok(())
+}
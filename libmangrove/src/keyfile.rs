@@ -0,0 +1,169 @@
+//! # Key files
+//! Key files are how keys are handed around outside of the trustcache and the key store, as small TOML files that carry
+//! the name and fingerprint of the key along with it. A public key file holds the public key only, and a private key file
+//! holds the key store entry of the key, still encrypted with its passphrase.
+
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::crypt::PublicKey;
+use crate::keystore::{EncryptedKey, KEYSTORE_KDF, write_private};
+
+/// The version of the key file format
+pub const KEYFILE_VERSION: u8 = 1;
+
+// KeyFileKind
+/// What kind of key a key file holds
+//
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum KeyFileKind {
+    /// A public key
+    Public,
+    /// A private key, encrypted with a passphrase
+    Private
+}
+
+// KeyFile
+/// A public or private key file
+//
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct KeyFile {
+    /// The version of the key file format, see `KEYFILE_VERSION`
+    pub version: u8,
+    /// What kind of key the file holds
+    pub kind: KeyFileKind,
+    /// The base64-encoded anonymous public key
+    pub key: String,
+    /// The fingerprint of the public key, see `PublicKey::fingerprint`
+    pub fingerprint: String,
+    /// The name of the key, if it has one
+    #[serde(default)]
+    pub name: String,
+    /// A comment about the key
+    #[serde(default)]
+    pub comment: String,
+    /// The key store entry of a private key
+    pub private: Option<EncryptedKey>
+}
+
+impl KeyFile {
+    // public
+    /// Create a public key file
+    /// ```
+    /// use libmangrove::crypt::PrivateKey;
+    /// use libmangrove::keyfile::KeyFile;
+    /// let key = PrivateKey::generate(String::from("test_key")).derive();
+    /// let keyfile = KeyFile::public(&key, "");
+    /// let loaded = KeyFile::from_bytes(keyfile.to_string().unwrap().as_bytes()).unwrap();
+    /// assert_eq!(loaded.public_key().unwrap().name, "test_key");
+    /// ```
+    //
+    pub fn public(key: &PublicKey, comment: &str) -> Self {
+        Self {
+            version: KEYFILE_VERSION,
+            kind: KeyFileKind::Public,
+            key: key.to_anonymous(),
+            fingerprint: key.fingerprint(),
+            name: if key.name == "__anonymous__" { String::new() } else { key.name.clone() },
+            comment: comment.to_string(),
+            private: None
+        }
+    }
+
+    // private
+    /// Create a private key file from the key store entry of a key
+    //
+    pub fn private(entry: EncryptedKey, comment: &str) -> Self {
+        Self {
+            version: KEYFILE_VERSION,
+            kind: KeyFileKind::Private,
+            key: entry.key.clone(),
+            fingerprint: entry.fingerprint.clone(),
+            name: entry.name.clone(),
+            comment: comment.to_string(),
+            private: Some(entry)
+        }
+    }
+
+    // validate
+    /// Check that the key file is well-formed, and that its fingerprint and private key belong to its public key
+    /// # Errors
+    /// This function will error if the key file has an unsupported version, an invalid key, a fingerprint that does not match
+    /// its key, or a private key that does not match its kind or its public key, or is encrypted with an unknown key derivation function.
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.version != KEYFILE_VERSION {
+            return Err(format!("Unsupported key file version {}", self.version).into());
+        }
+        let Ok(key) = PublicKey::from_anonymous(&self.key) else {
+            return Err("The key file does not hold a valid public key".into());
+        };
+        if key.fingerprint() != self.fingerprint {
+            return Err(format!("The fingerprint {} of the key file does not match its key {}", self.fingerprint, key.fingerprint()).into());
+        }
+        match (self.kind, &self.private) {
+            (KeyFileKind::Public, Some(_)) => Err("The public key file holds a private key".into()),
+            (KeyFileKind::Private, None) => Err("The private key file does not hold a private key".into()),
+            (KeyFileKind::Private, Some(entry)) if entry.key != self.key || entry.fingerprint != self.fingerprint => Err(format!("The private key in the key file does not belong to the key {}", self.fingerprint).into()),
+            (KeyFileKind::Private, Some(entry)) if entry.kdf != KEYSTORE_KDF => Err(format!("The private key in the key file is encrypted with an unknown key derivation function {}", entry.kdf).into()),
+            _ => Ok(())
+        }
+    }
+
+    // public_key
+    /// Get the public key of the key file, named after the key file if it has a name
+    /// # Errors
+    /// This function will error if the public key of the key file is invalid.
+    pub fn public_key(&self) -> Result<PublicKey, Box<dyn Error>> {
+        let mut key = PublicKey::from_anonymous(&self.key)?;
+        if !self.name.is_empty() {
+            key.name.clone_from(&self.name);
+        }
+        Ok(key)
+    }
+
+    // from_bytes
+    /// Parse and validate a key file
+    /// # Errors
+    /// This function will error if the data is not a key file, or the key file is invalid, see `validate`.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        let keyfile: Self = match toml::from_slice(data) {
+            Ok(k) => k,
+            Err(e) => return Err(format!("The data is not a key file: {e}").into())
+        };
+        keyfile.validate()?;
+        Ok(keyfile)
+    }
+
+    // to_string
+    /// Serialize the key file into TOML
+    /// # Errors
+    /// This function will error if the key file could not be serialized.
+    pub fn to_string(&self) -> Result<String, Box<dyn Error>> {
+        Ok(toml::to_string_pretty(self)?)
+    }
+}
+
+// keyfile_load
+/// Load a key file from disk
+/// # Errors
+/// This function will error if the file could not be read, or is not a valid key file.
+pub fn keyfile_load(path: &Path) -> Result<KeyFile, Box<dyn Error>> {
+    KeyFile::from_bytes(&fs::read(path)?)
+}
+
+// keyfile_save
+/// Save a key file to disk. Private key files are only readable by their owner.
+/// # Errors
+/// This function will error if the key file could not be serialized or written.
+pub fn keyfile_save(keyfile: &KeyFile, path: &Path) -> Result<(), Box<dyn Error>> {
+    let data = keyfile.to_string()?;
+    match keyfile.kind {
+        KeyFileKind::Public => fs::write(path, data)?,
+        KeyFileKind::Private => write_private(&path.to_string_lossy(), data.as_bytes())?
+    }
+    Ok(())
+}
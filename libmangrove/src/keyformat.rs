@@ -0,0 +1,463 @@
+//! # Key formats
+//! Conversion of keys between the anonymous form Mangrove stores them in and the formats of other tooling:
+//! OpenSSH public and private keys, PKCS#8 PEM private keys and SPKI PEM public keys, see RFC 8410.
+//! Only unencrypted ed25519 keys can be converted, as those are the only keys Mangrove can use.
+
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::str::FromStr;
+
+use ed25519_dalek::{Keypair, PublicKey as VerifyingKey, SecretKey};
+use rand_dalek::RngCore;
+use rand_dalek::rngs::OsRng;
+
+use crate::crypt::{PrivateKey, PublicKey};
+
+/// The key type of ed25519 keys in OpenSSH
+pub const OPENSSH_KEY_TYPE: &str = "ssh-ed25519";
+/// The magic at the start of OpenSSH private keys
+pub const OPENSSH_PRIVATE_MAGIC: &[u8] = b"openssh-key-v1\0";
+/// The PEM label of OpenSSH private keys
+pub const OPENSSH_PRIVATE_LABEL: &str = "OPENSSH PRIVATE KEY";
+/// The PEM label of PKCS#8 private keys
+pub const PKCS8_LABEL: &str = "PRIVATE KEY";
+/// The PEM label of encrypted PKCS#8 private keys, which cannot be converted
+pub const PKCS8_ENCRYPTED_LABEL: &str = "ENCRYPTED PRIVATE KEY";
+/// The PEM label of SPKI public keys
+pub const SPKI_LABEL: &str = "PUBLIC KEY";
+/// The DER encoding of the ed25519 object identifier, 1.3.101.112
+pub const ED25519_OID: [u8; 5] = [0x06, 0x03, 0x2b, 0x65, 0x70];
+
+const DER_SEQUENCE: u8 = 0x30;
+const DER_INTEGER: u8 = 0x02;
+const DER_OCTET_STRING: u8 = 0x04;
+const DER_BIT_STRING: u8 = 0x03;
+const DER_PUBLIC_KEY: u8 = 0x81;
+
+// KeyFormat
+/// The formats keys can be exported in
+//
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum KeyFormat {
+    /// A Mangrove key file, see `crate::keyfile`
+    #[default]
+    Mangrove,
+    /// OpenSSH, an `authorized_keys` line for public keys and an OPENSSH PRIVATE KEY for private keys
+    OpenSsh,
+    /// PEM, SPKI for public keys and PKCS#8 for private keys
+    Pem
+}
+
+impl Display for KeyFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Mangrove => write!(f, "mangrove"),
+            Self::OpenSsh => write!(f, "openssh"),
+            Self::Pem => write!(f, "pem")
+        }
+    }
+}
+
+impl FromStr for KeyFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mangrove" => Ok(Self::Mangrove),
+            "openssh" | "ssh" => Ok(Self::OpenSsh),
+            "pem" | "pkcs8" | "spki" => Ok(Self::Pem),
+            _ => Err(format!("unknown key format {s}, expected mangrove, openssh or pem"))
+        }
+    }
+}
+
+// ParsedKey
+/// A key read from any of the supported formats
+//
+#[derive(Debug)]
+pub enum ParsedKey {
+    /// A public key
+    Public(PublicKey),
+    /// A private key
+    Private(PrivateKey)
+}
+
+// parse_key
+/// Read a public or private key in any of the supported formats: the anonymous form, an OpenSSH public or private key,
+/// or a PKCS#8 or SPKI PEM file. OpenSSH keys are named after their comment.
+/// ```
+/// use libmangrove::crypt::PrivateKey;
+/// use libmangrove::keyformat::{parse_key, ParsedKey};
+/// let key = PrivateKey::generate(String::from("test_key"));
+/// let ParsedKey::Private(parsed) = parse_key(&key.to_openssh()).unwrap() else { panic!() };
+/// assert_eq!(parsed.to_anonymous(), key.to_anonymous());
+/// assert_eq!(parsed.name, "test_key");
+/// ```
+/// # Errors
+/// This function will error if the data is not a key in a supported format, or the key is encrypted or not an ed25519 key.
+pub fn parse_key(data: &str) -> Result<ParsedKey, Box<dyn Error>> {
+    let data = data.trim();
+    if data.contains(&pem_begin(OPENSSH_PRIVATE_LABEL)) {
+        Ok(ParsedKey::Private(PrivateKey::from_openssh(data)?))
+    } else if data.contains(&pem_begin(PKCS8_ENCRYPTED_LABEL)) {
+        Err("Encrypted PKCS#8 private keys are not supported, decrypt the key first".into())
+    } else if data.contains(&pem_begin(PKCS8_LABEL)) {
+        Ok(ParsedKey::Private(PrivateKey::from_pem(data)?))
+    } else if data.contains(&pem_begin(SPKI_LABEL)) {
+        Ok(ParsedKey::Public(PublicKey::from_pem(data)?))
+    } else if data.starts_with("ssh-") {
+        Ok(ParsedKey::Public(PublicKey::from_openssh(data)?))
+    } else if let Ok(sk) = PrivateKey::from_anonymous(&data.to_string()) {
+        Ok(ParsedKey::Private(sk))
+    } else if let Ok(pk) = PublicKey::from_anonymous(&data.to_string()) {
+        Ok(ParsedKey::Public(pk))
+    } else {
+        Err("The data is not a key in any supported format".into())
+    }
+}
+
+impl PublicKey {
+    // to_openssh
+    /// Serialize this `PublicKey` into an OpenSSH public key, as found in `authorized_keys`, with the name of the key as its comment
+    //
+    pub fn to_openssh(&self) -> String {
+        let blob = openssh_public_blob(&self.key_data);
+        if self.name == "__anonymous__" || self.name.is_empty() {
+            format!("{OPENSSH_KEY_TYPE} {}", base64::encode(blob))
+        } else {
+            format!("{OPENSSH_KEY_TYPE} {} {}", base64::encode(blob), self.name)
+        }
+    }
+
+    // from_openssh
+    /// Read an OpenSSH public key, named after its comment if it has one
+    /// # Errors
+    /// This function will error if the data is not an OpenSSH public key, or not an ed25519 key.
+    pub fn from_openssh(data: &str) -> Result<Self, Box<dyn Error>> {
+        let mut parts = data.trim().splitn(3, char::is_whitespace);
+        let key_type = parts.next().unwrap_or_default();
+        if key_type != OPENSSH_KEY_TYPE {
+            return Err(format!("Unsupported OpenSSH key type {key_type}, only {OPENSSH_KEY_TYPE} keys are supported").into());
+        }
+        let Some(Ok(blob)) = parts.next().map(base64::decode) else {
+            return Err("The OpenSSH public key is not valid base64".into());
+        };
+        let key_data = read_openssh_public_blob(&mut SshReader::new(&blob))?;
+        let comment = parts.next().unwrap_or_default().trim();
+        Ok(Self {
+            name: if comment.is_empty() { String::from("__anonymous__") } else { comment.to_string() },
+            key_data
+        })
+    }
+
+    // to_pem
+    /// Serialize this `PublicKey` into an SPKI PEM public key
+    //
+    pub fn to_pem(&self) -> String {
+        // no unused bits, then the key
+        let mut bits = vec![0u8];
+        bits.extend_from_slice(self.key_data.as_bytes());
+        let mut content = der_encode(DER_SEQUENCE, &ED25519_OID);
+        content.extend(der_encode(DER_BIT_STRING, &bits));
+        pem_encode(SPKI_LABEL, &der_encode(DER_SEQUENCE, &content))
+    }
+
+    // from_pem
+    /// Read an SPKI PEM public key
+    /// # Errors
+    /// This function will error if the data is not an SPKI PEM public key, or not an ed25519 key.
+    pub fn from_pem(data: &str) -> Result<Self, Box<dyn Error>> {
+        let der = pem_decode(data, SPKI_LABEL)?;
+        let (info, _) = der_read(&der, DER_SEQUENCE)?;
+        let (algorithm, rest) = der_read(info, DER_SEQUENCE)?;
+        if algorithm != ED25519_OID {
+            return Err("The public key is not an ed25519 key".into());
+        }
+        let (bits, _) = der_read(rest, DER_BIT_STRING)?;
+        if bits.len() != 33 || bits[0] != 0 {
+            return Err("The SPKI public key has an invalid length".into());
+        }
+        Ok(Self {
+            name: String::from("__anonymous__"),
+            key_data: VerifyingKey::from_bytes(&bits[1..])?
+        })
+    }
+}
+
+impl PrivateKey {
+    // to_openssh
+    /// Serialize this `PrivateKey` into an unencrypted OpenSSH private key, with the name of the key as its comment
+    //
+    pub fn to_openssh(&self) -> String {
+        let public = openssh_public_blob(&self.key_data.public);
+        let check = OsRng {}.next_u32();
+        let mut private = vec![];
+        private.extend_from_slice(&check.to_be_bytes());
+        private.extend_from_slice(&check.to_be_bytes());
+        ssh_write_string(&mut private, OPENSSH_KEY_TYPE.as_bytes());
+        ssh_write_string(&mut private, self.key_data.public.as_bytes());
+        ssh_write_string(&mut private, &self.key_data.to_bytes());
+        ssh_write_string(&mut private, if self.name == "__anonymous__" { b"" } else { self.name.as_bytes() });
+        // pad to the block size of the "none" cipher
+        let padding = (8 - private.len() % 8) % 8;
+        private.extend((1..=8u8).take(padding));
+        let mut data = OPENSSH_PRIVATE_MAGIC.to_vec();
+        ssh_write_string(&mut data, b"none");
+        ssh_write_string(&mut data, b"none");
+        ssh_write_string(&mut data, b"");
+        data.extend_from_slice(&1u32.to_be_bytes());
+        ssh_write_string(&mut data, &public);
+        ssh_write_string(&mut data, &private);
+        pem_encode_width(OPENSSH_PRIVATE_LABEL, &data, 70)
+    }
+
+    // from_openssh
+    /// Read an unencrypted OpenSSH private key, named after its comment if it has one
+    /// # Errors
+    /// This function will error if the data is not an OpenSSH private key, the key is encrypted with a passphrase,
+    /// or it is not a single, consistent ed25519 key.
+    pub fn from_openssh(data: &str) -> Result<Self, Box<dyn Error>> {
+        let data = pem_decode(data, OPENSSH_PRIVATE_LABEL)?;
+        if !data.starts_with(OPENSSH_PRIVATE_MAGIC) {
+            return Err("The data is not an OpenSSH private key".into());
+        }
+        let mut reader = SshReader::new(&data[OPENSSH_PRIVATE_MAGIC.len()..]);
+        let cipher = reader.string()?;
+        if cipher != b"none" {
+            return Err("The OpenSSH private key is encrypted with a passphrase, which is not supported. Remove it with ssh-keygen -p first".into());
+        }
+        reader.string()?; // kdf name
+        reader.string()?; // kdf options
+        let count = reader.u32()?;
+        if count != 1 {
+            return Err(format!("The OpenSSH private key file holds {count} keys, expected 1").into());
+        }
+        let public = read_openssh_public_blob(&mut SshReader::new(reader.string()?))?;
+        let mut private = SshReader::new(reader.string()?);
+        if private.u32()? != private.u32()? {
+            return Err("The OpenSSH private key is corrupt (check bytes do not match)".into());
+        }
+        let key_type = private.string()?;
+        if key_type != OPENSSH_KEY_TYPE.as_bytes() {
+            return Err(format!("Unsupported OpenSSH key type {}, only {OPENSSH_KEY_TYPE} keys are supported", String::from_utf8_lossy(key_type)).into());
+        }
+        let inner_public = private.string()?;
+        let keypair = private.string()?;
+        if keypair.len() != 64 {
+            return Err("The OpenSSH private key has an invalid length".into());
+        }
+        let comment = String::from_utf8(private.string()?.to_vec())?;
+        let key_data = keypair_from_seed(&keypair[..32])?;
+        if key_data.public != public || inner_public != public.as_bytes() || &keypair[32..] != public.as_bytes() {
+            return Err("The OpenSSH private key does not match its public key".into());
+        }
+        Ok(Self {
+            name: if comment.is_empty() { String::from("__anonymous__") } else { comment },
+            key_data
+        })
+    }
+
+    // to_pem
+    /// Serialize this `PrivateKey` into an unencrypted PKCS#8 PEM private key
+    //
+    pub fn to_pem(&self) -> String {
+        let mut content = der_encode(DER_INTEGER, &[0]);
+        content.extend(der_encode(DER_SEQUENCE, &ED25519_OID));
+        content.extend(der_encode(DER_OCTET_STRING, &der_encode(DER_OCTET_STRING, self.key_data.secret.as_bytes())));
+        pem_encode(PKCS8_LABEL, &der_encode(DER_SEQUENCE, &content))
+    }
+
+    // from_pem
+    /// Read an unencrypted PKCS#8 PEM private key, of either version of RFC 8410
+    /// # Errors
+    /// This function will error if the data is not a PKCS#8 PEM private key, or not a consistent ed25519 key.
+    pub fn from_pem(data: &str) -> Result<Self, Box<dyn Error>> {
+        let der = pem_decode(data, PKCS8_LABEL)?;
+        let (info, _) = der_read(&der, DER_SEQUENCE)?;
+        let (version, rest) = der_read(info, DER_INTEGER)?;
+        if version != [0] && version != [1] {
+            return Err("Unsupported PKCS#8 version".into());
+        }
+        let (algorithm, rest) = der_read(rest, DER_SEQUENCE)?;
+        if algorithm != ED25519_OID {
+            return Err("The private key is not an ed25519 key".into());
+        }
+        let (private, mut rest) = der_read(rest, DER_OCTET_STRING)?;
+        let (seed, _) = der_read(private, DER_OCTET_STRING)?;
+        let key_data = keypair_from_seed(seed)?;
+        // skip the attributes, and check the public key if there is one
+        while !rest.is_empty() {
+            let tag = rest[0];
+            let (value, next) = der_read(rest, tag)?;
+            if tag == DER_PUBLIC_KEY && (value.len() != 33 || &value[1..] != key_data.public.as_bytes()) {
+                return Err("The PKCS#8 private key does not match its public key".into());
+            }
+            rest = next;
+        }
+        Ok(Self {
+            name: String::from("__anonymous__"),
+            key_data
+        })
+    }
+}
+
+// keypair_from_seed
+/// Create a keypair from a 32 byte ed25519 seed, which is what other tooling calls the private key
+//
+fn keypair_from_seed(seed: &[u8]) -> Result<Keypair, Box<dyn Error>> {
+    let secret = SecretKey::from_bytes(seed)?;
+    let public = VerifyingKey::from(&secret);
+    Ok(Keypair { secret, public })
+}
+
+// SshReader
+/// Reads the integers and strings of the SSH wire format, see RFC 4251
+//
+struct SshReader<'a> {
+    data: &'a [u8]
+}
+
+impl<'a> SshReader<'a> {
+    const fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    fn u32(&mut self) -> Result<u32, Box<dyn Error>> {
+        if self.data.len() < 4 {
+            return Err("The OpenSSH key is truncated".into());
+        }
+        let value = u32::from_be_bytes([self.data[0], self.data[1], self.data[2], self.data[3]]);
+        self.data = &self.data[4..];
+        Ok(value)
+    }
+
+    fn string(&mut self) -> Result<&'a [u8], Box<dyn Error>> {
+        let len = self.u32()? as usize;
+        if self.data.len() < len {
+            return Err("The OpenSSH key is truncated".into());
+        }
+        let (value, rest) = self.data.split_at(len);
+        self.data = rest;
+        Ok(value)
+    }
+}
+
+// ssh_write_string
+/// Append a string in the SSH wire format, its length followed by the data
+//
+fn ssh_write_string(out: &mut Vec<u8>, data: &[u8]) {
+    out.extend_from_slice(&u32::try_from(data.len()).unwrap_or(u32::MAX).to_be_bytes());
+    out.extend_from_slice(data);
+}
+
+// openssh_public_blob
+/// The OpenSSH encoding of an ed25519 public key, its key type followed by the key
+//
+fn openssh_public_blob(key: &VerifyingKey) -> Vec<u8> {
+    let mut blob = vec![];
+    ssh_write_string(&mut blob, OPENSSH_KEY_TYPE.as_bytes());
+    ssh_write_string(&mut blob, key.as_bytes());
+    blob
+}
+
+// read_openssh_public_blob
+/// Read the OpenSSH encoding of an ed25519 public key
+//
+fn read_openssh_public_blob(reader: &mut SshReader) -> Result<VerifyingKey, Box<dyn Error>> {
+    let key_type = reader.string()?;
+    if key_type != OPENSSH_KEY_TYPE.as_bytes() {
+        return Err(format!("Unsupported OpenSSH key type {}, only {OPENSSH_KEY_TYPE} keys are supported", String::from_utf8_lossy(key_type)).into());
+    }
+    Ok(VerifyingKey::from_bytes(reader.string()?)?)
+}
+
+// der_encode
+/// Encode a DER value with a tag. Only lengths below 65536 are supported, which is plenty for keys.
+//
+fn der_encode(tag: u8, value: &[u8]) -> Vec<u8> {
+    let mut out = vec![tag];
+    let [.., high, low] = value.len().to_be_bytes();
+    match value.len() {
+        len if len < 0x80 => out.push(low),
+        len if len < 0x100 => out.extend_from_slice(&[0x81, low]),
+        _ => out.extend_from_slice(&[0x82, high, low])
+    }
+    out.extend_from_slice(value);
+    out
+}
+
+// DerValue
+/// The content of a DER value, and the data after it
+//
+type DerValue<'a> = (&'a [u8], &'a [u8]);
+
+// der_read
+/// Read a DER value with the expected tag, returning its content and the data after it
+//
+fn der_read(data: &[u8], tag: u8) -> Result<DerValue<'_>, Box<dyn Error>> {
+    if data.len() < 2 {
+        return Err("The key is truncated".into());
+    }
+    if data[0] != tag {
+        return Err(format!("The key is malformed (expected DER tag {tag:#04x}, found {:#04x})", data[0]).into());
+    }
+    let (len, start) = match data[1] {
+        len if len < 0x80 => (len as usize, 2),
+        0x81 if data.len() >= 3 => (data[2] as usize, 3),
+        0x82 if data.len() >= 4 => (((data[2] as usize) << 8) | data[3] as usize, 4),
+        _ => return Err("The key is malformed (unsupported DER length)".into())
+    };
+    if data.len() < start + len {
+        return Err("The key is truncated".into());
+    }
+    Ok((&data[start..start + len], &data[start + len..]))
+}
+
+// pem_begin
+/// The line a PEM block with this label begins with
+//
+fn pem_begin(label: &str) -> String {
+    format!("-----BEGIN {label}-----")
+}
+
+// pem_encode
+/// Wrap data in a PEM block with this label, with lines of 64 characters
+//
+fn pem_encode(label: &str, data: &[u8]) -> String {
+    pem_encode_width(label, data, 64)
+}
+
+// pem_encode_width
+/// Wrap data in a PEM block with this label, with lines of the given width
+//
+fn pem_encode_width(label: &str, data: &[u8], width: usize) -> String {
+    let encoded = base64::encode(data);
+    let mut out = pem_begin(label);
+    out.push('\n');
+    for line in encoded.as_bytes().chunks(width) {
+        out.push_str(&String::from_utf8_lossy(line));
+        out.push('\n');
+    }
+    format!("{out}-----END {label}-----\n")
+}
+
+// pem_decode
+/// Get the data of the PEM block with this label
+//
+fn pem_decode(data: &str, label: &str) -> Result<Vec<u8>, Box<dyn Error>> {
+    let begin = pem_begin(label);
+    let end = format!("-----END {label}-----");
+    let Some(start) = data.find(&begin) else {
+        return Err(format!("The data is not a {label} PEM block").into());
+    };
+    let body = &data[start + begin.len()..];
+    let Some(stop) = body.find(&end) else {
+        return Err(format!("The {label} PEM block is not terminated").into());
+    };
+    let encoded: String = body[..stop].chars().filter(|c| !c.is_whitespace()).collect();
+    match base64::decode(encoded) {
+        Ok(d) => Ok(d),
+        Err(e) => Err(format!("The {label} PEM block is not valid base64: {e}").into())
+    }
+}
@@ -0,0 +1,273 @@
+//! # Key store
+//! Private keys are kept apart from the trustcache, in a key store that is usually located at /etc/mangrove/keys.toml.
+//! Every key in it is encrypted with a passphrase, with an encryption key derived from the passphrase with Argon2id,
+//! and the file is only readable by its owner. The public key of every entry is stored in the clear, so keys can be
+//! looked up and listed without the passphrase.
+
+use std::error::Error;
+use std::fs;
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::Path;
+
+use aes_gcm::{Aes256Gcm, KeyInit, Nonce};
+use aes_gcm::aead::{Aead, Payload};
+use argon2::{Algorithm, Argon2, Params, Version};
+use ed25519_dalek::Keypair;
+use rand_dalek::RngCore;
+use rand_dalek::rngs::OsRng;
+use serde::{Deserialize, Serialize};
+
+use crate::config::get_keystore_file;
+use crate::crypt::{PrivateKey, PublicKey};
+use crate::trustcache::{is_pk_blacklisted, key_metadata, Trustcache};
+
+/// The name of the only key derivation function entries are encrypted with so far
+pub const KEYSTORE_KDF: &str = "argon2id";
+/// The length of the salt of every entry
+pub const KEYSTORE_SALT_LEN: usize = 16;
+/// The length of the AES-256-GCM nonce of every entry
+pub const KEYSTORE_NONCE_LEN: usize = 12;
+
+// KdfParams
+/// The cost parameters of Argon2id, see RFC 9106
+//
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KdfParams {
+    /// The memory cost, in KiB
+    pub m_cost: u32,
+    /// The number of passes over the memory
+    pub t_cost: u32,
+    /// The degree of parallelism
+    pub p_cost: u32
+}
+
+impl Default for KdfParams {
+    // default
+    /// The second recommended option of RFC 9106: 64 MiB of memory, 3 passes and 4 lanes
+    //
+    fn default() -> Self {
+        Self { m_cost: 64 * 1024, t_cost: 3, p_cost: 4 }
+    }
+}
+
+// EncryptedKey
+/// A private key in the key store, encrypted with a passphrase
+//
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct EncryptedKey {
+    /// The base64-encoded anonymous public key of the private key
+    pub key: String,
+    /// The fingerprint of the public key, see `PublicKey::fingerprint`
+    pub fingerprint: String,
+    /// The name of the key, if it has one
+    #[serde(default)]
+    pub name: String,
+    /// The key derivation function the encryption key is derived from the passphrase with, always `argon2id` for now
+    pub kdf: String,
+    /// The base64-encoded salt of the key derivation function
+    pub salt: String,
+    /// The base64-encoded AES-256-GCM nonce
+    pub nonce: String,
+    /// The base64-encoded encrypted keypair. The public key is authenticated along with it, so entries cannot be swapped.
+    pub ciphertext: String,
+    /// The cost parameters of the key derivation function
+    pub params: KdfParams
+}
+
+// derive_cipher
+/// Derive the cipher an entry is encrypted with from its passphrase
+//
+fn derive_cipher(passphrase: &[u8], salt: &[u8], params: KdfParams) -> Result<Aes256Gcm, Box<dyn Error>> {
+    let params = match Params::new(params.m_cost, params.t_cost, params.p_cost, Some(32)) {
+        Ok(p) => p,
+        Err(e) => return Err(format!("Invalid key derivation parameters: {e}").into())
+    };
+    let mut key = [0u8; 32];
+    if let Err(e) = Argon2::new(Algorithm::Argon2id, Version::V0x13, params).hash_password_into(passphrase, salt, &mut key) {
+        return Err(format!("Failed to derive the encryption key from the passphrase: {e}").into());
+    }
+    Ok(Aes256Gcm::new(&key.into()))
+}
+
+impl EncryptedKey {
+    // encrypt
+    /// Encrypt a private key with a passphrase, with a fresh salt and nonce.
+    /// ```
+    /// use libmangrove::crypt::PrivateKey;
+    /// use libmangrove::keystore::{EncryptedKey, KdfParams};
+    /// let key = PrivateKey::generate(String::from("test_key"));
+    /// let params = KdfParams { m_cost: 64, t_cost: 1, p_cost: 1 };
+    /// let encrypted = EncryptedKey::encrypt(&key, b"passphrase", params).unwrap();
+    /// assert_eq!(encrypted.decrypt(b"passphrase").unwrap().to_anonymous(), key.to_anonymous());
+    /// assert!(encrypted.decrypt(b"wrong").is_err());
+    /// ```
+    /// # Errors
+    /// This function will error if the key derivation parameters are invalid, or the key could not be encrypted.
+    pub fn encrypt(key: &PrivateKey, passphrase: &[u8], params: KdfParams) -> Result<Self, Box<dyn Error>> {
+        let mut salt = [0u8; KEYSTORE_SALT_LEN];
+        let mut nonce = [0u8; KEYSTORE_NONCE_LEN];
+        let mut rng = OsRng {};
+        rng.fill_bytes(&mut salt);
+        rng.fill_bytes(&mut nonce);
+        let public = key.derive();
+        let cipher = derive_cipher(passphrase, &salt, params)?;
+        let Ok(ciphertext) = cipher.encrypt(Nonce::from_slice(&nonce), Payload { msg: &key.key_data.to_bytes(), aad: public.key_data.as_bytes() }) else {
+            return Err("Failed to encrypt the private key".into());
+        };
+        Ok(Self {
+            key: public.to_anonymous(),
+            fingerprint: public.fingerprint(),
+            name: if key.name == "__anonymous__" { String::new() } else { key.name.clone() },
+            kdf: KEYSTORE_KDF.to_string(),
+            salt: base64::encode(salt),
+            nonce: base64::encode(nonce),
+            ciphertext: base64::encode(ciphertext),
+            params
+        })
+    }
+
+    // decrypt
+    /// Decrypt the private key with its passphrase. The key is given the name of the entry, if it has one.
+    /// # Errors
+    /// This function will error if the passphrase is wrong, the entry is corrupt, or it uses an unknown key derivation function.
+    pub fn decrypt(&self, passphrase: &[u8]) -> Result<PrivateKey, Box<dyn Error>> {
+        if self.kdf != KEYSTORE_KDF {
+            return Err(format!("The key {} is encrypted with an unknown key derivation function {}", self.fingerprint, self.kdf).into());
+        }
+        let public = self.public_key()?;
+        let salt = base64::decode(&self.salt)?;
+        let nonce = base64::decode(&self.nonce)?;
+        if nonce.len() != KEYSTORE_NONCE_LEN {
+            return Err(format!("The key {} is corrupt (nonce of {} bytes, expected {KEYSTORE_NONCE_LEN})", self.fingerprint, nonce.len()).into());
+        }
+        let cipher = derive_cipher(passphrase, &salt, self.params)?;
+        let ciphertext = base64::decode(&self.ciphertext)?;
+        let Ok(keypair) = cipher.decrypt(Nonce::from_slice(&nonce), Payload { msg: &ciphertext, aad: public.key_data.as_bytes() }) else {
+            return Err(format!("Wrong passphrase for the key {}, or the key store entry is corrupt", self.fingerprint).into());
+        };
+        let key_data = Keypair::from_bytes(&keypair)?;
+        if key_data.public != public.key_data {
+            return Err(format!("The key store entry for {} holds another key", self.fingerprint).into());
+        }
+        Ok(PrivateKey {
+            name: if self.name.is_empty() { String::from("__anonymous__") } else { self.name.clone() },
+            key_data
+        })
+    }
+
+    // public_key
+    /// Get the public key of the entry, which does not need the passphrase
+    /// # Errors
+    /// This function will error if the public key of the entry is invalid.
+    pub fn public_key(&self) -> Result<PublicKey, Box<dyn Error>> {
+        let mut key = PublicKey::from_anonymous(&self.key)?;
+        if !self.name.is_empty() {
+            key.name.clone_from(&self.name);
+        }
+        Ok(key)
+    }
+}
+
+// KeyStore
+/// The key store, a list of encrypted private keys
+//
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct KeyStore {
+    /// The encrypted private keys
+    #[serde(default)]
+    pub keys: Vec<EncryptedKey>
+}
+
+impl KeyStore {
+    // add
+    /// Add an encrypted key to the key store, replacing the entry for the same key if there is one
+    //
+    pub fn add(&mut self, entry: EncryptedKey) {
+        self.keys.retain(|x| x.key != entry.key);
+        self.keys.push(entry);
+    }
+
+    // remove
+    /// Remove the entry for a public key from the key store, returning whether there was one
+    //
+    pub fn remove(&mut self, key: &PublicKey) -> bool {
+        let anonymous = key.to_anonymous();
+        let len = self.keys.len();
+        self.keys.retain(|x| x.key != anonymous);
+        self.keys.len() != len
+    }
+
+    // find
+    /// Find an entry by its name, or by a prefix of its fingerprint or public key.
+    /// Fingerprints are computed from the public key of each entry, never taken from the stored `fingerprint` field,
+    /// and entries with an invalid public key are skipped.
+    //
+    pub fn find(&self, query: &str) -> Option<&EncryptedKey> {
+        if query.is_empty() { return None; }
+        self.keys.iter().find(|x| x.name == query)
+            .or_else(|| self.keys.iter().find(|x| {
+                x.public_key().is_ok_and(|key| key.fingerprint().starts_with(query) || x.key.starts_with(query))
+            }))
+    }
+}
+
+// write_private
+/// Write a file that only its owner may read and write, fixing the permissions of the file if it already exists
+/// # Errors
+/// This function will error if the file could not be written, or its permissions could not be set.
+pub fn write_private(path: &str, data: &[u8]) -> Result<(), Box<dyn Error>> {
+    let mut file = OpenOptions::new().write(true).create(true).truncate(true).mode(0o600).open(path)?;
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(data)?;
+    Ok(())
+}
+
+// keystore_load
+/// Load the key store from disk. A key store that does not exist yet is empty.
+/// # Errors
+/// This function will error if the key store could not be read or deserialized.
+pub fn keystore_load(use_local_keystore: bool) -> Result<KeyStore, Box<dyn Error>> {
+    let path = get_keystore_file(use_local_keystore);
+    if !Path::new(&path).exists() {
+        return Ok(KeyStore::default());
+    }
+    Ok(toml::from_slice(&fs::read(path)?)?)
+}
+
+// keystore_save
+/// Save the key store to disk, readable by its owner only.
+/// # Errors
+/// This function will error if the key store could not be serialized or written.
+pub fn keystore_save(keystore: &KeyStore, use_local_keystore: bool) -> Result<(), Box<dyn Error>> {
+    write_private(&get_keystore_file(use_local_keystore), toml::to_string_pretty(keystore)?.as_bytes())
+}
+
+// migrate_privkeys
+/// Move the plaintext private keys in the allowlist of the trustcache into the key store, encrypted with the passphrase,
+/// returning how many keys were moved.
+///
+/// The public key of every moved key is added to the allowlist in its place, unless it is blacklisted, so the keys stay trusted,
+/// and keys keep the name recorded for them. The private keys in the blacklist are left where they are, as they are only kept to refuse them.
+/// # Errors
+/// This function will error if a private key in the trustcache is invalid, or a key could not be encrypted.
+pub fn migrate_privkeys(trustcache: &mut Trustcache, keystore: &mut KeyStore, passphrase: &[u8], params: KdfParams) -> Result<usize, Box<dyn Error>> {
+    let privkeys = trustcache.keydb.known_privkeys.clone();
+    for anonymous in &privkeys {
+        let mut key = PrivateKey::from_anonymous(anonymous)?;
+        let public = key.derive();
+        if let Some(metadata) = key_metadata(trustcache, &public) {
+            if !metadata.name.is_empty() {
+                key.name.clone_from(&metadata.name);
+            }
+        }
+        keystore.add(EncryptedKey::encrypt(&key, passphrase, params)?);
+        let public_anonymous = public.to_anonymous();
+        if !trustcache.keydb.known_pubkeys.contains(&public_anonymous) && !is_pk_blacklisted(trustcache, &public)? {
+            trustcache.keydb.known_pubkeys.push(public_anonymous);
+        }
+        trustcache.keydb.known_privkeys.retain(|x| x != anonymous);
+    }
+    Ok(privkeys.len())
+}
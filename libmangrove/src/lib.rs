@@ -26,15 +26,25 @@ extern crate core;
 
 use git_version::git_version;
 
+pub mod agent; // Signing agent
 pub mod crypt; // Various cryptographic helper functions to remove repetitive code
 pub mod db; // Package database
 pub mod file; // Traits, structs, and functions for interfacing with the filesystem
+pub mod journal; // Journaling and rollback of filesystem changes
+pub mod keyfile; // Public and private key files
+pub mod keyformat; // OpenSSH and PEM keys
+pub mod keystore; // Passphrase-protected private keys
 pub mod pkg; // Structs and functions for dealing with Packages
 pub mod pkginfo; // Provides implementation of FileOps
 pub mod platform; // Platform-specific code
 pub mod repo; // Structs and functions for dealing with Repositories
+pub mod resolver; // Dependency resolution
+pub mod sig; // Detached signatures
+pub mod spf; // Parsing of the Signed Package Format
 pub mod stropt; // String operations
+pub mod sync; // Repository synchronization
 pub mod test; // Testing
+pub mod transaction; // Transactions
 #[macro_use]
 pub mod aes; // AES helper functions
 pub mod config; // Configuration
@@ -2,28 +2,32 @@
 
 use std::collections::HashMap;
 use std::error::Error;
-use std::fmt::Debug;
+use std::fmt::{self, Debug, Display, Formatter};
 use std::fs::{self, create_dir_all, File, Permissions, remove_dir_all, remove_file, set_permissions};
-use std::io::{Cursor, Read};
+use std::io::{self, BufReader, BufWriter, Cursor, Read};
 use std::os::unix::fs::{PermissionsExt, symlink};
-use std::path::Path;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
 use file_owner::PathExt;
 
 use log::debug;
-use serde::{Deserialize, Serialize};
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
 use tar::{Archive, Builder};
 use uuid::Uuid;
-use version::{Version, VersionReq};
+use version::{Comparator, Op, Version, VersionReq};
 use zstd::Decoder;
 use zstd::stream::copy_encode;
 
 use crate::{
-    crypt::{encrypt_package, mcrypt_sha256_verify_file, PrivateKey},
+    crypt::{encrypt_package_stream, mcrypt_sha256_verify_file, PrivateKey},
     file::{FileOps, get_cwd, set_cwd},
     platform::{arch_str, Architecture}
 };
-use crate::crypt::mcrypt_sha256_raw;
+use crate::crypt::{mcrypt_sha256_copy, PackageReader, PublicKey};
+use crate::db::Database;
+use crate::journal::Journal;
 use crate::pkgdb::PackageDb;
+use crate::sig::{DetachedReader, DetachedSignature};
 
 //
 // Package
@@ -77,15 +81,150 @@ pub fn get_pkg_filename(package: &Package) -> String {
 }
 
 // PkgSpec
-/// Represents a package specification (ie `test-package>=1`)
+/// Represents a package specification (ie `test-package>=1.0.0`)
+///
+/// The textual form follows the grammar in `book-backup/internals/pkgspec.md`: an optional `repo/` prefix, the package name,
+/// and optionally one of `<`, `<=`, `@`, `>=` or `>` followed by a full semantic version.
+/// In human-readable formats such as `.mgve.toml` specs are written in this textual form whenever possible, and both the textual
+/// form and the struct form are accepted when reading. Binary formats always use the struct form.
 //
-#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 #[allow(clippy::module_name_repetitions)]
 pub struct PkgSpec {
     /// The name of the package
     pub pkgname: String,
     /// The version requirements for this package
     pub version: VersionReq,
+    /// The repository this package must come from, if any
+    pub repository: Option<String>,
+}
+
+// PkgSpecStruct
+/// The struct form of a `PkgSpec`, used for serialization
+//
+#[derive(Serialize, Deserialize)]
+#[serde(rename = "PkgSpec")]
+struct PkgSpecStruct {
+    pkgname: String,
+    version: VersionReq,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    repository: Option<String>,
+}
+
+// PkgSpecRepr
+/// Every form a `PkgSpec` can take in a human-readable format
+//
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum PkgSpecRepr {
+    Text(String),
+    Struct(PkgSpecStruct),
+}
+
+impl From<PkgSpecStruct> for PkgSpec {
+    fn from(spec: PkgSpecStruct) -> Self {
+        Self { pkgname: spec.pkgname, version: spec.version, repository: spec.repository }
+    }
+}
+
+// pkgspec_name_valid
+/// Check a package or repository name against the pkgspec grammar: a letter, followed by at least one more allowed character
+//
+fn pkgspec_name_valid(name: &str, allow_digits: bool) -> bool {
+    let mut chars = name.chars();
+    chars.next().is_some_and(|c| c.is_ascii_alphabetic())
+        && name.len() > 1
+        && chars.all(|c| c.is_ascii_alphabetic() || c == '-' || c == '_' || (allow_digits && c.is_ascii_digit()))
+}
+
+impl FromStr for PkgSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (repository, rest) = match s.split_once('/') {
+            Some((repo, rest)) => {
+                if !pkgspec_name_valid(repo, false) {
+                    return Err(format!("invalid repository name '{repo}' in package spec '{s}'"));
+                }
+                (Some(repo.to_string()), rest)
+            },
+            None => (None, s)
+        };
+
+        let (pkgname, version) = match rest.find(['<', '>', '@']) {
+            Some(i) => {
+                let (pkgname, req) = rest.split_at(i);
+                // longest operators first, so that `<=` is not read as `<`
+                let (op, ver) = match [("<=", Op::LessEq), (">=", Op::GreaterEq), ("<", Op::Less), (">", Op::Greater), ("@", Op::Exact)].iter()
+                    .find_map(|(prefix, op)| req.strip_prefix(prefix).map(|ver| (*op, ver))) {
+                    Some(x) => x,
+                    None => return Err(format!("invalid version requirement in package spec '{s}'"))
+                };
+                let ver = Version::parse(ver).map_err(|e| format!("invalid version '{ver}' in package spec '{s}': {e}"))?;
+                (pkgname, VersionReq { comparators: vec![Comparator { op, major: ver.major, minor: Some(ver.minor), patch: Some(ver.patch), pre: ver.pre }] })
+            },
+            None => (rest, VersionReq { comparators: vec![] })
+        };
+
+        if !pkgspec_name_valid(pkgname, true) {
+            return Err(format!("invalid package name '{pkgname}' in package spec '{s}'"));
+        }
+
+        Ok(Self { pkgname: pkgname.to_string(), version, repository })
+    }
+}
+
+impl Display for PkgSpec {
+    /// Write this spec in its textual form. Version requirements that the pkgspec grammar cannot express are written in `semver` syntax instead.
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        if let Some(repository) = &self.repository {
+            write!(f, "{repository}/")?;
+        }
+        write!(f, "{}", self.pkgname)?;
+        match &self.version.comparators[..] {
+            [] => Ok(()),
+            [Comparator { op, major, minor: Some(minor), patch: Some(patch), pre }] if matches!(op, Op::Exact | Op::Less | Op::LessEq | Op::Greater | Op::GreaterEq) => {
+                let op_str = match op {
+                    Op::Exact => "@",
+                    Op::Less => "<",
+                    Op::LessEq => "<=",
+                    Op::Greater => ">",
+                    _ => ">="
+                };
+                write!(f, "{op_str}{major}.{minor}.{patch}")?;
+                if !pre.is_empty() {
+                    write!(f, "-{pre}")?;
+                }
+                Ok(())
+            },
+            _ => write!(f, "{}", self.version)
+        }
+    }
+}
+
+impl Serialize for PkgSpec {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            let text = self.to_string();
+            if text.parse::<Self>().as_ref() == Ok(self) {
+                return serializer.serialize_str(&text);
+            }
+        }
+        PkgSpecStruct { pkgname: self.pkgname.clone(), version: self.version.clone(), repository: self.repository.clone() }.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for PkgSpec {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        if deserializer.is_human_readable() {
+            match PkgSpecRepr::deserialize(deserializer)? {
+                PkgSpecRepr::Text(text) => text.parse().map_err(de::Error::custom),
+                PkgSpecRepr::Struct(spec) => Ok(spec.into())
+            }
+        } else {
+            Ok(PkgSpecStruct::deserialize(deserializer)?.into())
+        }
+    }
 }
 
 // PackageContents
@@ -193,9 +332,9 @@ macro_rules! version_any {
 /// - the archive could not be compressed
 /// - the compressed archive could not be written
 /// - the temporary archive could not be removed
-/// - the file could not be read for encryption
-/// - the file could not be encrypted
-/// - the encrypted file could not be written
+/// - the file could not be opened for encryption
+/// - the file could not be encrypted or the encrypted file could not be written
+/// - the encrypted file could not be moved into place
 pub fn save_package_raw(package: &Package, data_dir: String, signing_key: Option<PrivateKey>) -> Result<String, Box<dyn Error>> {
     // Step 1: Create temporary dir
     let random_identifier: String = Uuid::new_v4().to_string(); // Get a random uuidv4
@@ -285,6 +424,10 @@ pub fn save_package_raw(package: &Package, data_dir: String, signing_key: Option
         Err(err) => return Err(format!("Failed to open file ({}) for writing: {}", &archive_path_uncompressed, err).into()),
     };
     let mut tar = Builder::new(tar_archive_bare);
+    // pkginfo goes first, so that packages can be verified and extracted in a single pass
+    if let Err(err) = tar.append_path("./pkginfo") {
+        return Err(format!("Failed to write file to archive: {err}").into());
+    }
     if need_files {
         for file in files {
             match tar.append_path(format!("./{}", file.name)) {
@@ -293,10 +436,6 @@ pub fn save_package_raw(package: &Package, data_dir: String, signing_key: Option
             }
         }
     }
-    match tar.append_path("./pkginfo") {
-        Ok(_) => (),
-        Err(err) => return Err(format!("Failed to write file to archive: {}", err).into()),
-    }
     match tar.finish() {
         Ok(_) => (),
         Err(err) => return Err(format!("Failed to finalize archive: {}", err).into()),
@@ -337,22 +476,25 @@ pub fn save_package_raw(package: &Package, data_dir: String, signing_key: Option
         Some(_) => ()
     }
 
-    // Signing is required
-    let dat: Vec<u8> = match fs::read(&archive_path) {
-        Ok(dat) => dat,
-        Err(err) => return Err(format!("Failed to read file: {}", err).into())
-    };
+    // Signing is required, the package is encrypted a chunk at a time into a temporary file
     let sk = match &signing_key {
         Some(k) => k,
         None => return Err("The private key could not be extracted".into())
     };
-    let enc_res = match encrypt_package(sk, &dat) {
-        Ok(vec) => vec,
-        Err(err) => return Err(format!("Failed to encrypt package: {}", err).into())
+    let archive_path_signed = format!("{}.spf", &archive_path);
+    let plain_istream = match File::open(&archive_path) {
+        Ok(ptr) => ptr,
+        Err(err) => return Err(format!("Failed to open file {archive_path} for reading: {err}").into()),
     };
-    match fs::write(&archive_path, enc_res) {
-        Ok(_) => (),
-        Err(err) => return Err(format!("Failed to write to file: {}", err).into())
+    let signed_ostream = match File::create(&archive_path_signed) {
+        Ok(ptr) => ptr,
+        Err(err) => return Err(format!("Failed to open file for writing: {err}").into()),
+    };
+    if let Err(err) = encrypt_package_stream(sk, plain_istream, BufWriter::new(signed_ostream)) {
+        return Err(format!("Failed to encrypt package: {err}").into());
+    }
+    if let Err(err) = fs::rename(&archive_path_signed, &archive_path) {
+        return Err(format!("Failed to write to file: {err}").into());
     }
 
     Ok(archive_path)
@@ -390,7 +532,17 @@ pub fn save_package_signed(package: &Package, data_dir: String, signing_key: Pri
 /// # Errors
 /// Literally too many things to list.
 pub fn load_package(data: &Vec<u8>) -> Result<Package, Box<dyn Error>> {
-    let mut archive = Archive::new(Decoder::new(Cursor::new(data))?);
+    load_package_stream(Cursor::new(data))
+}
+
+// load_package_stream
+/// Read an **unencrypted!** Package from `reader`, verifying the hash of every file in it without holding any of them in memory.
+///
+/// Signed packages can be loaded by passing a `PackageReader`, the package is read to its end so that it is verified in full.
+/// # Errors
+/// Literally too many things to list.
+pub fn load_package_stream<R: Read>(reader: R) -> Result<Package, Box<dyn Error>> {
+    let mut archive = Archive::new(Decoder::new(reader)?);
     // Pull out pkginfo
     let entries = archive.entries()?;
     let mut pkginfo: Option<Package> = None;
@@ -413,16 +565,17 @@ pub fn load_package(data: &Vec<u8>) -> Result<Package, Box<dyn Error>> {
             pkginfo = Some(rmp_serde::from_slice(&*pkinfo)?);
             /* END BUGGY-LINT-SECTION */
         } else {
-            let mut fdat: Vec<u8> = vec![];
-            entry.read_to_end(&mut fdat)?;
-            hashes.insert(format!("/{}", match entry.path()?.to_str() {
-                Some(f) => f,
+            let path = match entry.path()?.to_str() {
+                Some(f) => format!("/{f}"),
                 None => {
                     return Err("Failed to convert string".into())
                 }
-            }), hex::encode(mcrypt_sha256_raw(&fdat[..])));
+            };
+            hashes.insert(path, mcrypt_sha256_copy(&mut entry, &mut io::sink())?);
         }
     }
+    // Read whatever is left, so that all of a signed package is verified
+    io::copy(&mut archive.into_inner(), &mut io::sink())?;
     if pkginfo.is_none() {
         return Err("Failed to find pkginfo file".into())
     }
@@ -452,6 +605,81 @@ pub fn load_package(data: &Vec<u8>) -> Result<Package, Box<dyn Error>> {
     Ok(pkg)
 }
 
+// PackageVerification
+/// How the data in a `StoredPackage` is verified while it is read
+//
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub enum PackageVerification {
+    /// An unsigned package, which is read as it is
+    #[default]
+    Unsigned,
+    /// A package in the Signed Package Format, which is decrypted with the key that signed it, see `PackageReader`
+    Signed(PublicKey),
+    /// An unsigned package with a detached signature made with the key, see `DetachedReader`
+    Detached(PublicKey, DetachedSignature)
+}
+
+// StoredPackage
+/// A package file on disk, and how to verify it.
+///
+/// The package is read from the file every time it is needed, and verified again on the way, so it is never held in memory.
+//
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct StoredPackage {
+    /// The path of the package file
+    pub path: PathBuf,
+    /// How the data in the package file is verified
+    pub verification: PackageVerification
+}
+
+impl StoredPackage {
+    // unsigned
+    /// An unsigned package file
+    //
+    pub fn unsigned(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into(), verification: PackageVerification::Unsigned }
+    }
+
+    // signed
+    /// A package file in the Signed Package Format, signed by `vkey`
+    //
+    pub fn signed(path: impl Into<PathBuf>, vkey: PublicKey) -> Self {
+        Self { path: path.into(), verification: PackageVerification::Signed(vkey) }
+    }
+
+    // detached
+    /// An unsigned package file with a detached signature made with `vkey`
+    //
+    pub fn detached(path: impl Into<PathBuf>, vkey: PublicKey, signature: DetachedSignature) -> Self {
+        Self { path: path.into(), verification: PackageVerification::Detached(vkey, signature) }
+    }
+
+    // open
+    /// Open the package file, returning a reader for the unencrypted package data that verifies it as it is read.
+    /// The package has only been verified in full once the reader has been read to its end.
+    /// # Errors
+    /// This function will error if the file could not be opened, or if the package header or detached signature is not valid for the key
+    pub fn open(&self) -> Result<Box<dyn Read>, Box<dyn Error>> {
+        let file = match File::open(&self.path) {
+            Ok(f) => BufReader::new(f),
+            Err(e) => return Err(format!("Failed to open {}: {e}", self.path.display()).into())
+        };
+        Ok(match &self.verification {
+            PackageVerification::Unsigned => Box::new(file),
+            PackageVerification::Signed(vkey) => Box::new(PackageReader::new(vkey, file)?),
+            PackageVerification::Detached(vkey, signature) => Box::new(DetachedReader::new(vkey, signature, file)?)
+        })
+    }
+
+    // load
+    /// Read and verify the package file, returning its pkginfo, see `load_package_stream`
+    /// # Errors
+    /// This function will error if the package could not be opened, verified or loaded
+    pub fn load(&self) -> Result<Package, Box<dyn Error>> {
+        load_package_stream(self.open()?)
+    }
+}
+
 fn show_opt<T: Debug>(opt: Option<T>) -> String {
     opt.map_or_else(|| "Not provided".to_string(), |val| format!("{:?}", val))
 }
@@ -483,78 +711,237 @@ pub fn dump_package(pkg: &Package) {
 
 // extract_pkg_to
 /// Extract a &Package to the given target directory, performing validation as it goes.
+/// If extraction fails partway through, everything that was already extracted is rolled back.
 /// # Errors
 /// Once again, due to the amount of filesystem operations there are too many things to list here.
 pub fn extract_pkg_to(package: &Vec<u8>, target: String) -> Result<(), Box<dyn Error>> {
-    debug!("extract package atl to {}", target);
-    let pkginfo = load_package(package)?;
-    debug!("pkginfo load success");
-    // package is valid, open the archive
-    let mut archive = Archive::new(Decoder::new(Cursor::new(package))?);
-    debug!("archive load success");
-    if let Some(folders) = pkginfo.pkgcontents.folders {
-        for folder in folders {
-            if Path::new(&format!("{}{}", target, folder.installpath)).exists() {
-                debug!("removing directory {}", format!("{}{}", target, folder.installpath));
-                remove_dir_all(&format!("{}{}", target, folder.installpath))?;
-            }
-            debug!("creating directory {}", format!("{}{}", target, folder.installpath));
-            create_dir_all(format!("{}{}", target, folder.installpath))?;
-
-            // Set permissions
-            #[allow(clippy::cast_possible_truncation)] // Safe, because any value that would cause this is an invalid value anyways
-            {
-                format!("{}{}", target, folder.installpath).set_owner(folder.meta.owner as u32)?;
-                format!("{}{}", target, folder.installpath).set_group(folder.meta.group as u32)?;
-                set_permissions(format!("{}{}", target, folder.installpath), Permissions::from_mode(folder.meta.permissions as u32))?;
+    extract_pkg_stream_to(Cursor::new(package), &target).map(|_| ())
+}
+
+// extract_pkg_stream_to
+/// Extract an unencrypted package read from `reader` to the given target directory in a single pass, and return its pkginfo.
+///
+/// If extraction or validation fails partway through, everything that was already extracted is rolled back.
+/// Signed packages can be extracted by passing a `PackageReader`. As it only hands out verified data and the package is read to its end,
+/// a package that fails verification anywhere is never left behind.
+/// # Errors
+/// Once again, due to the amount of filesystem operations there are too many things to list here.
+pub fn extract_pkg_stream_to<R: Read>(reader: R, target: &str) -> Result<Package, Box<dyn Error>> {
+    let mut journal = Journal::new(target);
+    match extract_pkg_stream_journaled(reader, target, &mut journal) {
+        Ok(pkginfo) => {
+            journal.commit()?;
+            Ok(pkginfo)
+        },
+        Err(e) => {
+            if let Err(rollback_err) = journal.rollback() {
+                return Err(format!("{e}\n{rollback_err}").into());
             }
+            Err(e)
         }
     }
-    if let Some(files) = pkginfo.pkgcontents.files {
-        debug!("extracting files");
-        for file_raw in archive.entries()? {
-            debug!("extract file");
-            let mut file = file_raw?;
-            debug!("file_decode success for {:?}", file.path()?);
-            for f_to_extract in &files {
-                let path_str = "/".to_owned() + &match file.path()?.to_str() {
-                    Some(f) => f,
-                    None => return Err("Failed to convert string types".into())
-                };
+}
 
-                if path_str == "/pkginfo" {
-                    debug!("p_extract_pathcheck skip package info");
-                }
+// set_metadata
+/// Set the ownership and permissions of `path` to the given `FileMetadata`
+//
+fn set_metadata(path: &str, meta: &FileMetadata) -> Result<(), Box<dyn Error>> {
+    #[allow(clippy::cast_possible_truncation)] // Safe, because any value that would cause this is an invalid value anyways
+    {
+        path.set_owner(meta.owner as u32)?;
+        path.set_group(meta.group as u32)?;
+        set_permissions(path, Permissions::from_mode(meta.permissions as u32))?;
+    }
+    Ok(())
+}
 
-                debug!("{} {}", f_to_extract.installpath, path_str);
+// is_plain_installpath
+/// Check that a path from a pkginfo is absolute, and has no `.` or `..` components, so that it stays inside the target directory
+//
+fn is_plain_installpath(path: &str) -> bool {
+    let mut components = Path::new(path).components();
+    components.next() == Some(Component::RootDir) && components.all(|x| matches!(x, Component::Normal(_)))
+}
 
-                if f_to_extract.installpath == path_str {
-                    debug!("path match, begin routine f_extract_fullbom");
-                    let mut data: Vec<u8> = vec![];
-                    file.read_to_end(&mut data)?;
-                    debug!("data: {:?}", data);
-                    fs::write(format!("{}{}", target, &f_to_extract.installpath), data)?;
-                }
+// check_pkg_paths
+/// Check that every path in the contents of a package stays inside the target directory it is installed to:
+/// the install path of every folder and file, and both the path and the target of every link.
+/// # Errors
+/// This function will error if any of these paths is not absolute, or has `.` or `..` components.
+pub fn check_pkg_paths(pkginfo: &Package) -> Result<(), Box<dyn Error>> {
+    let contents = &pkginfo.pkgcontents;
+    let paths = contents.folders.iter().flatten().map(|x| &x.installpath)
+        .chain(contents.files.iter().flatten().map(|x| &x.installpath))
+        .chain(contents.links.iter().flatten().flat_map(|x| [&x.target, &x.file]));
+    for path in paths {
+        if !is_plain_installpath(path) {
+            return Err(format!("Refusing to install {} {}, which contains {path}, not a plain absolute path", pkginfo.pkgname, pkginfo.pkgver).into());
+        }
+    }
+    Ok(())
+}
 
-                // Set permissions
-                #[allow(clippy::cast_possible_truncation)] // Safe, because any value that would cause this is an invalid value anyways
-                {
-                    format!("{}{}", target, f_to_extract.installpath).set_owner(f_to_extract.meta.owner as u32)?;
-                    format!("{}{}", target, f_to_extract.installpath).set_group(f_to_extract.meta.group as u32)?;
-                    set_permissions(format!("{}{}", target, f_to_extract.installpath), Permissions::from_mode(f_to_extract.meta.permissions as u32))?;
-                }
+// extract_folder
+/// Create a package folder in the target directory, or update the ownership and permissions of the existing one
+//
+fn extract_folder(folder: &PackageFolder, target: &str, journal: &mut Journal) -> Result<(), Box<dyn Error>> {
+    let path = format!("{}{}", target, folder.installpath);
+    if Path::new(&path).is_dir() {
+        journal.record_metadata(&path)?;
+    } else {
+        debug!("creating directory {}", path);
+        journal.create_dir_all(&path)?;
+    }
+    set_metadata(&path, &folder.meta)
+}
+
+// extract_pkg_journaled
+/// Extract a stored package to the given target directory, recording every change in the provided `Journal`, and return its pkginfo.
+/// See `extract_pkg_stream_journaled`.
+/// # Errors
+/// Once again, due to the amount of filesystem operations there are too many things to list here.
+pub fn extract_pkg_journaled(package: &StoredPackage, target: &str, journal: &mut Journal) -> Result<Package, Box<dyn Error>> {
+    extract_pkg_stream_journaled(package.open()?, target, journal)
+}
+
+// StagedFile
+/// A file of a package that has been written out, but not renamed into place yet
+//
+struct StagedFile {
+    /// The path of the file in the package, which is where it is installed to in the target directory
+    installpath: String,
+    /// Where the file was written
+    path: String,
+    /// The sha256 hash of the file
+    sha256: String
+}
+
+// stage_pkg_stream
+/// Read an unencrypted package from `reader` to its end in a single pass, writing out every file while it is hashed, and return its pkginfo
+/// along with a staged file for every file in the pkginfo, in the same order.
+///
+/// Every file is written next to its final path, so that no file is ever held in memory.
+/// Files that come before the pkginfo in the archive are written to the journal's backup directory instead, and files the pkginfo does not list are never written into the target directory.
+/// The staged files have been checked against the hashes in the pkginfo, but nothing has been renamed into place yet.
+/// # Errors
+/// This function will error if:
+/// - an archive entry has an absolute path, or a path with `.` or `..` components
+/// - the package has paths that would leave the target directory, see `check_pkg_paths`
+/// - the pkginfo is missing, or a file is missing or does not match its hash
+/// - reading fails, or a file could not be written
+fn stage_pkg_stream<R: Read>(reader: R, target: &str, journal: &mut Journal) -> Result<(Package, Vec<StagedFile>), Box<dyn Error>> {
+    let mut archive = Archive::new(Decoder::new(reader)?);
+    let mut pkginfo: Option<Package> = None;
+    let mut staged: Vec<StagedFile> = vec![];
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let entry_path = entry.path()?.into_owned();
+        if !entry_path.components().all(|x| matches!(x, Component::Normal(_))) {
+            return Err(format!("Refusing to extract {}, which is not a plain relative path", entry_path.display()).into());
+        }
+        let Some(installpath) = entry_path.to_str().map(|x| format!("/{x}")) else {
+            return Err("Failed to convert string types".into());
+        };
+        if installpath == "/pkginfo" {
+            let loaded: Package = rmp_serde::from_read(&mut entry)?;
+            check_pkg_paths(&loaded)?;
+            pkginfo = Some(loaded);
+            debug!("pkginfo load success");
+            continue;
+        }
+        // packages built before pkginfo was moved to the front of the archive have it last, so until then
+        // everything is staged in the journal, without touching the target root
+        let path = if let Some(pkginfo) = &pkginfo {
+            if !pkginfo.pkgcontents.files.iter().flatten().any(|x| x.installpath == installpath) {
+                debug!("skipping {}, which is not in the pkginfo", installpath);
+                continue;
+            }
+            let path = format!("{target}{installpath}");
+            if let Some(parent) = Path::new(&path).parent().and_then(Path::to_str) {
+                journal.create_dir_all(parent)?;
             }
+            let staged_path = format!("{}.mgve_new_{}", path, Uuid::new_v4());
+            journal.prepare_path(&staged_path)?;
+            staged_path
+        } else {
+            journal.scratch_path()?
+        };
+        debug!("staging {} at {}", installpath, path);
+        let sha256 = mcrypt_sha256_copy(&mut entry, &mut File::create(&path)?)?;
+        staged.push(StagedFile { installpath, path, sha256 });
+    }
+    // Read whatever is left, so that all of a signed package is verified
+    io::copy(&mut archive.into_inner(), &mut io::sink())?;
+    let Some(pkginfo) = pkginfo else {
+        return Err("Failed to find pkginfo file".into());
+    };
+
+    let mut files: Vec<StagedFile> = vec![];
+    for file in pkginfo.pkgcontents.files.iter().flatten() {
+        let Some(index) = staged.iter().position(|x| x.installpath == file.installpath) else {
+            return Err(format!("Hash for {} is missing", file.name).into());
+        };
+        let staged_file = staged.swap_remove(index);
+        if staged_file.sha256 != file.sha256 {
+            return Err(format!("Fatal error: hash verification failed for {} (expected {} got {})", file.name, file.sha256, staged_file.sha256).into());
+        }
+        files.push(staged_file);
+    }
+    // anything left is not in the pkginfo
+    for unlisted in staged {
+        remove_file(unlisted.path)?;
+    }
+    Ok((pkginfo, files))
+}
+
+// install_staged_file
+/// Set the metadata of a staged file, and rename it over its final path in the target directory
+/// # Errors
+/// This function will error if the metadata could not be set, or the file could not be renamed into place
+//
+fn install_staged_file(file: &PackageFile, staged: &StagedFile, target: &str, journal: &mut Journal) -> Result<(), Box<dyn Error>> {
+    set_metadata(&staged.path, &file.meta)?;
+    let path = format!("{}{}", target, file.installpath);
+    // files staged before the pkginfo was read may not have their directory yet
+    if let Some(parent) = Path::new(&path).parent().and_then(Path::to_str) {
+        journal.create_dir_all(parent)?;
+    }
+    debug!("installing file {}", path);
+    journal.replace_path(&path, &staged.path)
+}
+
+// extract_pkg_stream_journaled
+/// Extract an unencrypted package read from `reader` to the given target directory in a single pass,
+/// recording every change in the provided `Journal`, and return its pkginfo.
+///
+/// Files are staged while they are hashed, see `stage_pkg_stream`, so no file is ever held in memory.
+/// Once the whole archive has been read and every hash has been checked against the pkginfo, the files are renamed into place.
+/// Existing folders are kept and only have their ownership and permissions updated, existing files and links are backed up and replaced.
+/// Nothing is rolled back on failure, that is left up to the owner of the journal.
+/// # Errors
+/// Archive entries with absolute paths, or paths with `.` or `..` components, are refused, and so are packages
+/// with paths that would leave the target directory, see `check_pkg_paths`.
+/// Otherwise, once again, due to the amount of filesystem operations there are too many things to list here.
+pub fn extract_pkg_stream_journaled<R: Read>(reader: R, target: &str, journal: &mut Journal) -> Result<Package, Box<dyn Error>> {
+    debug!("extract package stream to {}", target);
+    let (pkginfo, staged) = stage_pkg_stream(reader, target, journal)?;
+
+    if let Some(folders) = &pkginfo.pkgcontents.folders {
+        for folder in folders {
+            extract_folder(folder, target, journal)?;
         }
     }
-    if let Some(links) = pkginfo.pkgcontents.links {
+    for (file, staged) in pkginfo.pkgcontents.files.iter().flatten().zip(&staged) {
+        install_staged_file(file, staged, target, journal)?;
+    }
+    if let Some(links) = &pkginfo.pkgcontents.links {
         for link in links {
-            if Path::new(&link.target).exists() {
-                remove_file(&link.target)?;
-            }
-            symlink(format!("{}{}", target, link.file), format!("{}{}", target, link.target))?;
+            let path = format!("{}{}", target, link.target);
+            journal.prepare_path(&path)?;
+            symlink(format!("{}{}", target, link.file), &path)?;
         }
     }
-    Ok(())
+    Ok(pkginfo)
 }
 
 
@@ -565,27 +952,22 @@ pub fn extract_pkg_to(package: &Vec<u8>, target: String) -> Result<(), Box<dyn E
 pub fn install_pkg_to(package: &Vec<u8>, target: String, db: &mut PackageDb) -> Result<(), Box<dyn Error>> {
     let pkginfo = load_package(package)?;
 
-    for pkg in &db.db.installed_packages {
-        // Conflict checking: another package lists this one as a conflict
-        if let Some(conflicts) = &pkg.conflicts {
-            let conflicting = conflicts.iter().find(|x| x.pkgname == pkginfo.pkgname && x.version.matches(&pkginfo.pkgver));
-            if let Some(conflict) = conflicting {
-                return Err(format!("This package conflicts with {}, remove it first", conflict.pkgname).into());
-            }
-        }
-        // Conflict checking: this package lists another one as a conflict
-        if let Some(conflicts) = &pkginfo.conflicts {
-            let conflicting = conflicts.iter().find(|x| x.pkgname == pkginfo.pkgname && x.version.matches(&pkginfo.pkgver));
-            if let Some(conflict) = conflicting {
-                return Err(format!("This package conflicts with {}, remove it first", conflict.pkgname).into());
-            }
-        }
+    db.db.check_not_held(&pkginfo.pkgname)?;
+    if db.db.installed_packages.iter().any(|x| x.pkgname == pkginfo.pkgname) {
+        return Err(format!("Package {} is already installed, upgrade it instead", pkginfo.pkgname).into());
+    }
+    // Conflict checking, in both directions
+    let installed: Vec<&Package> = db.db.installed_packages.iter().collect();
+    let conflicts = find_conflicts(&[&pkginfo], &installed);
+    if !conflicts.is_empty() {
+        let conflicts: Vec<String> = conflicts.iter().map(ToString::to_string).collect();
+        return Err(format!("This package conflicts with installed packages, remove them first:\n{}", conflicts.join("\n")).into());
     }
     // No conflicts
     // Dependency checking
     if let Some(dependencies) = &pkginfo.depends {
         for dependency in dependencies {
-            if !&db.db.installed_packages.iter().any(|x| x.pkgname == dependency.pkgname && dependency.version.matches(&x.pkgver)) {
+            if !db.db.installed_packages.iter().any(|x| pkg_satisfies(x, dependency)) {
                 return Err(format!("Required dependency {} not installed", dependency.pkgname).into());
             }
         }
@@ -598,3 +980,428 @@ pub fn install_pkg_to(package: &Vec<u8>, target: String, db: &mut PackageDb) ->
     // All done!
     Ok(())
 }
+
+// upgrade_pkg
+/// Upgrade an installed package to the newer version in `package`, returning the version that was replaced.
+///
+/// Only the differences between the installed and the new package contents are applied, see `upgrade_pkg_journaled`.
+/// If the upgrade fails partway through, everything is rolled back.
+/// # Errors
+/// This function will error if:
+/// - the package could not be loaded, is not installed, or is held
+/// - the package is not newer than the installed version
+/// - the new version conflicts with an installed package, or needs a dependency that is not installed
+/// - the new version no longer satisfies the dependency of another installed package
+/// - a path could not be removed, extracted or replaced
+pub fn upgrade_pkg(package: &StoredPackage, target: String, db: &mut PackageDb) -> Result<Package, Box<dyn Error>> {
+    let pkginfo = package.load()?;
+    db.db.check_not_held(&pkginfo.pkgname)?;
+    let Some(installed) = db.db.installed_packages.iter().find(|x| x.pkgname == pkginfo.pkgname) else {
+        return Err(format!("Package {} is not installed", pkginfo.pkgname).into());
+    };
+    if pkginfo.pkgver <= installed.pkgver {
+        return Err(format!("Cannot upgrade {} from {} to {}, which is not newer", pkginfo.pkgname, installed.pkgver, pkginfo.pkgver).into());
+    }
+    let others: Vec<&Package> = db.db.installed_packages.iter().filter(|x| x.pkgname != pkginfo.pkgname).collect();
+    // Conflict checking, in both directions
+    let conflicts = find_conflicts(&[&pkginfo], &others);
+    if !conflicts.is_empty() {
+        let conflicts: Vec<String> = conflicts.iter().map(ToString::to_string).collect();
+        return Err(format!("This package conflicts with installed packages, remove them first:\n{}", conflicts.join("\n")).into());
+    }
+    // Dependency checking, for the new version and for everything that depends on the old version
+    for dependency in pkginfo.depends.iter().flatten() {
+        if !others.iter().any(|x| pkg_satisfies(x, dependency)) {
+            return Err(format!("Required dependency {dependency} not installed").into());
+        }
+    }
+    for other in &others {
+        for dependency in other.depends.iter().flatten() {
+            if pkg_satisfies(installed, dependency) && !pkg_satisfies(&pkginfo, dependency) && !others.iter().any(|x| pkg_satisfies(x, dependency)) {
+                return Err(format!("{} requires {dependency}, which {} {} does not satisfy", other.pkgname, pkginfo.pkgname, pkginfo.pkgver).into());
+            }
+        }
+    }
+
+    let mut journal = Journal::new(&target);
+    match upgrade_pkg_journaled(package, &target, db, &mut journal) {
+        Ok(old) => {
+            journal.commit()?;
+            Ok(old)
+        },
+        Err(e) => {
+            if let Err(rollback_err) = journal.rollback() {
+                return Err(format!("{e}\n{rollback_err}").into());
+            }
+            Err(e)
+        }
+    }
+}
+
+// upgrade_pkg_journaled
+/// Replace an installed package with the version in `package`, recording every change in the provided `Journal`, and return the replaced version.
+///
+/// The package is read once, and every file in it is staged and checked against its hash before anything is changed, see `stage_pkg_stream`.
+/// The installed and new package contents are then compared:
+/// - links, files and folders the new version no longer contains are removed, unless another installed package owns them
+/// - new and changed files are atomically renamed over their final path, and so are new and changed links
+/// - files with the same hash and metadata as in the installed version are left alone
+///
+/// The database entry of the installed version is then swapped for the new one. No dependency or conflict checking is done,
+/// and nothing is rolled back on failure, that is left up to the owner of the journal.
+/// # Errors
+/// This function will error if the package could not be read or is not installed, if it has paths that would leave the target directory,
+/// see `check_pkg_paths`, if a file does not match its hash, or if a path could not be removed, extracted or replaced.
+pub fn upgrade_pkg_journaled(package: &StoredPackage, target: &str, db: &mut PackageDb, journal: &mut Journal) -> Result<Package, Box<dyn Error>> {
+    let (pkginfo, staged_files) = stage_pkg_stream(package.open()?, target, journal)?;
+    let Some(index) = db.db.installed_packages.iter().position(|x| x.pkgname == pkginfo.pkgname) else {
+        return Err(format!("Package {} is not installed", pkginfo.pkgname).into());
+    };
+    let old = db.db.installed_packages[index].clone();
+    let mut owners: Vec<&Package> = db.db.installed_packages.iter().filter(|x| x.pkgname != pkginfo.pkgname).collect();
+    owners.push(&pkginfo);
+
+    // Remove what the new version no longer contains: links, then files, then folders
+    if let Some(links) = &old.pkgcontents.links {
+        for link in links {
+            if path_is_shared(&link.target, &owners) { continue; }
+            let path = format!("{}{}", target, link.target);
+            // staging already moved it out of the way of a new folder
+            if Path::new(&path).symlink_metadata().is_ok_and(|m| m.is_dir()) { continue; }
+            debug!("removing dropped link {}", path);
+            journal.remove_path(&path)?;
+        }
+    }
+    if let Some(files) = &old.pkgcontents.files {
+        for file in files {
+            if path_is_shared(&file.installpath, &owners) { continue; }
+            let path = format!("{}{}", target, file.installpath);
+            // staging already moved it out of the way of a new folder
+            if Path::new(&path).symlink_metadata().is_ok_and(|m| m.is_dir()) { continue; }
+            debug!("removing dropped file {}", path);
+            journal.remove_path(&path)?;
+        }
+    }
+    if let Some(folders) = &old.pkgcontents.folders {
+        let mut folders: Vec<&PackageFolder> = folders.iter().filter(|x| !path_is_shared(&x.installpath, &owners)).collect();
+        folders.sort_by_key(|x| std::cmp::Reverse(x.installpath.matches('/').count()));
+        for folder in folders {
+            let path = format!("{}{}", target, folder.installpath);
+            if !Path::new(&path).is_dir() || fs::read_dir(&path)?.next().is_some() { continue; }
+            debug!("removing dropped directory {}", path);
+            journal.remove_dir(&path)?;
+        }
+    }
+
+    // Folders
+    if let Some(folders) = &pkginfo.pkgcontents.folders {
+        for folder in folders {
+            extract_folder(folder, target, journal)?;
+        }
+    }
+    // New and changed files
+    for (file, staged) in pkginfo.pkgcontents.files.iter().flatten().zip(&staged_files) {
+        let path = format!("{}{}", target, file.installpath);
+        let unchanged = old.pkgcontents.files.iter().flatten().any(|x| x.installpath == file.installpath && x.sha256 == file.sha256 && x.meta == file.meta);
+        if unchanged && Path::new(&path).symlink_metadata().is_ok_and(|m| m.is_file()) {
+            debug!("keeping unchanged file {}", path);
+            remove_file(&staged.path)?;
+            continue;
+        }
+        install_staged_file(file, staged, target, journal)?;
+    }
+    // New and changed links
+    if let Some(links) = &pkginfo.pkgcontents.links {
+        for link in links {
+            let path = format!("{}{}", target, link.target);
+            let file = format!("{}{}", target, link.file);
+            if fs::read_link(&path).is_ok_and(|x| x == Path::new(&file)) { continue; }
+            let staged = format!("{}.mgve_new_{}", path, Uuid::new_v4());
+            journal.prepare_path(&staged)?;
+            symlink(&file, &staged)?;
+            debug!("replacing link {}", path);
+            journal.replace_path(&path, &staged)?;
+        }
+    }
+
+    // Swap the database entry
+    db.db.installed_packages[index] = pkginfo;
+    Ok(old)
+}
+
+// pkg_provides
+/// Determine if the provided `Package` provides the given `PkgSpec` through one of the entries in its `provides`.
+///
+/// An entry without a version satisfies any version requirement, and an entry with an exact version (`name@version`) the
+/// requirements that version matches. An entry with any other version requirement only satisfies specs without one.
+//
+pub fn pkg_provides(package: &Package, spec: &PkgSpec) -> bool {
+    package.provides.iter().flatten().any(|provided| provided.pkgname == spec.pkgname && match &provided.version.comparators[..] {
+        [] => true,
+        [Comparator { op: Op::Exact, major, minor: Some(minor), patch: Some(patch), pre }] => {
+            let mut version = Version::new(*major, *minor, *patch);
+            version.pre = pre.clone();
+            spec.version.matches(&version)
+        },
+        _ => spec.version.comparators.is_empty()
+    })
+}
+
+// pkg_satisfies
+/// Determine if the provided `Package` satisfies the given `PkgSpec`, either by its own name and version or by one of the entries in
+/// its `provides`, see `pkg_provides`
+//
+pub fn pkg_satisfies(package: &Package, spec: &PkgSpec) -> bool {
+    (package.pkgname == spec.pkgname && spec.version.matches(&package.pkgver)) || pkg_provides(package, spec)
+}
+
+// Conflict
+/// Represents a single conflict: `package` lists `spec` as a conflict, and `other` matches it
+//
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Conflict {
+    /// The name of the package that lists the conflict
+    pub package: String,
+    /// The version of the package that lists the conflict
+    pub package_version: Version,
+    /// The name of the package it conflicts with
+    pub other: String,
+    /// The version of the package it conflicts with
+    pub other_version: Version,
+    /// The entry in `package`'s conflicts that matched, including the matched version requirement
+    pub spec: PkgSpec,
+    /// True if `spec` matched a name that `other` provides, rather than its own name and version
+    pub provided: bool,
+}
+
+impl Display for Conflict {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {} conflicts with {} {}", self.package, self.package_version, self.other, self.other_version)?;
+        if self.provided {
+            write!(f, ", which provides {}", self.spec.pkgname)?;
+        }
+        write!(f, " (matched {})", self.spec)
+    }
+}
+
+// listed_conflicts
+/// Find every entry in `package`'s conflicts that matches `other`, by its own name and version or by one of the entries in its
+/// `provides`, see `pkg_provides`
+//
+fn listed_conflicts(package: &Package, other: &Package) -> Vec<Conflict> {
+    package.conflicts.iter().flatten().filter_map(|spec| {
+        let provided = if spec.pkgname == other.pkgname && spec.version.matches(&other.pkgver) {
+            false
+        } else if pkg_provides(other, spec) {
+            true
+        } else {
+            return None;
+        };
+        Some(Conflict {
+            package: package.pkgname.clone(),
+            package_version: package.pkgver.clone(),
+            other: other.pkgname.clone(),
+            other_version: other.pkgver.clone(),
+            spec: spec.clone(),
+            provided
+        })
+    }).collect()
+}
+
+// pkg_conflicts
+/// Find every conflict between two packages, in both directions. Packages with the same name never conflict with each other.
+//
+pub fn pkg_conflicts(package: &Package, other: &Package) -> Vec<Conflict> {
+    if package.pkgname == other.pkgname {
+        return vec![];
+    }
+    let mut conflicts = listed_conflicts(package, other);
+    conflicts.extend(listed_conflicts(other, package));
+    conflicts
+}
+
+// find_conflicts
+/// Find every conflict among `packages`, and between `packages` and `others`.
+///
+/// Every pair of packages is only checked once, and packages in `others` that share a name with one of `packages` are skipped,
+/// as they are about to be replaced by it.
+//
+pub fn find_conflicts(packages: &[&Package], others: &[&Package]) -> Vec<Conflict> {
+    let mut conflicts = vec![];
+    for (i, package) in packages.iter().enumerate() {
+        for other in &packages[i + 1..] {
+            conflicts.extend(pkg_conflicts(package, other));
+        }
+        for other in others.iter().filter(|x| !packages.iter().any(|p| p.pkgname == x.pkgname)) {
+            conflicts.extend(pkg_conflicts(package, other));
+        }
+    }
+    conflicts
+}
+
+// get_removal_queue
+/// Given a list of installed package names, determine the order they need to be removed in.
+///
+/// If `cascade` is true, every installed package that would be left with an unsatisfied dependency is removed as well.
+/// Dependent packages are always placed before the packages they depend on.
+/// # Errors
+/// This function will error if:
+/// - one of the requested packages is not installed
+/// - removing the requested packages would break the dependencies of another installed package and `cascade` is false
+/// - one of the packages that would be removed is held
+pub fn get_removal_queue(pkgnames: &[String], database: &Database, cascade: bool) -> Result<Vec<Package>, Box<dyn Error>> {
+    let mut removing: Vec<Package> = vec![];
+    for pkgname in pkgnames {
+        let package = match database.installed_packages.iter().find(|x| &x.pkgname == pkgname) {
+            Some(p) => p,
+            None => return Err(format!("Package {pkgname} is not installed").into())
+        };
+        if !removing.iter().any(|x| x.pkgname == package.pkgname) {
+            removing.push(package.clone());
+        }
+    }
+
+    // Find installed packages that would be left with unsatisfied dependencies, until there are none left
+    loop {
+        let mut broken: Vec<Package> = vec![];
+        for pkg in &database.installed_packages {
+            if removing.iter().any(|x| x.pkgname == pkg.pkgname) { continue; }
+            if let Some(dependencies) = &pkg.depends {
+                for dependency in dependencies {
+                    let satisfied_by_removed = removing.iter().any(|x| pkg_satisfies(x, dependency));
+                    let satisfied_by_remaining = database.installed_packages.iter()
+                        .filter(|x| !removing.iter().any(|r| r.pkgname == x.pkgname))
+                        .any(|x| pkg_satisfies(x, dependency));
+                    if satisfied_by_removed && !satisfied_by_remaining {
+                        if !cascade {
+                            return Err(format!("{} depends on {}, which would be removed", pkg.pkgname, dependency).into());
+                        }
+                        broken.push(pkg.clone());
+                        break;
+                    }
+                }
+            }
+        }
+        if broken.is_empty() { break; }
+        removing.append(&mut broken);
+    }
+
+    // Held packages cannot be removed, including by cascading
+    if let Some(held) = removing.iter().find(|x| database.is_held(&x.pkgname)) {
+        return Err(format!("Package {} is held, and cannot be removed until it is unheld", held.pkgname).into());
+    }
+
+    // Order the queue so that dependents are removed before their dependencies
+    let mut queue: Vec<Package> = vec![];
+    while !removing.is_empty() {
+        let next = removing.iter().position(|candidate| {
+            !removing.iter().any(|other| other.pkgname != candidate.pkgname && other.depends.as_ref().is_some_and(|deps| deps.iter().any(|d| pkg_satisfies(candidate, d))))
+        }).unwrap_or(0); // dependency cycle, any order will do
+        queue.push(removing.remove(next));
+    }
+    Ok(queue)
+}
+
+// path_is_shared
+/// Determine if any of the provided packages owns the given path, or anything below it.
+//
+fn path_is_shared(path: &str, packages: &[&Package]) -> bool {
+    let prefix = format!("{}/", path.trim_end_matches('/'));
+    packages.iter().any(|pkg| {
+        let contents = &pkg.pkgcontents;
+        contents.folders.as_ref().is_some_and(|f| f.iter().any(|x| x.installpath == path || x.installpath.starts_with(&prefix)))
+            || contents.files.as_ref().is_some_and(|f| f.iter().any(|x| x.installpath == path || x.installpath.starts_with(&prefix)))
+            || contents.links.as_ref().is_some_and(|l| l.iter().any(|x| x.target == path || x.target.starts_with(&prefix)))
+    })
+}
+
+// remove_pkg_from
+/// Remove an installed package from the target directory, deleting its links, files and folders and removing it from the package database.
+///
+/// Paths that are also owned by another installed package are left alone, as are folders that are not empty after removal.
+/// Unless `force` is true, this will refuse to remove a package that another installed package depends on.
+/// # Errors
+/// This function will error if:
+/// - the package is not installed, or is held
+/// - removing the package would break the dependencies of another installed package and `force` is false
+/// - a link, file or folder could not be removed
+pub fn remove_pkg_from(pkgname: &str, target: String, db: &mut PackageDb, force: bool) -> Result<Package, Box<dyn Error>> {
+    db.db.check_not_held(pkgname)?;
+    let mut journal = Journal::new(&target);
+    match remove_pkg_journaled(pkgname, &target, db, force, &mut journal) {
+        Ok(pkginfo) => {
+            journal.commit()?;
+            Ok(pkginfo)
+        },
+        Err(e) => {
+            if let Err(rollback_err) = journal.rollback() {
+                return Err(format!("{e}\n{rollback_err}").into());
+            }
+            Err(e)
+        }
+    }
+}
+
+// remove_pkg_journaled
+/// Remove an installed package from the target directory like `remove_pkg_from`, recording every change in the provided `Journal`.
+///
+/// Removed links and files are kept as backups in the journal until it is committed.
+/// Nothing is rolled back on failure, that is left up to the owner of the journal.
+/// # Errors
+/// This function will error in the same cases as `remove_pkg_from`.
+pub fn remove_pkg_journaled(pkgname: &str, target: &str, db: &mut PackageDb, force: bool, journal: &mut Journal) -> Result<Package, Box<dyn Error>> {
+    let index = match db.db.installed_packages.iter().position(|x| x.pkgname == pkgname) {
+        Some(i) => i,
+        None => return Err(format!("Package {pkgname} is not installed").into())
+    };
+    if !force {
+        get_removal_queue(&[pkgname.to_string()], &db.db, false)?;
+    }
+    let pkginfo = db.db.installed_packages[index].clone();
+    let others: Vec<&Package> = db.db.installed_packages.iter().filter(|x| x.pkgname != pkgname).collect();
+
+    // Remove in reverse extraction order: links, then files, then folders
+    if let Some(links) = &pkginfo.pkgcontents.links {
+        for link in links {
+            if path_is_shared(&link.target, &others) { continue; }
+            let path = format!("{}{}", target, link.target);
+            if Path::new(&path).symlink_metadata().is_ok() {
+                debug!("removing link {}", path);
+                journal.remove_path(&path)?;
+            }
+        }
+    }
+    if let Some(files) = &pkginfo.pkgcontents.files {
+        for file in files {
+            if path_is_shared(&file.installpath, &others) { continue; }
+            let path = format!("{}{}", target, file.installpath);
+            if Path::new(&path).symlink_metadata().is_ok() {
+                debug!("removing file {}", path);
+                journal.remove_path(&path)?;
+            }
+        }
+    }
+    if let Some(folders) = &pkginfo.pkgcontents.folders {
+        // Deepest folders first, so that parents are empty by the time they are reached
+        let mut folders: Vec<&PackageFolder> = folders.iter().collect();
+        folders.sort_by_key(|x| std::cmp::Reverse(x.installpath.matches('/').count()));
+        for folder in folders {
+            if path_is_shared(&folder.installpath, &others) {
+                debug!("leaving shared directory {}", folder.installpath);
+                continue;
+            }
+            let path = format!("{}{}", target, folder.installpath);
+            if !Path::new(&path).is_dir() { continue; }
+            if fs::read_dir(&path)?.next().is_some() {
+                debug!("leaving non-empty directory {}", path);
+                continue;
+            }
+            debug!("removing directory {}", path);
+            journal.remove_dir(&path)?;
+        }
+    }
+
+    // Remove from package database
+    db.db.installed_packages.remove(index);
+    Ok(pkginfo)
+}
@@ -43,7 +43,8 @@ pub fn pkgdb_load(local: bool) -> Result<PackageDb, Box<dyn Error>> {
         // need to create the trustcache
         let data = Database {
             installed_packages: vec![],
-            repositories: vec![]
+            repositories: vec![],
+            held_packages: vec![]
         };
         fs::write(get_pkgdb_file(local), rmp_serde::to_vec(&data)?)?;
     }
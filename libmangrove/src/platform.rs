@@ -26,3 +26,18 @@ pub fn arch_str(arch: &Architecture) -> String {
         Architecture::armv7 => "armv7".to_string(),
     }
 }
+
+// host_arch
+/// Gets the `Architecture` this program was built for, if it is one Mangrove supports
+//
+pub const fn host_arch() -> Option<Architecture> {
+    if cfg!(target_arch = "x86_64") {
+        Some(Architecture::amd64)
+    } else if cfg!(target_arch = "aarch64") {
+        Some(Architecture::arm64)
+    } else if cfg!(target_arch = "arm") {
+        Some(Architecture::armv7)
+    } else {
+        None
+    }
+}
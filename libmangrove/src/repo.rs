@@ -1,11 +1,20 @@
 //! # Structs and functions for dealing with Repositories
 
 use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, File};
+use std::path::Path;
 
+use ed25519_dalek::{Signature, Verifier};
 use serde::{Deserialize, Serialize};
 use url::Url;
 
 use crate::{crypt::PublicKey, pkg::Package, platform::Architecture};
+use crate::crypt::KeySigner;
+use crate::db::{ConfiguredRepository, Database};
+use crate::pkg::{get_pkg_filename, PkgSpec, StoredPackage};
+use crate::sig::DetachedSignature;
+use crate::sync::{fetch_url, fetch_url_to};
 
 /// Represents a fully configured repository. This is the data contained in /repodata, and is what is synced by the package manager.
 #[derive(Serialize, Deserialize, Debug)]
@@ -18,7 +27,84 @@ pub struct Repository {
     /// The list of avaliable architectures
     pub avaliable_architectures: Vec<Architecture>,
     /// The list of avaliable packages
-    pub packages: HashMap<Architecture, Vec<Package>>
+    pub packages: HashMap<Architecture, Vec<Package>>,
+    /// How the pool files of this repository are signed
+    #[serde(default)]
+    pub pool_layout: PoolLayout,
+    /// The serial of this repository data, which increases every time it is generated. Repository data older than the stored data is refused by sync.
+    #[serde(default)]
+    pub serial: u64,
+    /// The unix timestamp, in seconds, at which this repository data expires and is refused by sync. Repository data without one never expires.
+    #[serde(default)]
+    pub expires: Option<u64>,
+    /// Every rotation of the signing key of this repository, oldest first. Sync follows them from the pinned key to move the pin to the newest key.
+    #[serde(default)]
+    pub key_rotations: Vec<KeyRotation>
+}
+
+// KeyRotation
+/// An announcement that a repository signing key is succeeded by another key, signed by the key being succeeded
+//
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct KeyRotation {
+    /// The key being succeeded
+    pub previous: PublicKey,
+    /// The key succeeding it
+    pub successor: PublicKey,
+    /// The signature of both keys by the previous key
+    pub signature: Signature
+}
+
+impl KeyRotation {
+    // message
+    /// The message the previous key signs
+    //
+    fn message(previous: &PublicKey, successor: &PublicKey) -> Vec<u8> {
+        let mut message = b"mangrove key rotation".to_vec();
+        message.extend_from_slice(previous.key_data.as_bytes());
+        message.extend_from_slice(successor.key_data.as_bytes());
+        message
+    }
+
+    // new
+    /// Announce that the provided key is succeeded by `successor`, signing the announcement with it.
+    /// ```
+    /// use libmangrove::crypt::PrivateKey;
+    /// use libmangrove::repo::KeyRotation;
+    /// let previous = PrivateKey::generate(String::from("previous"));
+    /// let successor = PrivateKey::generate(String::from("successor")).derive();
+    /// assert!(KeyRotation::new(&previous, &successor).unwrap().verify().is_ok());
+    /// ```
+    /// # Errors
+    /// This function will error if the previous key could not sign
+    pub fn new(previous: &dyn KeySigner, successor: &PublicKey) -> Result<Self, Box<dyn Error>> {
+        let previous_pk = previous.public_key();
+        let signature = previous.sign_message(&Self::message(&previous_pk, successor))?;
+        Ok(Self { previous: previous_pk, successor: successor.clone(), signature })
+    }
+
+    // verify
+    /// Check that the announcement was signed by the previous key
+    /// # Errors
+    /// This function will error if the signature is not a valid signature of both keys by the previous key
+    pub fn verify(&self) -> Result<(), Box<dyn Error>> {
+        if let Err(e) = self.previous.key_data.verify(&Self::message(&self.previous, &self.successor), &self.signature) {
+            return Err(format!("the rotation from {} to {} is not signed by {}: {e}", self.previous.fingerprint(), self.successor.fingerprint(), self.previous.fingerprint()).into());
+        }
+        Ok(())
+    }
+}
+
+// PoolLayout
+/// How the pool files of a repository are signed
+//
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PoolLayout {
+    /// Every pool file is a package in the Signed Package Format, signed with the repository's signing key
+    #[default]
+    Signed,
+    /// Every pool file is a plain package, with a detached signature made with the repository's signing key at `<pool file>.sig`
+    Detached
 }
 
 // get_repoinfo_url
@@ -26,4 +112,123 @@ pub struct Repository {
 //
 pub fn get_repoinfo_url(baseurl: Url) -> Result<Url, url::ParseError> {
     baseurl.join("repoinfo")
-}
\ No newline at end of file
+}
+
+// get_repository_dir
+/// Gets a baseurl as a directory `Url`, so that joining onto it keeps every path segment.
+//
+fn get_repository_dir(baseurl: Url) -> Url {
+    let mut baseurl = baseurl;
+    if !baseurl.path().ends_with('/') {
+        let path = format!("{}/", baseurl.path());
+        baseurl.set_path(&path);
+    }
+    baseurl
+}
+
+// get_repodata_url
+/// Gets the repodata `Url` for a given baseurl. The baseurl is always treated as a directory, whether or not it ends with a `/`.
+/// # Errors
+/// This function will error if the repodata `Url` could not be constructed.
+pub fn get_repodata_url(baseurl: Url) -> Result<Url, url::ParseError> {
+    get_repository_dir(baseurl).join("repodata")
+}
+
+// get_repodata_sig_url
+/// Gets the `Url` of the detached signature of the repodata, `<baseurl>/repodata.sig`, for a given baseurl.
+/// # Errors
+/// This function will error if the signature `Url` could not be constructed.
+pub fn get_repodata_sig_url(baseurl: Url) -> Result<Url, url::ParseError> {
+    get_repository_dir(baseurl).join("repodata.sig")
+}
+
+// get_pool_url
+/// Gets the `Url` of a package's pool file, `<baseurl>/pool/<filename>`, for a given baseurl.
+/// # Errors
+/// This function will error if the pool `Url` could not be constructed.
+pub fn get_pool_url(baseurl: Url, package: &Package) -> Result<Url, url::ParseError> {
+    get_repository_dir(baseurl).join(&format!("pool/{}", get_pkg_filename(package)))
+}
+
+// get_repository_name
+/// Gets the name a configured repository is referred to by in a `PkgSpec`: the last path segment of its baseurl, or its host if it has no path.
+//
+pub fn get_repository_name(configured: &ConfiguredRepository) -> String {
+    let last_segment = configured.baseurl.path_segments().and_then(|mut segments| segments.rfind(|x| !x.is_empty()));
+    last_segment.or_else(|| configured.baseurl.host_str()).unwrap_or_default().to_string()
+}
+
+// find_package
+/// Find the best package for the given `PkgSpec` and `Architecture` in the synced data of the configured repositories.
+///
+/// The best package is the one with the highest version satisfying the spec. If several repositories carry that version,
+/// the repository configured first wins. If the spec names a repository, only that repository is searched.
+//
+pub fn find_package<'a>(database: &'a Database, spec: &PkgSpec, arch: &Architecture) -> Option<(&'a ConfiguredRepository, &'a Package)> {
+    let mut best: Option<(&ConfiguredRepository, &Package)> = None;
+    for configured in &database.repositories {
+        if spec.repository.as_ref().is_some_and(|x| x != &get_repository_name(configured)) { continue; }
+        let Some(packages) = configured.repodata.packages.get(arch) else { continue; };
+        for package in packages {
+            if package.pkgname != spec.pkgname || !spec.version.matches(&package.pkgver) { continue; }
+            if best.is_none_or(|(_, b)| package.pkgver > b.pkgver) {
+                best = Some((configured, package));
+            }
+        }
+    }
+    best
+}
+
+// get_pool_sig_url
+/// Gets the `Url` of the detached signature of a package's pool file, `<baseurl>/pool/<filename>.sig`, for a given baseurl.
+/// Only repositories with the `PoolLayout::Detached` layout publish these.
+/// # Errors
+/// This function will error if the signature `Url` could not be constructed.
+pub fn get_pool_sig_url(baseurl: Url, package: &Package) -> Result<Url, url::ParseError> {
+    get_repository_dir(baseurl).join(&format!("pool/{}.sig", get_pkg_filename(package)))
+}
+
+// fetch_package
+/// Download a package's pool file from a configured repository into the directory `dir`, and verify it with the repository's signing key.
+///
+/// The pool file is written to disk as it is downloaded, and is verified by reading it back, so it is never held in memory.
+/// Signed pool files are decrypted with the key. Plain pool files of repositories with the `PoolLayout::Detached` layout
+/// are checked against their detached signature, which is downloaded as well. The returned `StoredPackage` verifies the file again every time it is read.
+/// # Errors
+/// This function will error if:
+/// - the pool file or its detached signature could not be downloaded, or the pool file could not be written to `dir`
+/// - the pool file could not be decrypted or verified with the repository's signing key
+/// - the pkginfo of the package differs in any way from the package listed in the repository data
+///
+/// The pool file is removed again if anything fails.
+pub fn fetch_package(configured: &ConfiguredRepository, package: &Package, dir: &Path) -> Result<StoredPackage, Box<dyn Error>> {
+    let path = dir.join(get_pkg_filename(package));
+    let fetch = || -> Result<StoredPackage, Box<dyn Error>> {
+        let url = get_pool_url(configured.baseurl.clone(), package)?;
+        fetch_url_to(&url, &mut File::create(&path)?)?;
+        let (stored, action) = match configured.repodata.pool_layout {
+            PoolLayout::Signed => (StoredPackage::signed(&path, configured.repodata.signing_key.clone()), "decrypt"),
+            PoolLayout::Detached => {
+                let sig_url = get_pool_sig_url(configured.baseurl.clone(), package)?;
+                let signature = match DetachedSignature::from_bytes(&fetch_url(&sig_url)?) {
+                    Ok(s) => s,
+                    Err(e) => return Err(format!("invalid signature {sig_url}: {e}").into())
+                };
+                (StoredPackage::detached(&path, configured.repodata.signing_key.clone(), signature), "verify")
+            }
+        };
+        let pkginfo = match stored.load() {
+            Ok(p) => p,
+            Err(e) => return Err(format!("failed to {action} {url}: {e}").into())
+        };
+        if pkginfo != *package {
+            return Err(format!("{url} contains {} {}, which does not match the repository data", pkginfo.pkgname, pkginfo.pkgver).into());
+        }
+        Ok(stored)
+    };
+    let result = fetch();
+    if result.is_err() && path.exists() {
+        fs::remove_file(&path)?;
+    }
+    result
+}
@@ -0,0 +1,466 @@
+//! # Dependency resolution
+//! Computes the complete set of packages that has to be installed to satisfy a request, choosing from local package files
+//! and the packages in the synced repositories. Dependencies are pulled in transitively, `provides` are treated as virtual
+//! packages, `conflicts` are avoided where possible and `replaces` schedule the replaced packages for removal.
+
+use std::error::Error;
+
+use url::Url;
+use version::{Comparator, Op, VersionReq};
+
+use crate::db::Database;
+use crate::pkg::{Package, pkg_conflicts, pkg_satisfies, PkgSpec, StoredPackage};
+use crate::platform::Architecture;
+use crate::repo::get_repository_name;
+use crate::version_any;
+
+// PackageSource
+/// Represents where a resolved package comes from
+//
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PackageSource {
+    /// A local package file
+    Local(StoredPackage),
+    /// The synced data of the configured repository with this baseurl
+    Repository(Url),
+}
+
+// ResolvedPackage
+/// Represents a package chosen by the resolver
+//
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedPackage {
+    /// The package to install
+    pub package: Package,
+    /// Where to get the package from
+    pub source: PackageSource,
+    /// The name of the package that needs this one, or `None` if it was requested directly
+    pub required_by: Option<String>,
+}
+
+// Resolution
+/// Represents the result of resolving a request
+//
+#[derive(Debug, Default)]
+pub struct Resolution {
+    /// The packages to install, ordered so that every package comes after the packages it depends on
+    pub install: Vec<ResolvedPackage>,
+    /// The names of installed packages that are replaced by packages in `install`, and have to be removed
+    pub replaced: Vec<String>,
+}
+
+// Candidate
+/// A package that could be chosen by the resolver
+//
+#[derive(Clone, Copy)]
+struct Candidate<'a> {
+    package: &'a Package,
+    /// The local package file, or the configured repository this package was found in and its position in the list of repositories
+    source: CandidateSource<'a>,
+}
+
+#[derive(Clone, Copy)]
+enum CandidateSource<'a> {
+    Local(&'a StoredPackage),
+    Repository(&'a Url, &'a str, usize),
+}
+
+// Selected
+/// A candidate that the resolver has chosen to install
+//
+struct Selected<'a> {
+    candidate: Candidate<'a>,
+    required_by: Option<String>,
+}
+
+// SelectError
+/// Why nothing could be selected for a spec
+//
+enum SelectError {
+    /// No candidate satisfies the spec, or every candidate conflicts with something, explained
+    Unavailable(String),
+    /// The best candidate that does not conflict with anything has dependencies that cannot be installed, one problem per entry
+    Dependencies(Vec<String>),
+}
+
+// pkg_replaces
+/// Determine if `package` replaces `other`
+//
+fn pkg_replaces(package: &Package, other: &Package) -> bool {
+    package.pkgname != other.pkgname && package.replaces.as_ref().is_some_and(|r| r.iter().any(|spec| spec.pkgname == other.pkgname && spec.version.matches(&other.pkgver)))
+}
+
+// from_repository
+/// Determine if a candidate from `source` comes from the repository `spec` names, if it names one
+//
+fn from_repository(spec: &PkgSpec, source: CandidateSource) -> bool {
+    match (&spec.repository, source) {
+        (None, _) => true,
+        (Some(_), CandidateSource::Local(_)) => false,
+        (Some(repository), CandidateSource::Repository(_, name, _)) => repository == name
+    }
+}
+
+struct Resolver<'a> {
+    database: &'a Database,
+    candidates: Vec<Candidate<'a>>,
+    selected: Vec<Selected<'a>>,
+}
+
+impl<'a> Resolver<'a> {
+    // remaining_installed
+    /// The installed packages that stay installed once every selected package is installed
+    fn remaining_installed(&self) -> impl Iterator<Item = &'a Package> + '_ {
+        self.database.installed_packages.iter().filter(move |installed| {
+            !self.selected.iter().any(|s| s.candidate.package.pkgname == installed.pkgname || pkg_replaces(s.candidate.package, installed))
+        })
+    }
+
+    // selected_satisfies
+    /// Determine if a selected package other than `pkgname` satisfies `spec`, coming from the repository it names if it names one
+    fn selected_satisfies(&self, spec: &PkgSpec, pkgname: &str) -> bool {
+        self.selected.iter().any(|s| s.candidate.package.pkgname != pkgname && pkg_satisfies(s.candidate.package, spec) && from_repository(spec, s.candidate.source))
+    }
+
+    // reason
+    /// Explain why the selected package at `index` is being installed
+    fn reason(&self, index: usize) -> String {
+        let mut chain: Vec<String> = vec![];
+        let mut current = index;
+        // bounded, in case of a dependency cycle
+        for _ in 0..self.selected.len() {
+            let Some(parent) = &self.selected[current].required_by else {
+                chain.push(if chain.is_empty() { "requested" } else { "which was requested" }.to_string());
+                break;
+            };
+            chain.push(format!("required by {parent}"));
+            match self.selected.iter().position(|s| &s.candidate.package.pkgname == parent) {
+                Some(i) => current = i,
+                None => break
+            }
+        }
+        format!("{} ({})", self.selected[index].candidate.package.pkgname, chain.join(", "))
+    }
+
+    // explain_missing
+    /// Explain why nothing satisfies `spec`
+    fn explain_missing(&self, spec: &PkgSpec) -> String {
+        if let Some(repository) = &spec.repository {
+            if !self.database.repositories.iter().any(|x| &get_repository_name(x) == repository) {
+                return format!("no repository named {repository} is configured");
+            }
+        }
+        let available: Vec<String> = self.candidates.iter()
+            .filter_map(|c| {
+                let source = match c.source {
+                    CandidateSource::Local(_) => "local file".to_string(),
+                    CandidateSource::Repository(_, name, _) => format!("from {name}")
+                };
+                if c.package.pkgname == spec.pkgname {
+                    return Some(format!("{} ({source})", c.package.pkgver));
+                }
+                let provided = c.package.provides.iter().flatten().find(|x| x.pkgname == spec.pkgname)?;
+                Some(format!("{provided} provided by {} {} ({source})", c.package.pkgname, c.package.pkgver))
+            })
+            .collect();
+        if available.is_empty() {
+            format!("no package named or providing {} was found", spec.pkgname)
+        } else {
+            format!("no version of {} satisfies the requirement, avaliable versions are {}", spec.pkgname, available.join(", "))
+        }
+    }
+
+    // matching
+    /// Every candidate that satisfies `spec`, best first.
+    ///
+    /// Packages with the exact name are preferred over packages that only provide it, local files over repositories,
+    /// higher versions over lower versions, and repositories configured earlier over ones configured later.
+    fn matching(&self, spec: &PkgSpec) -> Result<Vec<Candidate<'a>>, String> {
+        let mut matching: Vec<Candidate<'a>> = self.candidates.iter()
+            .filter(|c| pkg_satisfies(c.package, spec))
+            .filter(|c| from_repository(spec, c.source))
+            .copied()
+            .collect();
+        if matching.is_empty() {
+            return Err(self.explain_missing(spec));
+        }
+        matching.sort_by(|a, b| {
+            let key = |c: &Candidate| (
+                c.package.pkgname == spec.pkgname,
+                matches!(c.source, CandidateSource::Local(_)),
+                c.package.pkgver.clone(),
+                match c.source { CandidateSource::Repository(_, _, index) => std::cmp::Reverse(index), CandidateSource::Local(_) => std::cmp::Reverse(0) }
+            );
+            key(b).cmp(&key(a))
+        });
+        Ok(matching)
+    }
+
+    // rejections
+    /// Every reason `candidate` cannot be selected alongside the selected packages other than the one at `slot`, and the installed packages
+    fn rejections(&self, candidate: Candidate<'a>, slot: Option<usize>) -> Vec<String> {
+        let others = || self.selected.iter().enumerate().filter(move |(i, _)| Some(*i) != slot).map(|(_, s)| s.candidate.package);
+        if let Some(other) = others().find(|x| x.pkgname == candidate.package.pkgname) {
+            return vec![format!("{} {} cannot be installed alongside {} {}", candidate.package.pkgname, candidate.package.pkgver, other.pkgname, other.pkgver)];
+        }
+        let installed = self.remaining_installed().filter(|x| !pkg_replaces(candidate.package, x));
+        others().chain(installed).flat_map(|x| pkg_conflicts(candidate.package, x)).map(|x| x.to_string()).collect()
+    }
+
+    // choose
+    /// Choose the best candidate for `spec` that does not conflict with anything already chosen or staying installed, see `matching`
+    fn choose(&self, spec: &PkgSpec) -> Result<Candidate<'a>, String> {
+        let mut rejected: Vec<String> = vec![];
+        for candidate in self.matching(spec)? {
+            let rejections = self.rejections(candidate, None);
+            if rejections.is_empty() {
+                return Ok(candidate);
+            }
+            rejected.extend(rejections);
+        }
+        rejected.dedup();
+        Err(format!("every candidate was rejected: {}", rejected.join("; ")))
+    }
+
+    // select
+    /// Select the best candidate for `spec` along with its dependencies, transitively. `parent` is the index of the selected package
+    /// that depends on `spec`, if any. With a `slot`, the candidate replaces the selected package at that index instead of being added.
+    ///
+    /// Candidates are tried in the order of `matching`, skipping those that conflict with anything chosen or staying installed.
+    /// If the dependencies of a candidate cannot be installed, everything selected for them is dropped again and the next
+    /// candidate is tried, so a candidate is only given up on when it actually leads nowhere.
+    fn select(&mut self, spec: &PkgSpec, parent: Option<usize>, slot: Option<usize>) -> Result<(), SelectError> {
+        let matching = self.matching(spec).map_err(SelectError::Unavailable)?;
+        let required_by = parent.map(|i| self.selected[i].candidate.package.pkgname.clone());
+        let original = slot.map(|i| self.selected[i].candidate);
+        let mut rejected: Vec<String> = vec![];
+        let mut first_problems: Option<Vec<String>> = None;
+        for candidate in matching {
+            let rejections = self.rejections(candidate, slot);
+            if !rejections.is_empty() {
+                rejected.extend(rejections);
+                continue;
+            }
+            let mark = self.selected.len();
+            let index = if let Some(i) = slot {
+                self.selected[i].candidate = candidate;
+                i
+            } else {
+                self.selected.push(Selected { candidate, required_by: required_by.clone() });
+                mark
+            };
+            let problems = self.select_dependencies(index);
+            if problems.is_empty() {
+                return Ok(());
+            }
+            self.selected.truncate(mark);
+            first_problems.get_or_insert(problems);
+        }
+        if let (Some(i), Some(original)) = (slot, original) {
+            self.selected[i].candidate = original;
+        }
+        if let Some(problems) = first_problems {
+            return Err(SelectError::Dependencies(problems));
+        }
+        rejected.dedup();
+        Err(SelectError::Unavailable(format!("every candidate was rejected: {}", rejected.join("; "))))
+    }
+
+    // select_dependencies
+    /// Select every dependency of the selected package at `index` that is not satisfied yet, returning every dependency that could not be
+    //
+    fn select_dependencies(&mut self, index: usize) -> Vec<String> {
+        let package = self.selected[index].candidate.package;
+        let mut problems: Vec<String> = vec![];
+        for dependency in package.depends.iter().flatten() {
+            let satisfied = self.selected_satisfies(dependency, &package.pkgname)
+                || self.remaining_installed().any(|x| x.pkgname != package.pkgname && pkg_satisfies(x, dependency));
+            if satisfied { continue; }
+            match self.select(dependency, Some(index), None) {
+                Ok(()) => (),
+                Err(SelectError::Unavailable(e)) => problems.push(format!("cannot install {dependency}, needed by {}: {e}", self.reason(index))),
+                Err(SelectError::Dependencies(p)) => problems.extend(p)
+            }
+        }
+        problems
+    }
+
+    // install_order
+    /// The selected packages, ordered so that every package comes after the selected packages it depends on
+    fn install_order(&self) -> Vec<usize> {
+        let mut pending: Vec<usize> = (0..self.selected.len()).collect();
+        let mut queue: Vec<usize> = vec![];
+        while !pending.is_empty() {
+            let next = pending.iter().position(|&candidate| {
+                let package = self.selected[candidate].candidate.package;
+                !pending.iter().any(|&other| other != candidate && package.depends.as_ref().is_some_and(|d| d.iter().any(|x| pkg_satisfies(self.selected[other].candidate.package, x))))
+            }).unwrap_or(0); // dependency cycle, any order will do
+            queue.push(pending.remove(next));
+        }
+        queue
+    }
+}
+
+// resolve
+/// Compute the complete, ordered set of packages that needs to be installed for the given requests.
+///
+/// `local` contains local packages and their package files, which are always installed and are also used to satisfy dependencies.
+/// `requests` are resolved against the local packages and the synced repository data for `arch`. If `arch` is `None`,
+/// repositories are not searched. Dependencies that are already satisfied by installed packages are not installed again.
+/// # Errors
+/// This function will error with a readable explanation of every problem, one per line, if:
+/// - nothing satisfies a request or a dependency
+/// - every package that satisfies a request or dependency conflicts with something that is chosen or installed
+/// - two of the chosen packages conflict with each other, or with an installed package
+/// - a held package would be upgraded, reinstalled or replaced
+pub fn resolve(requests: &[PkgSpec], local: &[(Package, StoredPackage)], database: &Database, arch: Option<&Architecture>) -> Result<Resolution, Box<dyn Error>> {
+    let mut candidates: Vec<Candidate> = local.iter().map(|(package, stored)| Candidate { package, source: CandidateSource::Local(stored) }).collect();
+    let repository_names: Vec<String> = database.repositories.iter().map(get_repository_name).collect();
+    if let Some(arch) = arch {
+        for (index, configured) in database.repositories.iter().enumerate() {
+            if let Some(packages) = configured.repodata.packages.get(arch) {
+                candidates.extend(packages.iter().map(|package| Candidate { package, source: CandidateSource::Repository(&configured.baseurl, &repository_names[index], index) }));
+            }
+        }
+    }
+
+    let mut resolver = Resolver { database, candidates, selected: vec![] };
+    let mut problems: Vec<String> = vec![];
+
+    // Requests: local packages are always installed
+    for (package, stored) in local {
+        if resolver.selected.iter().any(|s| s.candidate.package.pkgname == package.pkgname) {
+            problems.push(format!("{} was requested more than once", package.pkgname));
+            continue;
+        }
+        resolver.selected.push(Selected { candidate: Candidate { package, source: CandidateSource::Local(stored) }, required_by: None });
+    }
+    let local_count = resolver.selected.len();
+    let mut requested: Vec<(usize, &PkgSpec)> = vec![];
+    for spec in requests {
+        if resolver.selected_satisfies(spec, "") { continue; }
+        match resolver.choose(spec) {
+            Ok(candidate) => {
+                requested.push((resolver.selected.len(), spec));
+                resolver.selected.push(Selected { candidate, required_by: None });
+            },
+            Err(e) => problems.push(format!("cannot install {spec}: {e}"))
+        }
+    }
+
+    // Dependencies, transitively. Requests whose dependencies cannot be installed are retried with the next best candidate.
+    for i in 0..local_count {
+        problems.extend(resolver.select_dependencies(i));
+    }
+    for (i, spec) in requested {
+        match resolver.select(spec, None, Some(i)) {
+            Ok(()) => (),
+            Err(SelectError::Unavailable(e)) => problems.push(format!("cannot install {spec}: {e}")),
+            Err(SelectError::Dependencies(p)) => problems.extend(p)
+        }
+    }
+
+    // Conflicts between the chosen packages, and with installed packages that stay installed
+    for (i, selected) in resolver.selected.iter().enumerate() {
+        let package = selected.candidate.package;
+        for (j, other) in resolver.selected.iter().enumerate().skip(i + 1) {
+            for conflict in pkg_conflicts(package, other.candidate.package) {
+                problems.push(format!("cannot install both {} and {}: {conflict}", resolver.reason(i), resolver.reason(j)));
+            }
+        }
+        for installed in resolver.remaining_installed().filter(|x| !pkg_replaces(package, x)) {
+            for conflict in pkg_conflicts(package, installed) {
+                problems.push(format!("cannot install {}: {conflict}", resolver.reason(i)));
+            }
+        }
+    }
+
+    // Held packages cannot be changed or replaced
+    for (i, selected) in resolver.selected.iter().enumerate() {
+        let package = selected.candidate.package;
+        if database.is_held(&package.pkgname) {
+            problems.push(format!("cannot install {}: {} is held", resolver.reason(i), package.pkgname));
+        }
+        for held in database.installed_packages.iter().filter(|x| database.is_held(&x.pkgname)) {
+            if pkg_replaces(package, held) {
+                problems.push(format!("cannot install {}: it replaces {}, which is held", resolver.reason(i), held.pkgname));
+            }
+        }
+    }
+
+    if !problems.is_empty() {
+        problems.dedup();
+        return Err(problems.join("\n").into());
+    }
+
+    let replaced: Vec<String> = database.installed_packages.iter()
+        .filter(|installed| !resolver.selected.iter().any(|s| s.candidate.package.pkgname == installed.pkgname))
+        .filter(|installed| resolver.selected.iter().any(|s| pkg_replaces(s.candidate.package, installed)))
+        .map(|installed| installed.pkgname.clone())
+        .collect();
+    let install = resolver.install_order().into_iter().map(|i| {
+        let selected = &resolver.selected[i];
+        ResolvedPackage {
+            package: selected.candidate.package.clone(),
+            source: match selected.candidate.source {
+                CandidateSource::Local(stored) => PackageSource::Local(stored.clone()),
+                CandidateSource::Repository(baseurl, _, _) => PackageSource::Repository(baseurl.clone())
+            },
+            required_by: selected.required_by.clone(),
+        }
+    }).collect();
+
+    Ok(Resolution { install, replaced })
+}
+
+// resolve_upgrades
+/// Compute the packages needed to bring every installed package up to date with the synced repository data for `arch`.
+///
+/// Every installed package is upgraded to the highest version available in the repositories. Installed packages that a
+/// repository package `replaces` are swapped for the replacing package instead. New dependencies are resolved like in `resolve`.
+/// Held packages, and packages named in `ignore`, are neither upgraded nor replaced.
+/// # Errors
+/// This function will error with a readable explanation of every problem, one per line, if:
+/// - the upgrades cannot be resolved, see `resolve`
+/// - an upgrade could only be resolved by also upgrading or replacing a held or ignored package
+pub fn resolve_upgrades(ignore: &[String], database: &Database, arch: &Architecture) -> Result<Resolution, Box<dyn Error>> {
+    let available: Vec<&Package> = database.repositories.iter().filter_map(|x| x.repodata.packages.get(arch)).flatten().collect();
+    let is_installed = |pkgname: &str| database.installed_packages.iter().any(|x| x.pkgname == pkgname);
+    let skipped = |pkgname: &String| ignore.contains(pkgname) || database.is_held(pkgname);
+
+    let mut requests: Vec<PkgSpec> = vec![];
+    for installed in database.installed_packages.iter().filter(|x| !skipped(&x.pkgname)) {
+        let replacement = available.iter().find(|x| !skipped(&x.pkgname) && !is_installed(&x.pkgname) && pkg_replaces(x, installed));
+        let request = match replacement {
+            Some(replacement) => PkgSpec { pkgname: replacement.pkgname.clone(), version: version_any!(), repository: None },
+            None if available.iter().any(|x| x.pkgname == installed.pkgname && x.pkgver > installed.pkgver) => PkgSpec {
+                pkgname: installed.pkgname.clone(),
+                version: VersionReq { comparators: vec![Comparator {
+                    op: Op::Greater,
+                    major: installed.pkgver.major,
+                    minor: Some(installed.pkgver.minor),
+                    patch: Some(installed.pkgver.patch),
+                    pre: installed.pkgver.pre.clone()
+                }] },
+                repository: None
+            },
+            None => continue
+        };
+        if !requests.contains(&request) {
+            requests.push(request);
+        }
+    }
+
+    let resolution = resolve(&requests, &[], database, Some(arch))?;
+    let mut problems: Vec<String> = vec![];
+    for resolved in resolution.install.iter().filter(|x| ignore.contains(&x.package.pkgname)) {
+        problems.push(format!("{} is ignored, but {} {} is needed by {}", resolved.package.pkgname, resolved.package.pkgname, resolved.package.pkgver, resolved.required_by.as_deref().unwrap_or("the upgrade")));
+    }
+    for pkgname in resolution.replaced.iter().filter(|x| ignore.contains(x)) {
+        problems.push(format!("{pkgname} is ignored, but it is replaced by a package being installed"));
+    }
+    if !problems.is_empty() {
+        return Err(problems.join("\n").into());
+    }
+    Ok(resolution)
+}
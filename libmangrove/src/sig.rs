@@ -0,0 +1,354 @@
+//! # Detached signatures
+//! A detached signature signs a plain, unencrypted package (or any other file) from a separate `.sig` file,
+//! so the signed file stays usable by tools that do not understand the Signed Package Format.
+
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+use std::io;
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use arrayref::array_ref;
+use ed25519_dalek::{Signature, Verifier};
+use sha2::{Digest, Sha256};
+
+use crate::crypt::{fingerprint_raw, KeySigner, PublicKey};
+use crate::spf::{SPF_END_SENTINEL, SPF_SIGNATURE_LEN};
+use crate::trustcache::{is_pk_trusted, known_keys, Trustcache};
+
+// SIG_MAGIC
+/// The magic at the start of every detached signature, 'MGVS' in ascii
+//
+pub const SIG_MAGIC: [u8; 4] = [0x4d, 0x47, 0x56, 0x53];
+
+// SIG_VERSION
+/// The version of the detached signature format
+//
+pub const SIG_VERSION: u8 = 0x01;
+
+// SIG_SIGNED_LEN
+/// Length of the part of a detached signature that is signed: magic, version, signer fingerprint, data length and data hash
+//
+const SIG_SIGNED_LEN: usize = 4 + 1 + 32 + 8 + 32;
+
+// SIG_LEN
+/// Length of a detached signature: the signed part, the signature and the end sentinel
+//
+pub const SIG_LEN: usize = SIG_SIGNED_LEN + SPF_SIGNATURE_LEN + 1;
+
+// SigError
+/// Every way data can fail to be a structurally valid detached signature
+//
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SigError {
+    /// The data does not start with the 'MGVS' magic
+    MissingMagic,
+    /// The byte after the magic is not a known format version
+    UnknownVersion(u8),
+    /// The data is not exactly `SIG_LEN` bytes long
+    BadLength(usize),
+    /// The last byte is not the 0x42 end sentinel
+    MissingEndSentinel(u8),
+    /// The signature is not a valid ed25519 signature
+    MalformedSignature(String)
+}
+
+impl Display for SigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingMagic => write!(f, "Not a detached signature (magic missing)"),
+            Self::UnknownVersion(v) => write!(f, "Not a detached signature (unknown format version 0x{v:02x})"),
+            Self::BadLength(n) => write!(f, "Signature has been corrupt ({n} bytes present, expected {SIG_LEN})"),
+            Self::MissingEndSentinel(b) => write!(f, "Signature has been corrupt (end sentinel missing, found 0x{b:02x})"),
+            Self::MalformedSignature(e) => write!(f, "Failed to load signature data: {e}")
+        }
+    }
+}
+
+impl Error for SigError {}
+
+// DetachedSignature
+/// A detached signature over a file, see the module documentation
+//
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DetachedSignature {
+    /// The fingerprint of the key the signature claims to be made with, see `PublicKey::fingerprint`
+    pub fingerprint: [u8; 32],
+    /// The length of the signed file
+    pub length: u64,
+    /// The sha256 hash of the signed file
+    pub sha256: [u8; 32],
+    /// The signature of every field above, as laid out in the signature file
+    pub signature: Signature
+}
+
+impl DetachedSignature {
+    // signed_bytes
+    /// The part of the signature file that the signature covers
+    //
+    fn signed_bytes(fingerprint: &[u8; 32], length: u64, sha256: &[u8; 32]) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(SIG_SIGNED_LEN);
+        bytes.extend_from_slice(&SIG_MAGIC);
+        bytes.push(SIG_VERSION);
+        bytes.extend_from_slice(fingerprint);
+        bytes.extend_from_slice(&length.to_be_bytes());
+        bytes.extend_from_slice(sha256);
+        bytes
+    }
+
+    // fingerprint_hex
+    /// The hex-encoded fingerprint of the key the signature claims to be made with
+    //
+    pub fn fingerprint_hex(&self) -> String {
+        hex::encode(self.fingerprint)
+    }
+
+    // to_bytes
+    /// Serialize this signature into the contents of a `.sig` file
+    //
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Self::signed_bytes(&self.fingerprint, self.length, &self.sha256);
+        bytes.extend_from_slice(&self.signature.to_bytes());
+        bytes.push(SPF_END_SENTINEL);
+        bytes
+    }
+
+    // from_bytes
+    /// Parse the contents of a `.sig` file, without verifying the signature.
+    /// ```
+    /// use libmangrove::crypt::PrivateKey;
+    /// use libmangrove::sig::{DetachedSignature, sign_detached, SigError};
+    /// let signature = sign_detached(&PrivateKey::generate(String::from("test_key")), &[0x42u8; 5]).unwrap();
+    /// assert_eq!(DetachedSignature::from_bytes(&signature.to_bytes()).unwrap(), signature);
+    /// assert_eq!(DetachedSignature::from_bytes(&signature.to_bytes()[..20]).unwrap_err(), SigError::BadLength(20));
+    /// ```
+    /// # Errors
+    /// This function returns a `SigError` describing the first malformation it finds, see its variants
+    //
+    pub fn from_bytes(data: &[u8]) -> Result<Self, SigError> {
+        if data.len() < 4 || data[0..4] != SIG_MAGIC {
+            return Err(SigError::MissingMagic);
+        }
+        match data.get(4) {
+            Some(&SIG_VERSION) => (),
+            Some(v) => return Err(SigError::UnknownVersion(*v)),
+            None => return Err(SigError::BadLength(data.len()))
+        }
+        if data.len() != SIG_LEN {
+            return Err(SigError::BadLength(data.len()));
+        }
+        if data[SIG_LEN - 1] != SPF_END_SENTINEL {
+            return Err(SigError::MissingEndSentinel(data[SIG_LEN - 1]));
+        }
+        Ok(Self {
+            fingerprint: *array_ref!(data, 5, 32),
+            length: u64::from_be_bytes(*array_ref!(data, 37, 8)),
+            sha256: *array_ref!(data, 45, 32),
+            signature: Signature::from_bytes(&data[SIG_SIGNED_LEN..SIG_SIGNED_LEN + SPF_SIGNATURE_LEN])
+                .map_err(|e| SigError::MalformedSignature(e.to_string()))?
+        })
+    }
+}
+
+// hash_stream
+/// Read the provided stream to its end, returning its length and sha256 hash
+/// # Errors
+/// This function will error if reading from `reader` fails
+//
+fn hash_stream<R: Read>(reader: &mut R) -> Result<(u64, [u8; 32]), Box<dyn Error>> {
+    let mut hasher = Sha256::new();
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut length: u64 = 0;
+    loop {
+        let n = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err.into())
+        };
+        hasher.update(&buffer[..n]);
+        length += n as u64;
+    }
+    Ok((length, hasher.finalize().into()))
+}
+
+// sign_detached
+/// Create a detached signature over the provided data.
+/// ```
+/// use libmangrove::crypt::PrivateKey;
+/// use libmangrove::sig::{sign_detached, verify_detached};
+/// let key = PrivateKey::generate(String::from("test_key"));
+/// let signature = sign_detached(&key, b"package data").unwrap();
+/// assert!(verify_detached(&key.derive(), &signature, b"package data").is_ok());
+/// assert!(verify_detached(&key.derive(), &signature, b"tampered data").is_err());
+/// ```
+/// # Errors
+/// This function will error if the key could not sign
+pub fn sign_detached(key: &dyn KeySigner, data: &[u8]) -> Result<DetachedSignature, Box<dyn Error>> {
+    sign_detached_hash(key, data.len() as u64, Sha256::digest(data).into())
+}
+
+// sign_detached_stream
+/// Create a detached signature over everything read from the provided stream, without holding it in memory.
+/// # Errors
+/// This function will error if reading from `reader` fails
+//
+pub fn sign_detached_stream<R: Read>(key: &dyn KeySigner, mut reader: R) -> Result<DetachedSignature, Box<dyn Error>> {
+    let (length, sha256) = hash_stream(&mut reader)?;
+    sign_detached_hash(key, length, sha256)
+}
+
+// sign_detached_hash
+/// Create a detached signature over a file with the provided length and hash
+/// # Errors
+/// This function will error if the key could not sign
+fn sign_detached_hash(key: &dyn KeySigner, length: u64, sha256: [u8; 32]) -> Result<DetachedSignature, Box<dyn Error>> {
+    let mut fingerprint = [0u8; 32];
+    fingerprint.copy_from_slice(&fingerprint_raw(&key.public_key().key_data));
+    let signature = key.sign_message(&DetachedSignature::signed_bytes(&fingerprint, length, &sha256))?;
+    Ok(DetachedSignature { fingerprint, length, sha256, signature })
+}
+
+// verify_detached
+/// Verify a detached signature over the provided data with the provided key, see `sign_detached`
+/// # Errors
+/// This function will error if the signature was not made with this key, or does not match the data
+//
+pub fn verify_detached(vkey: &PublicKey, signature: &DetachedSignature, data: &[u8]) -> Result<(), Box<dyn Error>> {
+    verify_detached_stream(vkey, signature, data).map(|_| ())
+}
+
+// verify_detached_stream
+/// Verify a detached signature over everything read from the provided stream with the provided key, returning the amount of data read.
+/// # Errors
+/// This function will error if reading from `reader` fails, or if the signature was not made with this key, or does not match the data
+//
+pub fn verify_detached_stream<R: Read>(vkey: &PublicKey, signature: &DetachedSignature, reader: R) -> Result<u64, Box<dyn Error>> {
+    let mut reader = DetachedReader::new(vkey, signature, reader)?;
+    Ok(io::copy(&mut reader, &mut io::sink())?)
+}
+
+// DetachedReader
+/// Reads a file that has a detached signature, verifying it on the way.
+///
+/// The signature itself is checked up front. The data is hashed as it is handed out, and once the end is reached, reading fails
+/// with an `InvalidData` error if it does not match the signed length and hash. The data has only been verified once a read returns 0.
+/// ```
+/// use std::io::Read;
+/// use libmangrove::crypt::PrivateKey;
+/// use libmangrove::sig::{DetachedReader, sign_detached};
+/// let key = PrivateKey::generate(String::from("test_key"));
+/// let signature = sign_detached(&key, b"package data").unwrap();
+/// let mut data = String::new();
+/// DetachedReader::new(&key.derive(), &signature, &b"package data"[..]).unwrap().read_to_string(&mut data).unwrap();
+/// assert_eq!(data, "package data");
+/// assert!(DetachedReader::new(&key.derive(), &signature, &b"tampered data"[..]).unwrap().read_to_string(&mut data).is_err());
+/// ```
+//
+pub struct DetachedReader<R: Read> {
+    inner: R,
+    length: u64,
+    sha256: [u8; 32],
+    hasher: Sha256,
+    /// How much data has been handed out
+    found: u64
+}
+
+impl<R: Read> DetachedReader<R> {
+    // new
+    /// Start reading the data in `inner`, which must match a detached signature made with `vkey`
+    /// # Errors
+    /// This function will error if the signature was not made with this key, or is invalid
+    //
+    pub fn new(vkey: &PublicKey, signature: &DetachedSignature, inner: R) -> Result<Self, Box<dyn Error>> {
+        if signature.fingerprint_hex() != vkey.fingerprint() {
+            return Err(format!("The signature was made with key {}, not {}", signature.fingerprint_hex(), vkey.fingerprint()).into());
+        }
+        let signed = DetachedSignature::signed_bytes(&signature.fingerprint, signature.length, &signature.sha256);
+        if let Err(e) = vkey.key_data.verify(&signed, &signature.signature) {
+            return Err(format!("The digital signature is invalid: {e}").into());
+        }
+        Ok(Self { inner, length: signature.length, sha256: signature.sha256, hasher: Sha256::new(), found: 0 })
+    }
+}
+
+impl<R: Read> Read for DetachedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if n == 0 && !buf.is_empty() {
+            if self.found != self.length {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!("The signed data has been modified ({} bytes present, {} bytes were signed)", self.found, self.length)));
+            }
+            if <[u8; 32]>::from(self.hasher.clone().finalize()) != self.sha256 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "The signed data has been modified (hash mismatch)"));
+            }
+        }
+        self.hasher.update(&buf[..n]);
+        self.found += n as u64;
+        Ok(n)
+    }
+}
+
+// find_detached_key
+/// Find the key in the trustcache that the detached signature claims to be made with, by its fingerprint.
+///
+/// The key is only proven to be the signer once `verify_detached` succeeds with it.
+//
+pub fn find_detached_key(signature: &DetachedSignature, trustcache: &Trustcache) -> Option<PublicKey> {
+    let fingerprint = signature.fingerprint_hex();
+    known_keys(trustcache).into_iter().find(|k| k.fingerprint() == fingerprint)
+}
+
+// verify_detached_trusted
+/// Verify a detached signature over the provided data against the trustcache, returning the key that made it.
+/// # Errors
+/// This function will error if:
+/// - the signing key is not in the trustcache, or has been blacklisted
+/// - an invalid key is present in the trustcache
+/// - the signature does not match the data, see `verify_detached`
+pub fn verify_detached_trusted(trustcache: &Trustcache, signature: &DetachedSignature, data: &[u8]) -> Result<PublicKey, Box<dyn Error>> {
+    verify_detached_trusted_stream(trustcache, signature, data)
+}
+
+// verify_detached_trusted_stream
+/// Verify a detached signature over everything read from the provided stream against the trustcache, see `verify_detached_trusted`
+/// # Errors
+/// This function will error if reading from `reader` fails, or for any of the reasons `verify_detached_trusted` does
+//
+pub fn verify_detached_trusted_stream<R: Read>(trustcache: &Trustcache, signature: &DetachedSignature, reader: R) -> Result<PublicKey, Box<dyn Error>> {
+    let key = trusted_detached_key(signature, trustcache)?;
+    verify_detached_stream(&key, signature, reader)?;
+    Ok(key)
+}
+
+// trusted_detached_key
+/// Find the key in the trustcache that the detached signature claims to be made with, and check that it is trusted, without reading the signed data.
+///
+/// The data can then be verified while it is read with a `DetachedReader`.
+/// # Errors
+/// This function will error if the signing key is not in the trustcache or is not trusted, or if an invalid key is present in the trustcache
+//
+pub fn trusted_detached_key(signature: &DetachedSignature, trustcache: &Trustcache) -> Result<PublicKey, Box<dyn Error>> {
+    let Some(key) = find_detached_key(signature, trustcache) else {
+        return Err(format!("The signature was made with key {}, which is not in the trustcache", signature.fingerprint_hex()).into());
+    };
+    let trust = is_pk_trusted(trustcache, &key)?;
+    if !trust.is_trusted() {
+        return Err(format!("The signature was made with key {}, which is {trust}", signature.fingerprint_hex()).into());
+    }
+    Ok(key)
+}
+
+// get_sig_path
+/// Get the path of the detached signature for a file, the file's path with `.sig` appended
+/// ```
+/// use std::path::{Path, PathBuf};
+/// use libmangrove::sig::get_sig_path;
+/// assert_eq!(get_sig_path(Path::new("pool/pkg_1.0.0_amd64.mgve")), PathBuf::from("pool/pkg_1.0.0_amd64.mgve.sig"));
+/// ```
+//
+pub fn get_sig_path(path: &Path) -> PathBuf {
+    let mut sig = path.as_os_str().to_owned();
+    sig.push(".sig");
+    PathBuf::from(sig)
+}
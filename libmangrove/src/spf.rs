@@ -0,0 +1,364 @@
+//! # Bounds-checked parsing of packages in the Signed Package Format
+
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fmt::{Display, Formatter};
+
+use arrayref::array_ref;
+use ed25519_dalek::Signature;
+
+use crate::crypt::{SPF_MAGIC, SpfVersion};
+
+// SPF_SIGNATURE_LEN
+/// Length of an ed25519 signature
+//
+pub(crate) const SPF_SIGNATURE_LEN: usize = 64;
+
+// SPF_V2_HEADER_LEN
+/// Length of the SPF v2 header: magic, version, signer fingerprint, package id and chunk size
+//
+pub(crate) const SPF_V2_HEADER_LEN: usize = 4 + 1 + 32 + 12 + 4;
+
+// SPF_V2_FRAME_LEN
+/// Length of the frame in front of every SPF v2 chunk: the chunk flag and the sealed chunk length
+//
+pub(crate) const SPF_V2_FRAME_LEN: usize = 1 + 4;
+
+// SPF_V2_TAG_LEN
+/// Length of the AES-256-GCM tag at the end of every sealed chunk of SPF v2 packages
+//
+pub(crate) const SPF_V2_TAG_LEN: usize = 16;
+
+// SPF_V2_CHUNK_SIZE
+/// The amount of package data sealed and signed in each chunk of SPF v2 packages, unless another size is asked for
+//
+pub const SPF_V2_CHUNK_SIZE: u32 = 1024 * 1024;
+
+// SPF_V2_MAX_CHUNK_SIZE
+/// The largest chunk size SPF v2 packages may use. Readers hold one chunk in memory at a time, so this bounds their memory use.
+//
+pub const SPF_V2_MAX_CHUNK_SIZE: u32 = 16 * 1024 * 1024;
+
+// SPF_V2_CHUNK_MORE
+/// The flag of every SPF v2 chunk but the last one
+//
+pub(crate) const SPF_V2_CHUNK_MORE: u8 = 0x00;
+
+// SPF_V2_CHUNK_LAST
+/// The flag of the last SPF v2 chunk
+//
+pub(crate) const SPF_V2_CHUNK_LAST: u8 = 0x01;
+
+// SPF_LEGACY_HEADER_LEN
+/// Length of the legacy header: magic, signature length, signature, separator and data length
+//
+const SPF_LEGACY_HEADER_LEN: usize = 4 + 1 + SPF_SIGNATURE_LEN + 1 + 4;
+
+// SPF_END_SENTINEL
+/// The last byte of every signed package
+//
+pub(crate) const SPF_END_SENTINEL: u8 = 0x42;
+
+// SpfError
+/// Every way data can fail to be a structurally valid package in the Signed Package Format
+//
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpfError {
+    /// The data does not start with the 'MGVE' magic
+    MissingMagic,
+    /// The byte after the magic is not a known format version
+    UnknownVersion(u8),
+    /// The data ends before the end of the header
+    ShortHeader {
+        /// Length of the header of this version
+        needed: usize,
+        /// Length of the data
+        found: usize
+    },
+    /// The data ends before the end of the package data and signature
+    Truncated {
+        /// Length of the package, according to its header
+        needed: u64,
+        /// Length of the data
+        found: usize
+    },
+    /// The package or chunk data length in the header is impossible for this version
+    BadDataLength {
+        /// The length in the header
+        declared: u64,
+        /// Why the length is impossible
+        reason: &'static str
+    },
+    /// The chunk size in the header of a SPF v2 package is zero, or above `SPF_V2_MAX_CHUNK_SIZE`
+    BadChunkSize(u32),
+    /// The flag of a SPF v2 chunk is neither 0x00 nor 0x01
+    UnknownChunkFlag(u8),
+    /// The signature/data separator of a legacy package is not 0x00
+    MissingSeparator(u8),
+    /// The byte at the end of the package is not the 0x42 end sentinel
+    MissingEndSentinel(u8),
+    /// There is data after the end sentinel
+    TrailingData(usize),
+    /// The signature is not a valid ed25519 signature
+    MalformedSignature(String)
+}
+
+impl Display for SpfError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::MissingMagic => write!(f, "Not an encrypted package (magic missing)"),
+            Self::UnknownVersion(v) => write!(f, "Not an encrypted package (unknown format version 0x{v:02x})"),
+            Self::ShortHeader { needed, found } => write!(f, "Package has been corrupt (header truncated, {found} of {needed} bytes present)"),
+            Self::Truncated { needed, found } => write!(f, "Package has been corrupt (data truncated, {found} of {needed} bytes present)"),
+            Self::BadDataLength { declared, reason } => write!(f, "Package has been corrupt (data length {declared} {reason})"),
+            Self::BadChunkSize(n) => write!(f, "Package has been corrupt (chunk size {n} is not between 1 and {SPF_V2_MAX_CHUNK_SIZE})"),
+            Self::UnknownChunkFlag(b) => write!(f, "Package has been corrupt (unknown chunk flag 0x{b:02x})"),
+            Self::MissingSeparator(b) => write!(f, "Package has been corrupt (s/d sentinel missing, found 0x{b:02x})"),
+            Self::MissingEndSentinel(b) => write!(f, "Package has been corrupt (end sentinel missing, found 0x{b:02x})"),
+            Self::TrailingData(n) => write!(f, "Package has been corrupt ({n} bytes of trailing data after the end sentinel)"),
+            Self::MalformedSignature(e) => write!(f, "Failed to load signature data: {e}")
+        }
+    }
+}
+
+impl Error for SpfError {}
+
+// LegacyPackage
+/// The fields of a legacy signed package, borrowed from the package data
+//
+#[derive(Debug)]
+pub struct LegacyPackage<'a> {
+    /// The signature of the unencrypted package data
+    pub signature: Signature,
+    /// The encrypted package data
+    pub data: &'a [u8]
+}
+
+// V2Header
+/// The header of a SPF v2 package, borrowed from the package data
+//
+#[derive(Debug)]
+pub struct V2Header<'a> {
+    /// The entire header, which every chunk signature covers and which is the associated data of every sealed chunk
+    pub bytes: &'a [u8],
+    /// The fingerprint of the key the package claims to be signed with
+    pub fingerprint: &'a [u8],
+    /// The random id of the package, which the package key and chunk nonces are derived from
+    pub id: &'a [u8; 12],
+    /// The amount of package data in every chunk but the last one
+    pub chunk_size: u32
+}
+
+// V2Chunk
+/// A single chunk of a SPF v2 package, borrowed from the package data
+//
+#[derive(Debug)]
+pub struct V2Chunk<'a> {
+    /// The position of this chunk in the package, starting at 0. It is not stored, but every chunk signature covers it.
+    pub index: u64,
+    /// Whether this is the last chunk of the package
+    pub last: bool,
+    /// The chunk flag and the sealed chunk length
+    pub frame: &'a [u8],
+    /// The sealed chunk data, including the tag
+    pub sealed: &'a [u8],
+    /// The signature of the header, the chunk index, the frame and the sealed chunk data
+    pub signature: Signature
+}
+
+// V2Package
+/// The fields of a SPF v2 package, borrowed from the package data
+//
+#[derive(Debug)]
+pub struct V2Package<'a> {
+    /// The package header
+    pub header: V2Header<'a>,
+    /// Every chunk of the package, in order. There is always at least one, and only the last one is flagged as such.
+    pub chunks: Vec<V2Chunk<'a>>
+}
+
+// SignedPackage
+/// A structurally valid package in the Signed Package Format. Nothing has been verified or decrypted yet.
+//
+#[derive(Debug)]
+pub enum SignedPackage<'a> {
+    /// A legacy package
+    Legacy(LegacyPackage<'a>),
+    /// A SPF v2 package
+    V2(V2Package<'a>)
+}
+
+impl SignedPackage<'_> {
+    // version
+    /// The format version of this package
+    //
+    pub const fn version(&self) -> SpfVersion {
+        match self {
+            Self::Legacy(_) => SpfVersion::Legacy,
+            Self::V2(_) => SpfVersion::V2
+        }
+    }
+}
+
+// parse_signature
+/// Load the signature, without verifying it
+/// # Errors
+/// This function will error if the bytes are not a valid ed25519 signature
+//
+pub(crate) fn parse_signature(bytes: &[u8]) -> Result<Signature, SpfError> {
+    Signature::from_bytes(bytes).map_err(|e| SpfError::MalformedSignature(e.to_string()))
+}
+
+// parse_end
+/// Check that the package is exactly `needed` bytes long, and ends with the end sentinel
+/// # Errors
+/// This function will error if the data is shorter or longer than that, or the end sentinel is missing
+//
+fn parse_end(data: &[u8], needed: u64) -> Result<(), SpfError> {
+    let end = match usize::try_from(needed) {
+        Ok(end) if end <= data.len() => end,
+        _ => return Err(SpfError::Truncated { needed, found: data.len() })
+    };
+    if data[end - 1] != SPF_END_SENTINEL {
+        return Err(SpfError::MissingEndSentinel(data[end - 1]));
+    }
+    if end != data.len() {
+        return Err(SpfError::TrailingData(data.len() - end));
+    }
+    Ok(())
+}
+
+// parse_package
+/// Parse the provided data as a package in the Signed Package Format, checking every bound on the way.
+/// ```
+/// use libmangrove::crypt::{encrypt_package, PrivateKey, SpfVersion};
+/// use libmangrove::spf::{parse_package, SpfError};
+/// let data = encrypt_package(&PrivateKey::generate(String::from("test_key")), &[0x42u8; 5]).unwrap();
+/// assert_eq!(parse_package(&data).unwrap().version(), SpfVersion::V2);
+/// assert_eq!(parse_package(&data[..20]).unwrap_err(), SpfError::ShortHeader { needed: 53, found: 20 });
+/// ```
+/// # Errors
+/// This function returns a `SpfError` describing the first malformation it finds, see its variants
+//
+pub fn parse_package(data: &[u8]) -> Result<SignedPackage<'_>, SpfError> {
+    if data.len() < 4 || data[0..4] != SPF_MAGIC {
+        return Err(SpfError::MissingMagic);
+    }
+    match SpfVersion::detect(data) {
+        Some(SpfVersion::V2) => parse_package_v2(data).map(SignedPackage::V2),
+        Some(SpfVersion::Legacy) => parse_package_legacy(data).map(SignedPackage::Legacy),
+        None => Err(data.get(4).map_or(SpfError::ShortHeader { needed: 5, found: data.len() }, |v| SpfError::UnknownVersion(*v)))
+    }
+}
+
+// parse_header_v2
+/// Parse the header at the start of a SPF v2 package
+/// # Errors
+/// This function will error if the data is shorter than the header, or the chunk size is impossible
+//
+pub(crate) fn parse_header_v2(data: &[u8]) -> Result<V2Header<'_>, SpfError> {
+    if data.len() < SPF_V2_HEADER_LEN {
+        return Err(SpfError::ShortHeader { needed: SPF_V2_HEADER_LEN, found: data.len() });
+    }
+    let chunk_size = u32::from_be_bytes(*array_ref!(data, 49, 4));
+    if chunk_size == 0 || chunk_size > SPF_V2_MAX_CHUNK_SIZE {
+        return Err(SpfError::BadChunkSize(chunk_size));
+    }
+    Ok(V2Header {
+        bytes: &data[..SPF_V2_HEADER_LEN],
+        fingerprint: &data[5..37],
+        id: array_ref!(data, 37, 12),
+        chunk_size
+    })
+}
+
+// parse_frame_v2
+/// Parse the frame in front of a SPF v2 chunk, returning whether it is the last chunk and the length of its sealed data.
+///
+/// Every chunk but the last one seals exactly `chunk_size` bytes, the last one seals at most that many.
+/// # Errors
+/// This function will error if the chunk flag is unknown, or the sealed length is impossible for the chunk size
+//
+pub(crate) fn parse_frame_v2(chunk_size: u32, frame: [u8; SPF_V2_FRAME_LEN]) -> Result<(bool, usize), SpfError> {
+    let last = match frame[0] {
+        SPF_V2_CHUNK_MORE => false,
+        SPF_V2_CHUNK_LAST => true,
+        flag => return Err(SpfError::UnknownChunkFlag(flag))
+    };
+    let c_len = u32::from_be_bytes(*array_ref!(frame, 1, 4));
+    let declared = u64::from(c_len);
+    let full = u64::from(chunk_size) + SPF_V2_TAG_LEN as u64;
+    if declared < SPF_V2_TAG_LEN as u64 {
+        return Err(SpfError::BadDataLength { declared, reason: "is shorter than the authentication tag" });
+    }
+    if declared > full {
+        return Err(SpfError::BadDataLength { declared, reason: "is longer than the chunk size" });
+    }
+    if !last && declared != full {
+        return Err(SpfError::BadDataLength { declared, reason: "does not fill the chunk size, but is not the last chunk" });
+    }
+    // at most SPF_V2_MAX_CHUNK_SIZE + SPF_V2_TAG_LEN, which always fits
+    Ok((last, c_len as usize))
+}
+
+// parse_package_v2
+/// Parse a SPF v2 package, see `parse_package`
+/// # Errors
+/// This function returns a `SpfError` describing the first malformation it finds
+//
+fn parse_package_v2(data: &[u8]) -> Result<V2Package<'_>, SpfError> {
+    let header = parse_header_v2(data)?;
+    let mut chunks: Vec<V2Chunk> = vec![];
+    let mut offset = SPF_V2_HEADER_LEN;
+    loop {
+        let frame_end = offset + SPF_V2_FRAME_LEN;
+        if frame_end > data.len() {
+            return Err(SpfError::Truncated { needed: frame_end as u64, found: data.len() });
+        }
+        let (last, sealed_len) = parse_frame_v2(header.chunk_size, *array_ref!(data, offset, SPF_V2_FRAME_LEN))?;
+        let sealed_end = frame_end + sealed_len;
+        let chunk_end = sealed_end + SPF_SIGNATURE_LEN;
+        if chunk_end > data.len() {
+            return Err(SpfError::Truncated { needed: chunk_end as u64, found: data.len() });
+        }
+        chunks.push(V2Chunk {
+            index: chunks.len() as u64,
+            last,
+            frame: &data[offset..frame_end],
+            sealed: &data[frame_end..sealed_end],
+            signature: parse_signature(&data[sealed_end..chunk_end])?
+        });
+        offset = chunk_end;
+        if last {
+            break;
+        }
+    }
+    parse_end(data, offset as u64 + 1)?;
+    Ok(V2Package { header, chunks })
+}
+
+// parse_package_legacy
+/// Parse a legacy package, see `parse_package`
+/// # Errors
+/// This function returns a `SpfError` describing the first malformation it finds
+//
+fn parse_package_legacy(data: &[u8]) -> Result<LegacyPackage<'_>, SpfError> {
+    if data.len() < SPF_LEGACY_HEADER_LEN {
+        return Err(SpfError::ShortHeader { needed: SPF_LEGACY_HEADER_LEN, found: data.len() });
+    }
+    // the signature length is the version byte, so it is always 64 here
+    let s_dat = &data[5..5 + SPF_SIGNATURE_LEN];
+    let separator = data[5 + SPF_SIGNATURE_LEN];
+    if separator != 0x00 {
+        return Err(SpfError::MissingSeparator(separator));
+    }
+    let d_len = u32::from_be_bytes(*array_ref!(data, 6 + SPF_SIGNATURE_LEN, 4));
+    if d_len == 0 || d_len % 16 != 0 {
+        return Err(SpfError::BadDataLength { declared: u64::from(d_len), reason: "is not a whole number of AES blocks" });
+    }
+    parse_end(data, u64::from(d_len) + (SPF_LEGACY_HEADER_LEN + 1) as u64)?;
+    Ok(LegacyPackage {
+        signature: parse_signature(s_dat)?,
+        data: &data[SPF_LEGACY_HEADER_LEN..data.len() - 1]
+    })
+}
@@ -0,0 +1,202 @@
+//! # Repository synchronization
+//! Downloads the `repodata` of every repository configured in the package database, and checks its detached signature
+//! in `repodata.sig` against the signing key pinned for that repository before replacing the stored copy.
+//! Repository data that is older than the stored copy, or has expired, is refused as well, so that a mirror cannot replay old data to hide updates.
+//! Both `file://` URLs and HTTP(S) URLs are supported, so repositories can be served from a local directory or a web server.
+
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, Write};
+
+use log::{debug, warn};
+use url::Url;
+
+use crate::crypt::PublicKey;
+use crate::db::{ConfiguredRepository, Database};
+use crate::repo::{get_repodata_sig_url, get_repodata_url, Repository};
+use crate::sig::{DetachedSignature, verify_detached};
+use crate::trustcache::{is_pk_trusted, KeyTrust, Trustcache, unix_now};
+
+/// The baseurl of a repository, and the result of syncing it
+pub type SyncResult = (Url, Result<(), Box<dyn Error>>);
+
+// fetch_url
+/// Fetch the contents of a `file://`, `http://` or `https://` URL
+/// # Errors
+/// This function will error if:
+/// - the URL uses an unsupported scheme
+/// - a `file://` URL does not point to a readable local file
+/// - the HTTP request failed, or the server returned an error status
+pub fn fetch_url(url: &Url) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut data: Vec<u8> = vec![];
+    fetch_url_to(url, &mut data)?;
+    Ok(data)
+}
+
+// fetch_url_to
+/// Fetch the contents of a `file://`, `http://` or `https://` URL into `writer` as they are read, without holding them in memory,
+/// returning how much was written
+/// # Errors
+/// This function will error for the same reasons as `fetch_url`, and if writing fails
+pub fn fetch_url_to<W: Write>(url: &Url, writer: &mut W) -> Result<u64, Box<dyn Error>> {
+    debug!("fetching {}", url);
+    match url.scheme() {
+        "file" => {
+            let path = match url.to_file_path() {
+                Ok(p) => p,
+                Err(()) => return Err(format!("{url} is not a valid local path").into())
+            };
+            Ok(io::copy(&mut File::open(path)?, writer)?)
+        },
+        "http" | "https" => {
+            let response = ureq::get(url.as_str()).call()?;
+            Ok(io::copy(&mut response.into_reader(), writer)?)
+        },
+        scheme => Err(format!("unsupported URL scheme {scheme} in {url}").into())
+    }
+}
+
+// verify_pinned_key
+/// Check that a signing key is pinned for a repository, and that it is trusted, see `is_pk_trusted`, returning it.
+/// A key the pin was moved to by key rotations only has to be not blacklisted, revoked or outside its validity window, see `ConfiguredRepository::pin_rotated`.
+/// # Errors
+/// This function will error if no key is pinned, if the pinned key is not trusted, such as when it is blacklisted, revoked or expired, or if an invalid key is present in the trustcache.
+fn verify_pinned_key<'a>(configured: &'a ConfiguredRepository, trustcache: &Trustcache) -> Result<&'a PublicKey, Box<dyn Error>> {
+    let Some(pinned) = configured.pinned_key() else {
+        return Err(format!("no signing key is pinned for {}, pin one before syncing it", configured.baseurl).into());
+    };
+    let trust = is_pk_trusted(trustcache, pinned)?;
+    if !(trust.is_trusted() || (configured.pin_rotated && matches!(trust, KeyTrust::Unknown))) {
+        return Err(format!("signing key {} for {} is not trusted, it is {trust}", pinned.to_anonymous(), configured.baseurl).into());
+    }
+    Ok(pinned)
+}
+
+// verify_key_rotations
+/// Follow the key rotations announced in repository data from the pinned signing key of the repository, returning every key passed through,
+/// starting with the pinned key.
+///
+/// Rotations that do not start at the last key reached so far, such as rotations from before the key was pinned, are skipped.
+/// Every rotation followed must be signed by the key it rotates away from, and its successor must not be blacklisted, revoked or outside its validity window.
+/// # Errors
+/// This function will error if:
+/// - no key is pinned, or the pinned key is not trusted
+/// - a rotation that is followed is not signed by the key it rotates away from, or rotates to a key that is blacklisted, revoked or outside its validity window, or back to a key passed through already
+/// - an invalid key is present in the trustcache
+pub fn verify_key_rotations(configured: &ConfiguredRepository, repodata: &Repository, trustcache: &Trustcache) -> Result<Vec<PublicKey>, Box<dyn Error>> {
+    let mut chain = vec![verify_pinned_key(configured, trustcache)?.clone()];
+    for rotation in &repodata.key_rotations {
+        let current = &chain[chain.len() - 1];
+        if rotation.previous.key_data != current.key_data { continue; }
+        if let Err(e) = rotation.verify() {
+            return Err(format!("invalid key rotation for {}: {e}", configured.baseurl).into());
+        }
+        if chain.iter().any(|x| x.key_data == rotation.successor.key_data) {
+            return Err(format!("invalid key rotation for {}: {} was already rotated away from", configured.baseurl, rotation.successor.fingerprint()).into());
+        }
+        // the successor is vouched for by the previous key, so it does not have to be in the trustcache yet
+        let trust = is_pk_trusted(trustcache, &rotation.successor)?;
+        if !matches!(trust, KeyTrust::Trusted | KeyTrust::Unknown) {
+            return Err(format!("signing key {} for {} is not trusted, it is {trust}", rotation.successor.to_anonymous(), configured.baseurl).into());
+        }
+        chain.push(rotation.successor.clone());
+    }
+    Ok(chain)
+}
+
+// verify_repodata
+/// Check freshly fetched, still encoded, repository data and its detached signature against the pinned signing key of the repository.
+///
+/// Returns the decoded repository data and the keys the pin passes through, see `verify_key_rotations`.
+/// The signing key of a repository is pinned in its `ConfiguredRepository`, which must be trusted and not blacklisted. New data is only accepted if it is
+/// signed with, and carries, the pinned key or a key the pinned key has been rotated to.
+/// # Errors
+/// This function will error if:
+/// - the repository data or its signature could not be decoded
+/// - the key rotations could not be followed, see `verify_key_rotations`
+/// - the signature was not made with a key the rotations pass through, or does not match the data
+/// - the repository data carries another key than the one it is signed with
+pub fn verify_repodata(configured: &ConfiguredRepository, data: &[u8], signature: &[u8], trustcache: &Trustcache) -> Result<(Repository, Vec<PublicKey>), Box<dyn Error>> {
+    let signature = match DetachedSignature::from_bytes(signature) {
+        Ok(s) => s,
+        Err(e) => return Err(format!("invalid repodata signature for {}: {e}", configured.baseurl).into())
+    };
+    // nothing in the repository data is trusted until the signature has been checked, the rotations verify themselves
+    let repodata: Repository = match rmp_serde::from_slice(data) {
+        Ok(r) => r,
+        Err(e) => return Err(format!("invalid repodata for {}: {e}", configured.baseurl).into())
+    };
+    let chain = verify_key_rotations(configured, &repodata, trustcache)?;
+    let Some(signer) = chain.iter().find(|x| x.fingerprint() == signature.fingerprint_hex()) else {
+        return Err(format!("repodata for {} is signed by {}, which is not the pinned key {} or a successor of it, refusing to sync", configured.baseurl, signature.fingerprint_hex(), chain[0].fingerprint()).into());
+    };
+    if let Err(e) = verify_detached(signer, &signature, data) {
+        return Err(format!("repodata for {} failed verification: {e}", configured.baseurl).into());
+    }
+    if repodata.signing_key.key_data != signer.key_data {
+        return Err(format!("signing key for {} changed from {} to {}, refusing to sync", configured.baseurl, signer.to_anonymous(), repodata.signing_key.to_anonymous()).into());
+    }
+    Ok((repodata, chain))
+}
+
+// verify_freshness
+/// Check that freshly fetched repository data is not older than the stored data, and has not expired at the unix timestamp `now`, in seconds.
+/// # Errors
+/// This function will error if the serial of the repository data is lower than the serial of the stored data, or if it expired at or before `now`.
+pub fn verify_freshness(configured: &ConfiguredRepository, repodata: &Repository, now: u64) -> Result<(), Box<dyn Error>> {
+    if repodata.serial < configured.repodata.serial {
+        return Err(format!("repodata for {} has serial {}, which is older than the stored serial {}, refusing to sync", configured.baseurl, repodata.serial, configured.repodata.serial).into());
+    }
+    if let Some(expires) = repodata.expires {
+        if expires <= now {
+            return Err(format!("repodata for {} expired {} seconds ago, refusing to sync", configured.baseurl, now - expires).into());
+        }
+    }
+    Ok(())
+}
+
+// sync_repository
+/// Download and verify the repository data for a single configured repository, replacing the stored data if it is valid.
+///
+/// The repository data must be signed with the pinned key, and must carry it. A repository without a pinned key is refused, see `ConfiguredRepository::pin_stored_key`.
+/// If the repository data announces that the pinned key has been rotated, the pin of this repository is moved to the newest key. The key is not added to
+/// the trustcache, so it is only accepted for this repository, see `ConfiguredRepository::pin_rotated`.
+/// Unless `allow_stale` is set, the repository data must also not be older than the stored data, nor expired, see `verify_freshness`.
+/// # Errors
+/// This function will error if the repository data or its signature could not be downloaded, verified or decoded. The stored data is left untouched in that case.
+pub fn sync_repository(configured: &mut ConfiguredRepository, trustcache: &Trustcache, allow_stale: bool) -> Result<(), Box<dyn Error>> {
+    let url = get_repodata_url(configured.baseurl.clone())?;
+    let sig_url = get_repodata_sig_url(configured.baseurl.clone())?;
+    let data = fetch_url(&url)?;
+    let signature = fetch_url(&sig_url)?;
+    let (repodata, mut chain) = verify_repodata(configured, &data, &signature, trustcache)?;
+    if let Err(e) = verify_freshness(configured, &repodata, unix_now()) {
+        if !allow_stale {
+            return Err(e);
+        }
+        warn!("{}, syncing anyway", e);
+    }
+    let newest = chain.pop();
+    if let Some(pinned) = newest {
+        if !chain.is_empty() {
+            // the previous key vouched for its successor, so this repository accepts it without having to allow it by hand
+            debug!("moving the pin of {} to {}", configured.baseurl, pinned.fingerprint());
+            configured.pin_rotated = true;
+        }
+        configured.signing_key = Some(pinned);
+    }
+    configured.repodata = repodata;
+    Ok(())
+}
+
+// sync_repositories
+/// Sync every repository configured in the database, returning the baseurl of every repository along with the result of syncing it.
+///
+/// A repository that fails to sync keeps its previous data, and does not stop the other repositories from syncing.
+/// `allow_stale` accepts repository data that is older than the stored data or expired, see `sync_repository`.
+//
+pub fn sync_repositories(database: &mut Database, trustcache: &Trustcache, allow_stale: bool) -> Vec<SyncResult> {
+    database.repositories.iter_mut()
+        .map(|configured| (configured.baseurl.clone(), sync_repository(configured, trustcache, allow_stale)))
+        .collect()
+}
@@ -0,0 +1,304 @@
+//! # Transactions
+//! A transaction is the highest level action in Mangrove. It is a non-persistent, in-memory collection of
+//! `install`, `update`, `remove` and `reinstall` actions, which are validated together and then applied in one go.
+//! See `book-backup/internals/transactions.md` for the rules every transaction has to follow.
+
+use std::error::Error;
+
+use crate::db::Database;
+use crate::journal::Journal;
+use crate::pkg::{extract_pkg_journaled, find_conflicts, Package, pkg_satisfies, remove_pkg_journaled, StoredPackage, upgrade_pkg_journaled};
+use crate::pkgdb::PackageDb;
+
+/// The name of the package containing Mangrove itself. Mangrove must always be updated in a transaction of its own.
+pub const MANGROVE_PKGNAME: &str = "mangrove";
+
+// TransactionAction
+/// Represents a single action inside of a `Transaction`. Actions that install files carry the package file they install from.
+//
+#[derive(Debug, Clone, PartialEq)]
+pub enum TransactionAction {
+    /// Install a package that is not currently installed
+    Install(Package, StoredPackage),
+    /// Update an already installed package to a newer version
+    Update(Package, StoredPackage),
+    /// Remove a currently installed package
+    Remove(String),
+    /// Remove, then install an already installed package
+    Reinstall(Package, StoredPackage),
+}
+
+impl TransactionAction {
+    // pkgname
+    /// Get the name of the package this action operates on
+    //
+    pub fn pkgname(&self) -> &str {
+        match self {
+            Self::Install(pkg, _) | Self::Update(pkg, _) | Self::Reinstall(pkg, _) => &pkg.pkgname,
+            Self::Remove(pkgname) => pkgname
+        }
+    }
+
+    // package
+    /// Get the package this action installs, if it installs one
+    //
+    pub const fn package(&self) -> Option<&Package> {
+        match self {
+            Self::Install(pkg, _) | Self::Update(pkg, _) | Self::Reinstall(pkg, _) => Some(pkg),
+            Self::Remove(_) => None
+        }
+    }
+
+    // action_str
+    /// Get the name of this action, as used in the transaction documentation
+    //
+    pub const fn action_str(&self) -> &'static str {
+        match self {
+            Self::Install(..) => "install",
+            Self::Update(..) => "update",
+            Self::Remove(..) => "remove",
+            Self::Reinstall(..) => "reinstall"
+        }
+    }
+}
+
+// Transaction
+/// Represents a set of actions that are validated up front and applied together
+//
+#[derive(Debug, Default)]
+pub struct Transaction {
+    /// The actions contained in this transaction, in the order they were added
+    pub actions: Vec<TransactionAction>,
+    /// Remove packages even if installed packages that are not part of this transaction depend on them
+    pub force: bool,
+}
+
+impl Transaction {
+    // new
+    /// Create a new, empty transaction
+    //
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // install
+    /// Add an `install` action for the provided package file
+    /// # Errors
+    /// This function will error if the package file could not be verified or loaded.
+    pub fn install(&mut self, package: StoredPackage) -> Result<(), Box<dyn Error>> {
+        let pkg = package.load()?;
+        self.actions.push(TransactionAction::Install(pkg, package));
+        Ok(())
+    }
+
+    // update
+    /// Add an `update` action for the provided package file
+    /// # Errors
+    /// This function will error if the package file could not be verified or loaded.
+    pub fn update(&mut self, package: StoredPackage) -> Result<(), Box<dyn Error>> {
+        let pkg = package.load()?;
+        self.actions.push(TransactionAction::Update(pkg, package));
+        Ok(())
+    }
+
+    // reinstall
+    /// Add a `reinstall` action for the provided package file
+    /// # Errors
+    /// This function will error if the package file could not be verified or loaded.
+    pub fn reinstall(&mut self, package: StoredPackage) -> Result<(), Box<dyn Error>> {
+        let pkg = package.load()?;
+        self.actions.push(TransactionAction::Reinstall(pkg, package));
+        Ok(())
+    }
+
+    // remove
+    /// Add a `remove` action for the given installed package
+    //
+    pub fn remove(&mut self, pkgname: &str) {
+        self.actions.push(TransactionAction::Remove(pkgname.to_string()));
+    }
+
+    // validate
+    /// Check the entire transaction against the package database, without changing anything.
+    ///
+    /// Every problem found is reported at once, one per line. This checks that:
+    /// - no package is operated on more than once
+    /// - no held package is operated on
+    /// - Mangrove itself is only ever updated on its own
+    /// - installed packages are not installed again, and packages that are not installed are not updated, removed or reinstalled
+    /// - updates only ever move to a newer version
+    /// - every dependency of every package is satisfied once all actions have been applied. If `force` is set, dependencies that the removals
+    ///   in this transaction break are not checked, only those of the packages it installs
+    /// - no two packages conflict with each other once all actions have been applied, by name or by a name one of them provides
+    /// # Errors
+    /// This function will error with a description of every problem if the transaction is not valid.
+    pub fn validate(&self, database: &Database) -> Result<(), Box<dyn Error>> {
+        let mut problems: Vec<String> = vec![];
+        let installed = |pkgname: &str| database.installed_packages.iter().find(|x| x.pkgname == pkgname);
+
+        for (i, action) in self.actions.iter().enumerate() {
+            // All operations are mutually exclusive
+            if self.actions[..i].iter().any(|x| x.pkgname() == action.pkgname()) {
+                problems.push(format!("{} has more than one action in this transaction", action.pkgname()));
+                continue;
+            }
+            // Held packages cannot be operated on
+            if database.is_held(action.pkgname()) {
+                problems.push(format!("{} is held, unhold it before trying to {} it", action.pkgname(), action.action_str()));
+                continue;
+            }
+            match action {
+                TransactionAction::Install(pkg, _) => {
+                    if installed(&pkg.pkgname).is_some() {
+                        problems.push(format!("{} is already installed, update or reinstall it instead", pkg.pkgname));
+                    }
+                },
+                TransactionAction::Update(pkg, _) => {
+                    match installed(&pkg.pkgname) {
+                        Some(old) if pkg.pkgver <= old.pkgver => problems.push(format!("cannot update {} from {} to {}, which is not newer", pkg.pkgname, old.pkgver, pkg.pkgver)),
+                        Some(_) => (),
+                        None => problems.push(format!("cannot update {}, it is not installed", pkg.pkgname))
+                    }
+                },
+                TransactionAction::Reinstall(pkg, _) => {
+                    if installed(&pkg.pkgname).is_none() {
+                        problems.push(format!("cannot reinstall {}, it is not installed", pkg.pkgname));
+                    }
+                },
+                TransactionAction::Remove(pkgname) => {
+                    if installed(pkgname).is_none() {
+                        problems.push(format!("cannot remove {pkgname}, it is not installed"));
+                    }
+                }
+            }
+        }
+
+        // Mangrove must update itself in a separate transaction
+        if self.actions.len() > 1 && self.actions.iter().any(|x| x.pkgname() == MANGROVE_PKGNAME && x.package().is_some()) {
+            problems.push(format!("{MANGROVE_PKGNAME} must be updated in a transaction of its own"));
+        }
+
+        // Determine what the installed packages will look like after this transaction
+        let new_packages: Vec<&Package> = self.actions.iter().filter_map(TransactionAction::package).collect();
+        let mut result: Vec<&Package> = database.installed_packages.iter()
+            .filter(|x| !self.actions.iter().any(|a| a.pkgname() == x.pkgname))
+            .collect();
+        result.extend(new_packages.iter());
+
+        // Dependency checking
+        for pkg in &result {
+            let is_new = new_packages.iter().any(|x| x.pkgname == pkg.pkgname);
+            if let Some(dependencies) = &pkg.depends {
+                for dependency in dependencies {
+                    if result.iter().any(|x| x.pkgname != pkg.pkgname && pkg_satisfies(x, dependency)) { continue; }
+                    if is_new {
+                        problems.push(format!("{} requires {}, which is not installed and not part of this transaction", pkg.pkgname, dependency));
+                    } else if !self.force && database.installed_packages.iter().any(|x| x.pkgname != pkg.pkgname && pkg_satisfies(x, dependency)) {
+                        // only complain about dependencies this transaction breaks, not ones that were already broken
+                        problems.push(format!("{} requires {}, which would no longer be installed", pkg.pkgname, dependency));
+                    }
+                }
+            }
+        }
+
+        // Conflict checking
+        for conflict in find_conflicts(&new_packages, &result) {
+            problems.push(conflict.to_string());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(problems.join("\n").into())
+        }
+    }
+
+    // apply
+    /// Validate, then apply this transaction to the target directory and package database.
+    /// All removals happen first, followed by all installations and upgrades, with dependencies installed before the packages that need them.
+    ///
+    /// Every change to the target directory is recorded in a `Journal`. If any action fails, the journal is rolled back and the
+    /// package database is restored, leaving both exactly as they were before the transaction started.
+    /// # Errors
+    /// This function will error if the transaction fails validation, or if any of the packages could not be removed or extracted.
+    /// If the rollback after a failure is incomplete, the rollback failures are included in the error.
+    pub fn apply(&self, target: String, db: &mut PackageDb) -> Result<(), Box<dyn Error>> {
+        self.validate(&db.db)?;
+
+        let mut journal = Journal::new(&target);
+        let installed_packages = db.db.installed_packages.clone();
+        match self.apply_journaled(&target, db, &mut journal) {
+            Ok(()) => journal.commit(),
+            Err(e) => {
+                db.db.installed_packages = installed_packages;
+                if let Err(rollback_err) = journal.rollback() {
+                    return Err(format!("{e}\n{rollback_err}").into());
+                }
+                Err(e)
+            }
+        }
+    }
+
+    // apply_journaled
+    /// Apply the actions of this transaction, recording every change in the provided `Journal`
+    //
+    fn apply_journaled(&self, target: &str, db: &mut PackageDb, journal: &mut Journal) -> Result<(), Box<dyn Error>> {
+        // Removals: removed packages, and the old versions of reinstalled packages
+        for action in &self.actions {
+            match action {
+                TransactionAction::Install(..) | TransactionAction::Update(..) => (),
+                TransactionAction::Reinstall(pkg, _) => { remove_pkg_journaled(&pkg.pkgname, target, db, true, journal)?; },
+                TransactionAction::Remove(pkgname) => { remove_pkg_journaled(pkgname, target, db, true, journal)?; }
+            }
+        }
+
+        // Installations and upgrades, dependencies first
+        for action in self.install_order() {
+            match action {
+                TransactionAction::Install(pkg, package) | TransactionAction::Reinstall(pkg, package) => {
+                    let extracted = extract_pkg_journaled(package, target, journal)?;
+                    check_extracted(pkg, &extracted)?;
+                    db.db.installed_packages.push(extracted);
+                },
+                TransactionAction::Update(pkg, package) => {
+                    upgrade_pkg_journaled(package, target, db, journal)?;
+                    // the upgrade swapped in the pkginfo it extracted
+                    if let Some(extracted) = db.db.installed_packages.iter().find(|x| x.pkgname == pkg.pkgname) {
+                        check_extracted(pkg, extracted)?;
+                    }
+                },
+                TransactionAction::Remove(_) => ()
+            }
+        }
+
+        Ok(())
+    }
+
+    // install_order
+    /// Get the actions that install packages, ordered so that every package comes after the packages in this transaction it depends on
+    //
+    pub fn install_order(&self) -> Vec<&TransactionAction> {
+        let mut pending: Vec<&TransactionAction> = self.actions.iter().filter(|x| x.package().is_some()).collect();
+        let mut queue: Vec<&TransactionAction> = vec![];
+        while !pending.is_empty() {
+            let next = pending.iter().position(|candidate| {
+                let deps = candidate.package().and_then(|p| p.depends.as_ref());
+                !pending.iter().any(|other| other.pkgname() != candidate.pkgname() && other.package().is_some_and(|o| deps.is_some_and(|d| d.iter().any(|x| pkg_satisfies(o, x)))))
+            }).unwrap_or(0); // dependency cycle, any order will do
+            queue.push(pending.remove(next));
+        }
+        queue
+    }
+}
+
+// check_extracted
+/// Check that the pkginfo of a package that was extracted is the one the transaction was validated with,
+/// so that the package database records what is actually installed
+/// # Errors
+/// This function will error if the pkginfos differ in any way
+fn check_extracted(validated: &Package, extracted: &Package) -> Result<(), Box<dyn Error>> {
+    if extracted != validated {
+        return Err(format!("{} {} does not match the package the transaction was validated with", extracted.pkgname, extracted.pkgver).into());
+    }
+    Ok(())
+}
@@ -4,17 +4,32 @@
 //! known public and private keys, and is used to prevent the need for specifying keys for every action.
 //! This module contains the core structures and functions for operating with the trustcache.
 
+use std::convert::TryFrom;
 use std::error::Error;
+use std::fmt::{Display, Formatter};
 use std::fs;
 use std::path::Path;
+use std::time::SystemTime;
 
+use arrayref::array_ref;
+use ed25519_dalek::{PublicKey as VerifyingKey, Signature, Signer, Verifier};
 use lockfile::Lockfile;
 
 use crate::config::get_trustcache_file;
 use crate::crypt::{PrivateKey, PublicKey};
-use crate::db::KeyDb;
+use crate::db::{KeyDb, KeyMetadata, KeyValidity};
+use crate::keystore::write_private;
 use crate::lock::lock_trustcache;
 
+/// The magic bytes at the start of every revocation certificate
+pub const REVOCATION_MAGIC: &[u8; 4] = b"MGVR";
+/// The current revocation certificate format version
+pub const REVOCATION_VERSION: u8 = 0x01;
+/// The sentinel byte at the end of every revocation certificate
+pub const REVOCATION_END_SENTINEL: u8 = 0x42;
+/// The length of a revocation certificate without its reason
+pub const REVOCATION_MIN_LEN: usize = 4 + 1 + 32 + 8 + 2 + 64 + 1;
+
 // Trustcache
 /// Provides a mutual lock on the trustcache and also access to the `KeyDb`.
 #[derive(Debug)]
@@ -48,7 +63,10 @@ pub fn trustcache_load(use_local_trustcache: bool) -> Result<Trustcache, Box<dyn
             known_pubkeys: vec![],
             known_privkeys: vec![],
             deny_pubkeys: vec![],
-            deny_privkeys: vec![]
+            deny_privkeys: vec![],
+            revocations: vec![],
+            validity: vec![],
+            metadata: vec![]
         };
         fs::write(get_trustcache_file(use_local_trustcache), toml::to_vec(&data)?)?;
     }
@@ -64,6 +82,8 @@ pub fn trustcache_load(use_local_trustcache: bool) -> Result<Trustcache, Box<dyn
 
 // trustcache_save
 /// Save the trustcache from a Trustcache object. Requires there to be a lock on the trustcache. Will release that lock.
+///
+/// A trustcache that still holds private keys is made readable by its owner only, see `keystore::migrate_privkeys` to move them out of it.
 /// # Errors
 /// As with `trustcache_load`, this function can return errors for any number of reasons:
 /// - failed to serialize the trustcache
@@ -73,7 +93,11 @@ pub fn trustcache_load(use_local_trustcache: bool) -> Result<Trustcache, Box<dyn
 pub fn trustcache_save(trustcache: Trustcache, use_local_trustcache: bool) -> Result<(), Box<dyn Error>> {
     // save the trustcache
     let str = toml::to_string_pretty(&trustcache.keydb)?;
-    fs::write(get_trustcache_file(use_local_trustcache), str)?;
+    if trustcache.keydb.known_privkeys.is_empty() && trustcache.keydb.deny_privkeys.is_empty() {
+        fs::write(get_trustcache_file(use_local_trustcache), str)?;
+    } else {
+        write_private(&get_trustcache_file(use_local_trustcache), str.as_bytes())?;
+    }
     trustcache.lockfile.release()?;
     Ok(())
 }
@@ -130,11 +154,318 @@ pub fn is_sk_blacklisted(trustcache: &Trustcache, key: &PrivateKey) -> Result<bo
     is_pk_blacklisted(trustcache, &key.derive())
 }
 
-// is_pk_trusted
-/// Determines if the provided public key is in the trustcache.
+// unix_now
+/// The current unix timestamp, in seconds
+//
+pub(crate) fn unix_now() -> u64 {
+    SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).map_or(0, |x| x.as_secs())
+}
+
+// KeyTrust
+/// Whether a key is trusted by the trustcache, and if not, why not
+//
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyTrust {
+    /// The key is in the trustcache and may be used
+    Trusted,
+    /// The key is not in the trustcache
+    Unknown,
+    /// The key, or the private key associated with it, has been blacklisted
+    Blacklisted,
+    /// The key owner has revoked the key with a revocation certificate
+    Revoked {
+        /// The unix timestamp the key was revoked at, according to its owner
+        revoked_at: u64,
+        /// The reason the owner gave for revoking the key
+        reason: String
+    },
+    /// The validity window of the key has not started yet, it starts at the unix timestamp provided
+    NotYetValid(u64),
+    /// The validity window of the key ended at the unix timestamp provided
+    Expired(u64)
+}
+
+impl KeyTrust {
+    // is_trusted
+    /// Whether the key may be used
+    //
+    pub fn is_trusted(&self) -> bool {
+        *self == Self::Trusted
+    }
+}
+
+impl Display for KeyTrust {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Trusted => write!(f, "trusted"),
+            Self::Unknown => write!(f, "not in the trustcache"),
+            Self::Blacklisted => write!(f, "blacklisted"),
+            Self::Revoked { revoked_at, reason } if reason.is_empty() => write!(f, "revoked since {revoked_at}"),
+            Self::Revoked { revoked_at, reason } => write!(f, "revoked since {revoked_at} ({reason})"),
+            Self::NotYetValid(not_before) => write!(f, "not valid until {not_before}"),
+            Self::Expired(not_after) => write!(f, "expired since {not_after}")
+        }
+    }
+}
+
+// RevocationCertificate
+/// A statement by the owner of a key that it must no longer be trusted, signed by the key itself.
+///
+/// Anyone holding the certificate can import it into their trustcache, so a key owner can revoke a compromised key
+/// everywhere without every administrator having to blacklist it by hand.
+/// In binary form, a certificate is laid out as:
+/// `MGVR | version (1) | public key (32) | revoked_at (8, big endian) | reason length (2, big endian) | reason | signature (64) | 0x42`,
+/// where the signature covers `mangrove key revocation` followed by every field up to the reason.
+//
+#[derive(Debug, Clone)]
+pub struct RevocationCertificate {
+    /// The key being revoked
+    pub key: PublicKey,
+    /// The unix timestamp, in seconds, the key is revoked from
+    pub revoked_at: u64,
+    /// Why the key was revoked, such as `compromised` or `superseded`
+    pub reason: String,
+    /// The signature of the certificate by the key being revoked
+    pub signature: Signature
+}
+
+impl RevocationCertificate {
+    // message
+    /// The message the revoked key signs
+    //
+    fn message(key: &PublicKey, revoked_at: u64, reason: &str) -> Vec<u8> {
+        let mut message = b"mangrove key revocation".to_vec();
+        message.extend_from_slice(key.key_data.as_bytes());
+        message.extend_from_slice(&revoked_at.to_be_bytes());
+        message.extend_from_slice(reason.as_bytes());
+        message
+    }
+
+    // new
+    /// Revoke the provided key from `revoked_at` on, signing the certificate with the key itself.
+    /// ```
+    /// use libmangrove::crypt::PrivateKey;
+    /// use libmangrove::trustcache::RevocationCertificate;
+    /// let key = PrivateKey::generate(String::from("compromised"));
+    /// let certificate = RevocationCertificate::new(&key, 1_700_000_000, "compromised").unwrap();
+    /// assert!(RevocationCertificate::from_bytes(&certificate.to_bytes()).is_ok());
+    /// ```
+    /// # Errors
+    /// This function will error if the reason is longer than 65535 bytes.
+    pub fn new(key: &PrivateKey, revoked_at: u64, reason: &str) -> Result<Self, Box<dyn Error>> {
+        if u16::try_from(reason.len()).is_err() {
+            return Err(format!("The revocation reason is {} bytes long, but may be at most {} bytes long", reason.len(), u16::MAX).into());
+        }
+        let public = key.derive();
+        let signature = key.key_data.sign(&Self::message(&public, revoked_at, reason));
+        Ok(Self { key: public, revoked_at, reason: reason.to_string(), signature })
+    }
+
+    // to_bytes
+    /// Serialize the certificate into its binary form
+    //
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut data = Vec::with_capacity(REVOCATION_MIN_LEN + self.reason.len());
+        data.extend_from_slice(REVOCATION_MAGIC);
+        data.push(REVOCATION_VERSION);
+        data.extend_from_slice(self.key.key_data.as_bytes());
+        data.extend_from_slice(&self.revoked_at.to_be_bytes());
+        // the length is checked when the certificate is created or parsed
+        data.extend_from_slice(&u16::try_from(self.reason.len()).unwrap_or(u16::MAX).to_be_bytes());
+        data.extend_from_slice(self.reason.as_bytes());
+        data.extend_from_slice(&self.signature.to_bytes());
+        data.push(REVOCATION_END_SENTINEL);
+        data
+    }
+
+    // from_bytes
+    /// Parse a certificate from its binary form, checking that it is signed by the key it revokes
+    /// # Errors
+    /// This function will error if the data is not a revocation certificate, is of an unknown version, is truncated or corrupt,
+    /// or if the certificate is not signed by the key it revokes.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, Box<dyn Error>> {
+        if data.len() < 5 || &data[0..4] != REVOCATION_MAGIC {
+            return Err("The data is not a revocation certificate".into());
+        }
+        if data[4] != REVOCATION_VERSION {
+            return Err(format!("Unknown revocation certificate version {}", data[4]).into());
+        }
+        if data.len() < REVOCATION_MIN_LEN {
+            return Err(format!("The revocation certificate is truncated ({} bytes, expected at least {REVOCATION_MIN_LEN})", data.len()).into());
+        }
+        let key = PublicKey {
+            name: String::from("__anonymous__"),
+            key_data: VerifyingKey::from_bytes(&data[5..37])?
+        };
+        let revoked_at = u64::from_be_bytes(*array_ref!(data, 37, 8));
+        let reason_len = usize::from(u16::from_be_bytes(*array_ref!(data, 45, 2)));
+        if data.len() != REVOCATION_MIN_LEN + reason_len {
+            return Err(format!("The revocation certificate has the wrong length ({} bytes, expected {})", data.len(), REVOCATION_MIN_LEN + reason_len).into());
+        }
+        let reason = String::from_utf8(data[47..47 + reason_len].to_vec())?;
+        let signature = Signature::from_bytes(&data[47 + reason_len..111 + reason_len])?;
+        if data[data.len() - 1] != REVOCATION_END_SENTINEL {
+            return Err(format!("The revocation certificate is corrupt (expected end sentinel {REVOCATION_END_SENTINEL:#04x}, found {:#04x})", data[data.len() - 1]).into());
+        }
+        if let Err(e) = key.key_data.verify(&Self::message(&key, revoked_at, &reason), &signature) {
+            return Err(format!("The revocation certificate for {} is not signed by the key it revokes: {e}", key.fingerprint()).into());
+        }
+        Ok(Self { key, revoked_at, reason, signature })
+    }
+}
+
+// key_revocation
+/// Find the revocation certificate for the provided public key in the trustcache, if it has been revoked.
+/// # Errors
+/// An error will occur if an invalid revocation certificate is present in the trustcache.
+pub fn key_revocation(trustcache: &Trustcache, key: &PublicKey) -> Result<Option<RevocationCertificate>, Box<dyn Error>> {
+    for revocation in &trustcache.keydb.revocations {
+        let certificate = RevocationCertificate::from_bytes(&base64::decode(revocation)?)?;
+        if certificate.key.key_data == key.key_data {
+            return Ok(Some(certificate));
+        }
+    }
+    Ok(None)
+}
+
+// import_revocation
+/// Import a revocation certificate in binary form into the trustcache, returning the key it revokes.
+///
+/// The revoked key will no longer be trusted, whether it is in the allowlist or not. Importing a certificate for a key
+/// that has already been revoked keeps whichever certificate revokes it earliest.
+/// Stored certificates that cannot be decoded are kept as they are, so they do not stop other keys from being revoked.
+/// # Errors
+/// This function will error if the certificate is invalid, see `RevocationCertificate::from_bytes`.
+pub fn import_revocation(trustcache: &mut Trustcache, data: &[u8]) -> Result<PublicKey, Box<dyn Error>> {
+    let certificate = RevocationCertificate::from_bytes(data)?;
+    let mut revocations = vec![];
+    let mut earliest: Option<u64> = None;
+    for revocation in &trustcache.keydb.revocations {
+        let stored = base64::decode(revocation).ok().and_then(|x| RevocationCertificate::from_bytes(&x).ok());
+        match stored {
+            Some(stored) if stored.key.key_data == certificate.key.key_data => {
+                earliest = Some(earliest.map_or(stored.revoked_at, |x| x.min(stored.revoked_at)));
+            },
+            _ => revocations.push(revocation.clone())
+        }
+    }
+    if earliest.is_none_or(|x| x > certificate.revoked_at) {
+        revocations.push(base64::encode(data));
+        trustcache.keydb.revocations = revocations;
+    }
+    Ok(certificate.key)
+}
+
+// key_validity
+/// Get the validity window of the provided public key, if it has one.
+//
+pub fn key_validity<'a>(trustcache: &'a Trustcache, key: &PublicKey) -> Option<&'a KeyValidity> {
+    let anonymous = key.to_anonymous();
+    trustcache.keydb.validity.iter().find(|x| x.key == anonymous)
+}
+
+// set_pk_validity
+/// Set the validity window of a public key, replacing any window it already has. Setting neither bound removes the window.
+/// # Errors
+/// This function will error if the window ends before it starts.
+pub fn set_pk_validity(trustcache: &mut Trustcache, key: &PublicKey, not_before: Option<u64>, not_after: Option<u64>) -> Result<(), Box<dyn Error>> {
+    if let (Some(start), Some(end)) = (not_before, not_after) {
+        if end <= start {
+            return Err(format!("The validity window of {} ends at {end}, before it starts at {start}", key.fingerprint()).into());
+        }
+    }
+    let anonymous = key.to_anonymous();
+    trustcache.keydb.validity.retain(|x| x.key != anonymous);
+    if not_before.is_some() || not_after.is_some() {
+        trustcache.keydb.validity.push(KeyValidity { key: anonymous, not_before, not_after });
+    }
+    Ok(())
+}
+
+// key_metadata
+/// Get the details recorded for the provided public key, if it has any.
+//
+pub fn key_metadata<'a>(trustcache: &'a Trustcache, key: &PublicKey) -> Option<&'a KeyMetadata> {
+    let anonymous = key.to_anonymous();
+    trustcache.keydb.metadata.iter().find(|x| x.key == anonymous)
+}
+
+// set_key_metadata
+/// Set the name and comment recorded for a public key, leaving out either to keep what is recorded already.
+///
+/// If the key has no details recorded yet, they are created without a date the key was added at.
+//
+pub fn set_key_metadata(trustcache: &mut Trustcache, key: &PublicKey, name: Option<&str>, comment: Option<&str>) {
+    let metadata = metadata_entry(trustcache, key);
+    if let Some(name) = name { metadata.name = name.to_string(); }
+    if let Some(comment) = comment { metadata.comment = comment.to_string(); }
+}
+
+// metadata_entry
+/// Get the details recorded for a public key, creating them if there are none yet
+//
+fn metadata_entry<'a>(trustcache: &'a mut Trustcache, key: &PublicKey) -> &'a mut KeyMetadata {
+    let anonymous = key.to_anonymous();
+    let index = if let Some(i) = trustcache.keydb.metadata.iter().position(|x| x.key == anonymous) { i } else {
+        trustcache.keydb.metadata.push(KeyMetadata {
+            key: anonymous,
+            fingerprint: key.fingerprint(),
+            name: String::new(),
+            comment: String::new(),
+            added: None
+        });
+        trustcache.keydb.metadata.len() - 1
+    };
+    &mut trustcache.keydb.metadata[index]
+}
+
+// record_key
+/// Record a key that is being added to the trustcache as added now, naming it after the key if it has a name and is not named yet.
+//
+fn record_key(trustcache: &mut Trustcache, key: &PublicKey) {
+    let metadata = metadata_entry(trustcache, key);
+    if metadata.added.is_none() { metadata.added = Some(unix_now()); }
+    if metadata.name.is_empty() && key.name != "__anonymous__" { metadata.name.clone_from(&key.name); }
+}
+
+// forget_key
+/// Remove the details recorded for a key once it is no longer in the trustcache in any form.
+//
+fn forget_key(trustcache: &mut Trustcache, key: &PublicKey) -> Result<(), Box<dyn Error>> {
+    if is_pk_listed(trustcache, key)? || is_pk_blacklisted(trustcache, key)? { return Ok(()); }
+    let anonymous = key.to_anonymous();
+    trustcache.keydb.metadata.retain(|x| x.key != anonymous);
+    Ok(())
+}
+
+// named_key
+/// Give a public key the name recorded for it in the trustcache, if there is one.
+//
+pub fn named_key(trustcache: &Trustcache, mut key: PublicKey) -> PublicKey {
+    if let Some(metadata) = key_metadata(trustcache, &key) {
+        if !metadata.name.is_empty() {
+            key.name = metadata.name.clone();
+        }
+    }
+    key
+}
+
+// known_keys
+/// Get every public key in the allowlist of the trustcache, including the public keys of the private keys in it, with their recorded names.
+///
+/// Keys that cannot be loaded are skipped, and the keys are not checked against the blacklist, revocations or validity windows, see `is_pk_trusted`.
+//
+pub fn known_keys(trustcache: &Trustcache) -> Vec<PublicKey> {
+    let pubkeys = trustcache.keydb.known_pubkeys.iter().filter_map(|k| PublicKey::from_anonymous(k).ok());
+    let privkeys = trustcache.keydb.known_privkeys.iter().filter_map(|k| PrivateKey::from_anonymous(k).ok()).map(|k| k.derive());
+    pubkeys.chain(privkeys).map(|k| named_key(trustcache, k)).collect()
+}
+
+// is_pk_listed
+/// Determines if the provided public key is in the allowlist, and not blacklisted, ignoring revocations and validity windows.
 /// # Errors
 /// An error will occur if an invalid key is present in the trustcache.
-pub fn is_pk_trusted(trustcache: &Trustcache, key: &PublicKey) -> Result<bool, Box<dyn Error>> {
+fn is_pk_listed(trustcache: &Trustcache, key: &PublicKey) -> Result<bool, Box<dyn Error>> {
     if is_pk_blacklisted(trustcache, key)? { return Ok(false); }
     for pk in &trustcache.keydb.known_pubkeys {
         if pk == &key.to_anonymous() {
@@ -144,22 +475,95 @@ pub fn is_pk_trusted(trustcache: &Trustcache, key: &PublicKey) -> Result<bool, B
     has_assoc_sk(trustcache, key)
 }
 
-// is_sk_trusted
-/// Determines if the provided private key is in the trustcache.
-pub fn is_sk_trusted(trustcache: &Trustcache, key: &PrivateKey) -> Result<bool, Box<dyn Error>> {
+// is_sk_listed
+/// Determines if the provided private key is in the allowlist, and not blacklisted, ignoring revocations and validity windows.
+/// # Errors
+/// An error will occur if an invalid key is present in the trustcache.
+fn is_sk_listed(trustcache: &Trustcache, key: &PrivateKey) -> Result<bool, Box<dyn Error>> {
     if is_sk_blacklisted(trustcache, key)? { return Ok(false); }
     for sk in &trustcache.keydb.known_privkeys {
         if sk == &key.to_anonymous() {
             return Ok(true)
         }
     }
-    is_pk_trusted(trustcache, &key.derive())
+    is_pk_listed(trustcache, &key.derive())
+}
+
+// check_pk
+/// Apply revocations and validity windows on top of whether a public key is listed, see `is_pk_trusted_at`
+//
+fn check_pk(trustcache: &Trustcache, key: &PublicKey, listed: bool, now: u64) -> Result<KeyTrust, Box<dyn Error>> {
+    if let Some(certificate) = key_revocation(trustcache, key)? {
+        if now >= certificate.revoked_at {
+            return Ok(KeyTrust::Revoked { revoked_at: certificate.revoked_at, reason: certificate.reason });
+        }
+    }
+    if !listed {
+        return Ok(if is_pk_blacklisted(trustcache, key)? { KeyTrust::Blacklisted } else { KeyTrust::Unknown });
+    }
+    if let Some(validity) = key_validity(trustcache, key) {
+        if let Some(not_before) = validity.not_before {
+            if now < not_before { return Ok(KeyTrust::NotYetValid(not_before)); }
+        }
+        if let Some(not_after) = validity.not_after {
+            if now >= not_after { return Ok(KeyTrust::Expired(not_after)); }
+        }
+    }
+    Ok(KeyTrust::Trusted)
+}
+
+// is_pk_trusted
+/// Determines if the provided public key is trusted by the trustcache right now, and if not, why not. See `is_pk_trusted_at`.
+/// # Errors
+/// An error will occur if an invalid key or revocation certificate is present in the trustcache.
+pub fn is_pk_trusted(trustcache: &Trustcache, key: &PublicKey) -> Result<KeyTrust, Box<dyn Error>> {
+    is_pk_trusted_at(trustcache, key, unix_now())
+}
+
+// is_pk_trusted_at
+/// Determines if the provided public key is trusted by the trustcache at the provided unix timestamp, and if not, why not.
+///
+/// A key is trusted if it is in the allowlist, or its private key is, it is not blacklisted, it has not been revoked by
+/// `now`, and `now` is within its validity window, if it has one.
+/// # Errors
+/// An error will occur if an invalid key or revocation certificate is present in the trustcache.
+pub fn is_pk_trusted_at(trustcache: &Trustcache, key: &PublicKey, now: u64) -> Result<KeyTrust, Box<dyn Error>> {
+    let listed = is_pk_listed(trustcache, key)?;
+    check_pk(trustcache, key, listed, now)
+}
+
+// is_sk_trusted
+/// Determines if the provided private key is trusted by the trustcache right now, and if not, why not. See `is_sk_trusted_at`.
+/// # Errors
+/// An error will occur if an invalid key or revocation certificate is present in the trustcache.
+pub fn is_sk_trusted(trustcache: &Trustcache, key: &PrivateKey) -> Result<KeyTrust, Box<dyn Error>> {
+    is_sk_trusted_at(trustcache, key, unix_now())
+}
+
+// is_sk_trusted_at
+/// Determines if the provided private key is trusted by the trustcache at the provided unix timestamp, and if not, why not.
+///
+/// Revocations and validity windows of the associated public key apply to the private key as well.
+/// # Errors
+/// An error will occur if an invalid key or revocation certificate is present in the trustcache.
+pub fn is_sk_trusted_at(trustcache: &Trustcache, key: &PrivateKey, now: u64) -> Result<KeyTrust, Box<dyn Error>> {
+    let listed = is_sk_listed(trustcache, key)?;
+    let trust = check_pk(trustcache, &key.derive(), listed, now)?;
+    if trust == KeyTrust::Unknown && is_sk_blacklisted(trustcache, key)? {
+        return Ok(KeyTrust::Blacklisted);
+    }
+    Ok(trust)
 }
 
 // allow_sk
 /// Add a secret key to the allowlist, removing it from the blacklist if it's blacklisted.
+///
+/// The key is recorded as added now if it is new to the trustcache. Keys that have been revoked with a revocation certificate cannot be added.
 pub fn allow_sk(trustcache: &mut Trustcache, key: &PrivateKey) -> Result<(), Box<dyn Error>> {
-    if is_sk_trusted(trustcache, key)? { return Ok(()); } // done! already trusted
+    if key_revocation(trustcache, &key.derive())?.is_some() {
+        return Err("Key has been revoked".into());
+    }
+    if is_sk_listed(trustcache, key)? { return Ok(()); } // done! already trusted
     if is_sk_blacklisted(trustcache, key)? {
         // remove from the blacklist
         let index = match trustcache.keydb.deny_privkeys.iter().position(|r| r == &key.to_anonymous()) {
@@ -168,6 +572,7 @@ pub fn allow_sk(trustcache: &mut Trustcache, key: &PrivateKey) -> Result<(), Box
         };
         trustcache.keydb.deny_privkeys.remove(index);
     }
+    record_key(trustcache, &key.derive());
     trustcache.keydb.known_privkeys.push(key.to_anonymous());
     Ok(())
 }
@@ -175,7 +580,7 @@ pub fn allow_sk(trustcache: &mut Trustcache, key: &PrivateKey) -> Result<(), Box
 /// Add a secret key to the blocklist, reoving it from the allowlist if it's allowlisted.
 pub fn deny_sk(trustcache: &mut Trustcache, key: &PrivateKey) -> Result<(), Box<dyn Error>> {
     if is_sk_blacklisted(trustcache, key)? { return Ok(()); } // done! already trusted
-    if is_sk_trusted(trustcache, key)? {
+    if is_sk_listed(trustcache, key)? {
         // remove from the allowlist
         let index = match trustcache.keydb.known_privkeys.iter().position(|r| r == &key.to_anonymous()) {
             Some(i) => i,
@@ -183,11 +588,13 @@ pub fn deny_sk(trustcache: &mut Trustcache, key: &PrivateKey) -> Result<(), Box<
         };
         trustcache.keydb.known_privkeys.remove(index);
     }
+    record_key(trustcache, &key.derive());
     trustcache.keydb.deny_privkeys.push(key.to_anonymous());
     Ok(())
 }
 // clear_sk
 /// Remove a secret key from the entirety of the trustcache, removing it from the allowlist and blacklist if either are present.
+/// The details recorded for the key are removed once its public key is no longer in the trustcache either.
 pub fn clear_sk(trustcache: &mut Trustcache, key: &PrivateKey) -> Result<(), Box<dyn Error>> {
     if is_sk_blacklisted(trustcache, key)? {
         // remove from the blacklist
@@ -197,7 +604,7 @@ pub fn clear_sk(trustcache: &mut Trustcache, key: &PrivateKey) -> Result<(), Box
         };
         trustcache.keydb.deny_privkeys.remove(index);
     }
-    if is_sk_trusted(trustcache, key)? {
+    if is_sk_listed(trustcache, key)? {
         // remove from the allowlist
         let index = match trustcache.keydb.known_privkeys.iter().position(|r| r == &key.to_anonymous()) {
             Some(i) => i,
@@ -205,13 +612,18 @@ pub fn clear_sk(trustcache: &mut Trustcache, key: &PrivateKey) -> Result<(), Box
         };
         trustcache.keydb.known_privkeys.remove(index);
     }
-    Ok(())
+    forget_key(trustcache, &key.derive())
 }
 
 // allow_pk
 /// Add a public key to the allowlist, removing it from the blacklist if it's blacklisted.
+///
+/// The key is recorded as added now if it is new to the trustcache. Keys that have been revoked with a revocation certificate cannot be added.
 pub fn allow_pk(trustcache: &mut Trustcache, key: &PublicKey) -> Result<(), Box<dyn Error>> {
-    if is_pk_trusted(trustcache, key)? { return Ok(()); } // done! already trusted
+    if key_revocation(trustcache, key)?.is_some() {
+        return Err("Key has been revoked".into());
+    }
+    if is_pk_listed(trustcache, key)? { return Ok(()); } // done! already trusted
     if is_pk_blacklisted(trustcache, key)? {
         // remove from the blacklist
         if assoc_sk_blacklisted(trustcache, key)? {
@@ -223,6 +635,7 @@ pub fn allow_pk(trustcache: &mut Trustcache, key: &PublicKey) -> Result<(), Box<
         };
         trustcache.keydb.deny_pubkeys.remove(index);
     }
+    record_key(trustcache, key);
     trustcache.keydb.known_pubkeys.push(key.to_anonymous());
     Ok(())
 }
@@ -230,7 +643,7 @@ pub fn allow_pk(trustcache: &mut Trustcache, key: &PublicKey) -> Result<(), Box<
 /// Add a public key to the blocklist, reoving it from the allowlist if it's allowlisted.
 pub fn deny_pk(trustcache: &mut Trustcache, key: &PublicKey) -> Result<(), Box<dyn Error>> {
     if is_pk_blacklisted(trustcache, key)? { return Ok(()); } // done! already trusted
-    if is_pk_trusted(trustcache, key)? {
+    if is_pk_listed(trustcache, key)? {
         // remove from the allowlist
         if has_assoc_sk(trustcache, key)? {
             return Err("Key is trusted by association".into());
@@ -241,11 +654,12 @@ pub fn deny_pk(trustcache: &mut Trustcache, key: &PublicKey) -> Result<(), Box<d
         };
         trustcache.keydb.known_pubkeys.remove(index);
     }
+    record_key(trustcache, key);
     trustcache.keydb.deny_pubkeys.push(key.to_anonymous());
     Ok(())
 }
 // clear_pk
-/// Remove a public key from the entirety of the trustcache, removing it from the allowlist and blacklist if either are present.
+/// Remove a public key from the entirety of the trustcache, removing it from the allowlist and blacklist if either are present, along with the details recorded for it.
 pub fn clear_pk(trustcache: &mut Trustcache, key: &PublicKey) -> Result<(), Box<dyn Error>> {
     if is_pk_blacklisted(trustcache, key)? {
         // remove from the blacklist
@@ -258,7 +672,7 @@ pub fn clear_pk(trustcache: &mut Trustcache, key: &PublicKey) -> Result<(), Box<
         };
         trustcache.keydb.deny_pubkeys.remove(index);
     }
-    if is_pk_trusted(trustcache, key)? {
+    if is_pk_listed(trustcache, key)? {
         // remove from the allowlist
         if has_assoc_sk(trustcache, key)? {
             return Err("Key is trusted by association".into());
@@ -269,5 +683,5 @@ pub fn clear_pk(trustcache: &mut Trustcache, key: &PublicKey) -> Result<(), Box<
         };
         trustcache.keydb.known_pubkeys.remove(index);
     }
-    Ok(())
+    forget_key(trustcache, key)
 }
\ No newline at end of file
@@ -0,0 +1,156 @@
+use std::error::Error;
+use std::io::{stdout, Write};
+use std::path::PathBuf;
+
+use clap::{ArgAction, Parser, Subcommand};
+use colored::Colorize;
+use tabwriter::TabWriter;
+
+use libmangrove::agent::{Agent, agent_bind, AgentClient};
+use libmangrove::config::get_agent_socket;
+use libmangrove::keystore::keystore_load;
+
+use crate::{err, ExecutableCommand};
+use crate::util::{get_passphrase, info};
+
+#[derive(Parser)]
+#[clap(name = "agent", about = "Run the signing agent, which holds decrypted keys from the key store and signs with them for sign and repogen", version, author)]
+pub struct AgentCommand {
+    #[clap(subcommand)]
+    pub command: AgentCommandOptions,
+}
+
+#[derive(Subcommand)]
+pub enum AgentCommandOptions {
+    #[clap(name = "start")]
+    Start(AgentCommandStart),
+    #[clap(name = "list")]
+    List(AgentCommandList),
+    #[clap(name = "stop")]
+    Stop(AgentCommandStop)
+}
+
+#[derive(Parser)]
+#[clap(about = "Decrypt keys from the key store and sign with them on request until stopped. The agent runs in the foreground")]
+pub struct AgentCommandStart {
+    #[clap(short = 'k', long = "key", value_parser, help = "The name, fingerprint prefix or public key prefix of a key in the key store to hold. May be given more than once. Defaults to every key in the key store")]
+    pub keys: Vec<String>,
+    #[clap(long = "passphrase-file", value_parser, help = "Read the passphrase of the keys from this file, instead of MANGROVE_PASSPHRASE or asking for it")]
+    pub passphrase_file: Option<PathBuf>,
+    #[clap(short = 's', long = "socket", value_parser, help = "The socket to listen on, instead of MANGROVE_AGENT_SOCK or the default one")]
+    pub socket: Option<String>,
+    #[clap(short = 'l', long = "local", action = ArgAction::SetTrue, default_value_t = false, help = "Use a local key store and agent socket instead of the default system-wide ones")]
+    pub local: bool
+}
+#[derive(Parser)]
+#[clap(about = "List the keys the signing agent holds")]
+pub struct AgentCommandList {
+    #[clap(short = 's', long = "socket", value_parser, help = "The socket of the agent, instead of MANGROVE_AGENT_SOCK or the default one")]
+    pub socket: Option<String>,
+    #[clap(short = 'l', long = "local", action = ArgAction::SetTrue, default_value_t = false, help = "Use a local agent socket instead of the default system-wide one")]
+    pub local: bool
+}
+#[derive(Parser)]
+#[clap(about = "Stop the signing agent")]
+pub struct AgentCommandStop {
+    #[clap(short = 's', long = "socket", value_parser, help = "The socket of the agent, instead of MANGROVE_AGENT_SOCK or the default one")]
+    pub socket: Option<String>,
+    #[clap(short = 'l', long = "local", action = ArgAction::SetTrue, default_value_t = false, help = "Use a local agent socket instead of the default system-wide one")]
+    pub local: bool
+}
+
+impl ExecutableCommand for AgentCommand {
+    fn execute(&self) -> Result<(), Box<dyn Error>> {
+        match &self.command {
+            AgentCommandOptions::Start(start) => start.execute()?,
+            AgentCommandOptions::List(list) => list.execute()?,
+            AgentCommandOptions::Stop(stop) => stop.execute()?
+        }
+        Ok(())
+    }
+}
+impl ExecutableCommand for AgentCommandStart {
+    fn execute(&self) -> Result<(), Box<dyn Error>> {
+        let keystore = keystore_load(self.local)?;
+        let entries = if self.keys.is_empty() {
+            keystore.keys.iter().collect::<Vec<_>>()
+        } else {
+            let mut entries = vec![];
+            for query in &self.keys {
+                if let Some(entry) = keystore.find(query) {
+                    entries.push(entry);
+                } else {
+                    err(format!("no key matching {query} in the key store"));
+                    return Ok(());
+                }
+            }
+            entries
+        };
+        if entries.is_empty() {
+            err("there are no keys in the key store for the agent to hold".into());
+            return Ok(());
+        }
+        let mut keys = vec![];
+        for entry in entries {
+            let name = if entry.name.is_empty() { &entry.fingerprint } else { &entry.name };
+            let passphrase = match get_passphrase(self.passphrase_file.as_ref(), &format!("Passphrase for {name}:"), false) {
+                Ok(p) => p,
+                Err(e) => {
+                    err(format!("{e}, the agent was not started"));
+                    return Ok(());
+                }
+            };
+            match entry.decrypt(passphrase.as_bytes()) {
+                Ok(key) => keys.push(key),
+                Err(e) => {
+                    err(format!("{e}, the agent was not started"));
+                    return Ok(());
+                }
+            }
+        }
+
+        let socket = self.socket.clone().unwrap_or_else(|| get_agent_socket(self.local));
+        let listener = match agent_bind(&socket) {
+            Ok(l) => l,
+            Err(e) => {
+                err(format!("{e}"));
+                return Ok(());
+            }
+        };
+        info(format!("holding {} keys, listening on {}", keys.len(), socket.blue()));
+        info(format!("to use the agent from another shell, export MANGROVE_AGENT_SOCK={socket}"));
+        Agent::new(keys).serve(&listener)?;
+        info("the agent was stopped".into());
+        Ok(())
+    }
+}
+impl ExecutableCommand for AgentCommandList {
+    fn execute(&self) -> Result<(), Box<dyn Error>> {
+        let socket = self.socket.clone().unwrap_or_else(|| get_agent_socket(self.local));
+        let keys = match AgentClient::connect(&socket).and_then(|client| client.list()) {
+            Ok(k) => k,
+            Err(e) => {
+                err(format!("{e}"));
+                return Ok(());
+            }
+        };
+        let mut tw = TabWriter::new(stdout());
+        writeln!(&mut tw, "Name\tFingerprint\tPublic key")?;
+        for key in &keys {
+            let name = if key.name == "__anonymous__" { "-" } else { &key.name };
+            writeln!(&mut tw, "{name}\t{}\t{}", &key.fingerprint()[..16], key.to_anonymous())?;
+        }
+        tw.flush()?;
+        Ok(())
+    }
+}
+impl ExecutableCommand for AgentCommandStop {
+    fn execute(&self) -> Result<(), Box<dyn Error>> {
+        let socket = self.socket.clone().unwrap_or_else(|| get_agent_socket(self.local));
+        match AgentClient::connect(&socket).and_then(|client| client.stop()) {
+            Ok(()) => info(format!("stopped the signing agent at {}", socket.blue())),
+            Err(e) => err(format!("{e}"))
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,75 @@
+use std::error::Error;
+
+use clap::{ArgAction, Parser};
+
+use libmangrove::pkgdb::{pkgdb_load, pkgdb_save};
+
+use crate::{err, ExecutableCommand};
+use crate::util::info;
+
+#[derive(Parser)]
+#[clap(name = "hold", about = "Hold installed packages, so that they cannot be upgraded, reinstalled or removed. Lists held packages if none are given", version, author)]
+pub struct HoldCommand {
+    #[clap(name = "package", help = "Specify an installed package to hold")]
+    pub packages: Vec<String>,
+
+    #[clap(name = "local", short = 'l', long = "local", help = "Use a local database file", action = ArgAction::SetTrue, default_value_t = false)]
+    pub local: bool
+}
+
+impl ExecutableCommand for HoldCommand {
+    fn execute(&self) -> Result<(), Box<dyn Error>> {
+        let mut pkgdb = pkgdb_load(self.local)?;
+
+        if self.packages.is_empty() {
+            if pkgdb.db.held_packages.is_empty() {
+                info("no packages are held".into());
+            }
+            for pkgname in &pkgdb.db.held_packages {
+                println!("{pkgname}");
+            }
+            pkgdb_save(pkgdb, self.local)?;
+            return Ok(());
+        }
+
+        for pkgname in &self.packages {
+            match pkgdb.db.hold(pkgname) {
+                Ok(()) => info(format!("held {pkgname}")),
+                Err(e) => err(e.to_string())
+            }
+        }
+        pkgdb_save(pkgdb, self.local)?;
+
+        Ok(())
+    }
+}
+
+#[derive(Parser)]
+#[clap(name = "unhold", about = "Release the hold on packages", version, author)]
+pub struct UnholdCommand {
+    #[clap(name = "package", help = "Specify a held package")]
+    pub packages: Vec<String>,
+
+    #[clap(name = "local", short = 'l', long = "local", help = "Use a local database file", action = ArgAction::SetTrue, default_value_t = false)]
+    pub local: bool
+}
+
+impl ExecutableCommand for UnholdCommand {
+    fn execute(&self) -> Result<(), Box<dyn Error>> {
+        if self.packages.is_empty() {
+            err("no targets specified".into());
+            return Ok(());
+        }
+
+        let mut pkgdb = pkgdb_load(self.local)?;
+        for pkgname in &self.packages {
+            match pkgdb.db.unhold(pkgname) {
+                Ok(()) => info(format!("unheld {pkgname}")),
+                Err(e) => err(e.to_string())
+            }
+        }
+        pkgdb_save(pkgdb, self.local)?;
+
+        Ok(())
+    }
+}
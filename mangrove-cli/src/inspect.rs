@@ -4,7 +4,7 @@ use std::path::PathBuf;
 
 use clap::{ArgAction, Parser};
 
-use libmangrove::crypt::{debug_dump_package, decrypt_package, find_key, is_signed_package, PublicKey};
+use libmangrove::crypt::{debug_dump_package, decrypt_package, find_key, is_signed_package, package_fingerprint, PublicKey};
 use libmangrove::pkg::{dump_package, load_package};
 use libmangrove::trustcache::{trustcache_load, trustcache_save};
 
@@ -48,9 +48,10 @@ impl ExecutableCommand for InspectCommand {
             println!("Package Type: Signed");
             println!("Signed Package Format Dump");
             println!("{}", debug_dump_package(data.clone(), key.as_ref()));
+            let fingerprint = package_fingerprint(&data);
             let mut foundkey = key;
             if foundkey.is_none() {
-                println!("no key provided, trying trustcache");
+                println!("no key provided, looking up the signer in the trustcache");
                 let trustcache = match trustcache_load(args.local_cache) {
                     Ok(t) => t,
                     Err(e) => {
@@ -68,10 +69,19 @@ impl ExecutableCommand for InspectCommand {
                         std::process::exit(1);
                     }
                 }
-                if realkey.is_none() {
-                    println!("err: no keys avaliable in trustcache");
-                    println!("err: decryption key missing, cannot proceed");
-                    std::process::exit(1);
+                match (&realkey, &fingerprint) {
+                    (Some(k), _) if k.name != "__anonymous__" => println!("Signed by: {} ({}, trusted key {})", k.fingerprint(), k.name, k.to_anonymous()),
+                    (Some(k), _) => println!("Signed by: {} (trusted key {})", k.fingerprint(), k.to_anonymous()),
+                    (None, Some(f)) => {
+                        println!("Signed by: {f} (not a trusted key, see mgve trust query)");
+                        println!("err: decryption key missing, cannot proceed");
+                        std::process::exit(1);
+                    },
+                    (None, None) => {
+                        println!("Signed by: unknown (legacy packages do not record their signer, and no key in the trustcache matched)");
+                        println!("err: decryption key missing, cannot proceed");
+                        std::process::exit(1);
+                    }
                 }
                 foundkey = realkey;
             }
@@ -1,15 +1,21 @@
-use std::collections::HashMap;
 use std::error::Error;
-use std::fs;
-use std::fs::create_dir_all;
-use std::io::{Read, stdin, stdout, Write};
-use std::path::{Path};
+use std::fs::{self, create_dir_all, File};
+use std::io::{BufReader, Read, stdin, stdout, Write};
+use std::path::{Path, PathBuf};
 use clap::{Parser, ArgAction};
 use human_bytes::human_bytes;
 use tabwriter::TabWriter;
-use libmangrove::crypt::{decrypt_package, find_key, is_signed_package};
-use libmangrove::pkg::{get_pkg_filename, install_pkg_to, load_package, Package};
+use libmangrove::config::get_package_cache_dir;
+use libmangrove::crypt::{find_key_stream, SpfVersion};
+use libmangrove::pkg::{Package, PkgSpec, StoredPackage};
 use libmangrove::pkgdb::{pkgdb_load, pkgdb_save};
+use libmangrove::platform::host_arch;
+use libmangrove::repo::fetch_package;
+use libmangrove::db::Database;
+use libmangrove::sig::{DetachedSignature, get_sig_path, trusted_detached_key};
+use libmangrove::resolver::{PackageSource, resolve, Resolution};
+use libmangrove::sync::sync_repositories;
+use libmangrove::transaction::{Transaction, TransactionAction};
 use libmangrove::trustcache::{trustcache_load, trustcache_save};
 use crate::{err, ExecutableCommand};
 use crate::util::{info, warn};
@@ -17,7 +23,7 @@ use crate::util::{info, warn};
 #[derive(Parser)]
 #[clap(name = "install", about = "Install Mangrove package files", version, author)]
 pub struct InstallCommand {
-    #[clap(name = "package", help = "Specify a repository package or file to install")]
+    #[clap(name = "package", help = "Specify a package file, or a package spec such as pkgname, pkgname>=1.0.0, pkgname@1.2.3 or repo/pkgname<2.0.0")]
     pub packages: Vec<String>,
 
     #[clap(name = "sync", short = 'S', long = "--sync", help = "Sync remote repositories to get an updated list of avaliable packages", action = ArgAction::SetTrue, default_value_t = false)]
@@ -27,7 +33,10 @@ pub struct InstallCommand {
     pub target: String,
 
     #[clap(name = "local", short = 'l', long = "--local", help = "Use a local database file", action = ArgAction::SetTrue, default_value_t = false)]
-    pub local: bool
+    pub local: bool,
+
+    #[clap(name = "allow-stale", long = "--allow-stale", help = "When syncing, accept repository data that is older than the synced data or has expired. This can hide updates, only use it if you know why the repository data is stale", action = ArgAction::SetTrue, default_value_t = false)]
+    pub allow_stale: bool
 }
 
 impl ExecutableCommand for InstallCommand {
@@ -38,8 +47,7 @@ impl ExecutableCommand for InstallCommand {
         }
 
         if self.sync {
-            // DO SYNC
-            println!("Syncing configured repositories...");
+            sync_configured(self.local, self.allow_stale)?;
             if self.packages.len() == 0 {
                 return Ok(());
             }
@@ -48,6 +56,7 @@ impl ExecutableCommand for InstallCommand {
         info("loading packages...".into());
 
         let mut files_to_install: Vec<String> = vec![];
+        let mut specs_to_install: Vec<PkgSpec> = vec![];
 
         info("resolving packages...".into());
         for package in &self.packages {
@@ -55,55 +64,64 @@ impl ExecutableCommand for InstallCommand {
             if file.exists() && file.is_file() {
                 files_to_install.push(package.clone());
             } else {
-                warn(format!("installing from repositories is currently not implemented, skipping {}", package));
-                print!("One or more packages could not be resolved. Continue? [Y/n] ");
-                let _=stdout().flush();
-
-                let mut c: [u8; 1] = [0];
-                stdin().read_exact(&mut c)?;
-                let c = c[0] as char;
-                if c == 'n' || c == 'N' {
-                    println!("Aborted by user");
-                    return Ok(());
-                }
-                continue;
+                let spec: PkgSpec = match package.parse() {
+                    Ok(s) => s,
+                    Err(e) => {
+                        err(format!("{package} is not a file or a valid package spec: {e}"));
+                        return Ok(());
+                    }
+                };
+                specs_to_install.push(spec);
             }
         }
 
         info("loading packages...".into());
 
-        let mut packages_to_install: HashMap<String, Package> = HashMap::new();
+        let mut packages_to_install: Vec<StoredPackage> = vec![];
 
         let mut needs_trustcache = false;
         let mut packages_need_decryption: Vec<String> = vec![];
+        let mut packages_need_verification: Vec<String> = vec![];
 
         for file in files_to_install {
-            let data = match fs::read(file.clone()) {
-                Ok(d) => d,
-                Err(e) => {
-                    warn(format!("an error occured reading {} ({}), it will be skipped", file, e).into());
-                    print!("One or more packages could not be read. Continue? [Y/n] ");
-                    let _=stdout().flush();
+            // only the magic is needed to tell signed packages apart, the packages themselves are read from their files as they are installed
+            let mut magic: Vec<u8> = vec![];
+            if let Err(e) = File::open(&file).and_then(|f| f.take(5).read_to_end(&mut magic)) {
+                warn(format!("an error occured reading {file} ({e}), it will be skipped"));
+                print!("One or more packages could not be read. Continue? [Y/n] ");
+                let _=stdout().flush();
 
-                    let mut c: [u8; 1] = [0];
-                    stdin().read_exact(&mut c)?;
-                    let c = c[0] as char;
-                    if c == 'n' || c == 'N' {
-                        println!("Aborted by user");
-                        return Ok(());
-                    }
-                    continue;
+                let mut c: [u8; 1] = [0];
+                stdin().read_exact(&mut c)?;
+                let c = c[0] as char;
+                if c == 'n' || c == 'N' {
+                    println!("Aborted by user");
+                    return Ok(());
                 }
-            };
-            if is_signed_package(data.clone()) {
+                continue;
+            }
+            if SpfVersion::detect(&magic).is_some() {
                 needs_trustcache = true;
                 packages_need_decryption.push(file);
+            } else if get_sig_path(Path::new(&file)).exists() {
+                // plain packages with a detached signature next to them are checked against the trustcache as well
+                needs_trustcache = true;
+                packages_need_verification.push(file);
             } else {
-                let pkg = match load_package(&data) {
-                    Ok(p) => p,
+                packages_to_install.push(StoredPackage::unsigned(file));
+            }
+        }
+
+        if needs_trustcache {
+            let trustcache = trustcache_load(self.local)?;
+            info("finding package keys".into());
+            for file in packages_need_decryption {
+                let key = File::open(&file).map_err(Into::into).and_then(|f| find_key_stream(BufReader::new(f), &trustcache));
+                match key {
+                    Ok(k) => packages_to_install.push(StoredPackage::signed(file, k)),
                     Err(e) => {
-                        err(format!("error loading package: {}, it will be skipped", e).into());
-                        print!("One or more packages could not be loaded. Continue? [Y/n] ");
+                        warn(format!("cannot decrypt {} ({}), it will be skipped", &file, e));
+                        print!("One or more packages could not be decrypted. Continue? [Y/n] ");
                         let _=stdout().flush();
 
                         let mut c: [u8; 1] = [0];
@@ -113,22 +131,24 @@ impl ExecutableCommand for InstallCommand {
                             println!("Aborted by user");
                             return Ok(());
                         }
-                        continue;
                     }
-                };
-                packages_to_install.insert(file, pkg);
+                }
             }
-        }
-
-        if needs_trustcache {
-            let trustcache = trustcache_load(self.local)?;
-            info("decrypting packages".into());
-            for file in packages_need_decryption {
-                let data = match fs::read(file.clone()) {
-                    Ok(d) => d,
+            if !packages_need_verification.is_empty() {
+                info("checking detached signatures".into());
+            }
+            for file in packages_need_verification {
+                let sig_path = get_sig_path(Path::new(&file));
+                let find_signer = || -> Result<StoredPackage, Box<dyn Error>> {
+                    let signature = DetachedSignature::from_bytes(&fs::read(&sig_path)?)?;
+                    let key = trusted_detached_key(&signature, &trustcache)?;
+                    Ok(StoredPackage::detached(&file, key, signature))
+                };
+                match find_signer() {
+                    Ok(stored) => packages_to_install.push(stored),
                     Err(e) => {
-                        warn(format!("an error occured decrypting {} ({}), it will be skipped", file, e).into());
-                        print!("One or more packages could not be loaded. Continue? [Y/n] ");
+                        err(format!("failed to verify {} against {} ({}), it will be skipped", &file, sig_path.display(), e));
+                        print!("One or more packages could not be verified. Continue? [Y/n] ");
                         let _=stdout().flush();
 
                         let mut c: [u8; 1] = [0];
@@ -138,70 +158,20 @@ impl ExecutableCommand for InstallCommand {
                             println!("Aborted by user");
                             return Ok(());
                         }
-                        continue;
                     }
-                };
-                if !is_signed_package(data.clone()) { continue; }
-
-                let key = find_key(&data[..], &trustcache);
-
-                if let Some(dec_k) = key {
-                    let data_dec = match decrypt_package(&dec_k, &data[..]) {
-                        Ok(d) => d,
-                        Err(e) => {
-                            err(format!("failed to decrypt {} ({}), it will be skipped", &file, e).into());
-                            print!("One or more packages could not be decrypted. Continue? [Y/n] ");
-                            let _=stdout().flush();
-
-                            let mut c: [u8; 1] = [0];
-                            stdin().read_exact(&mut c)?;
-                            let c = c[0] as char;
-                            if c == 'n' || c == 'N' {
-                                println!("Aborted by user");
-                                return Ok(());
-                            }
-                            continue;
-                        }
-                    };
-                    let pkg = match load_package(&data_dec) {
-                        Ok(d) => d,
-                        Err(e) => {
-                            err(format!("failed to load decrypted {} ({}), it will be skipped", &file, e).into());
-                            print!("One or more packages could not be loaded after decryption. Continue? [Y/n] ");
-                            let _=stdout().flush();
-
-                            let mut c: [u8; 1] = [0];
-                            stdin().read_exact(&mut c)?;
-                            let c = c[0] as char;
-                            if c == 'n' || c == 'N' {
-                                println!("Aborted by user");
-                                return Ok(());
-                            }
-                            continue;
-                        }
-                    };
-                    create_dir_all("/tmp/mgve")?;
-                    match fs::write(format!("/tmp/mgve/DECRYPTED_TMP_PACKAGE_MM_pkg{}.mgve", get_pkg_filename(&pkg)), data_dec) {
-                        Ok(_) => (),
-                        Err(e) => {
-                            err(format!("failed to write to temporary file {} ({}), {} will be skipped", format!("/tmp/mgve/DECRYPTED_TMP_PACKAGE_MM_pkg{}.mgve", get_pkg_filename(&pkg)), e, &file).into());
-                            print!("One or more packages could not be decrypted. Continue? [Y/n] ");
-                            let _=stdout().flush();
-
-                            let mut c: [u8; 1] = [0];
-                            stdin().read_exact(&mut c)?;
-                            let c = c[0] as char;
-                            if c == 'n' || c == 'N' {
-                                println!("Aborted by user");
-                                return Ok(());
-                            }
-                            continue;
-                        }
-                    };
-                    packages_to_install.insert(format!("/tmp/mgve/DECRYPTED_TMP_PACKAGE_MM_pkg{}.mgve", get_pkg_filename(&pkg)), pkg);
-                } else {
-                    warn(format!("no key avaliable to decrypt {}, it will be skipped", &file).into());
-                    print!("One or more packages could not be decrypted. Continue? [Y/n] ");
+                }
+            }
+            trustcache_save(trustcache, self.local)?;
+        }
+
+        // loading a package reads and verifies all of it, signed packages are decrypted on the way
+        let mut local_packages: Vec<(Package, StoredPackage)> = vec![];
+        for stored in packages_to_install {
+            match stored.load() {
+                Ok(p) => local_packages.push((p, stored)),
+                Err(e) => {
+                    err(format!("error loading {}: {e}, it will be skipped", stored.path.display()));
+                    print!("One or more packages could not be loaded. Continue? [Y/n] ");
                     let _=stdout().flush();
 
                     let mut c: [u8; 1] = [0];
@@ -213,97 +183,45 @@ impl ExecutableCommand for InstallCommand {
                     }
                 }
             }
-            trustcache_save(trustcache, self.local)?;
         }
 
-        println!("Caluclating conflicts...");
-        let pkgdb = pkgdb_load(self.local)?;
+        let mut pkgdb = pkgdb_load(self.local)?;
 
-        for (filename, pkginfo) in &packages_to_install {
-            let conflicting = pkgdb.db.installed_packages.iter().find(|pkg| {
-                if let Some(conflicts) = &pkginfo.conflicts {
-                    if conflicts.iter().any(|conflict| conflict.pkgname == pkg.pkgname && conflict.version.matches(&pkg.pkgver)) {
-                        return true;
-                    }
-                }
-                if let Some(conflicts) = &pkg.conflicts {
-                    return conflicts.iter().find(|conflict| conflict.pkgname == pkginfo.pkgname && conflict.version.matches(&pkginfo.pkgver)).is_some()
-                }
-                false
-            });
-            if let Some(conflict) = conflicting {
-                if filename.contains("DECRYPTED_TMP_PACKAGE_MM_pkg") {
-                    println!("Deleting temporary file {}...", filename);
-                    match fs::remove_file(filename) {
-                        Ok(_) => (),
-                        Err(e) => {
-                            err(format!("error removing temporary dir: {}", e));
-                            pkgdb_save(pkgdb, self.local)?;
-                            return Ok(())
-                        }
-                    }
-                }
-                err(format!("{} conflicts with {}, please resolve conflicts first", pkginfo.pkgname, conflict.pkgname));
-                return Ok(())
-            }
+        println!("Resolving dependencies...");
+        let arch = host_arch();
+        if arch.is_none() && !specs_to_install.is_empty() {
+            err("this architecture is not supported by mangrove repositories".into());
+            pkgdb_save(pkgdb, self.local)?;
+            return Ok(());
         }
-
-        println!("Resolving dependencies..");
-
-        let mut package_installation_queue: Vec<String> = vec![];
-        // TODO: real dependency resolution here, add missing packages
-        for (filename, pkginfo) in &packages_to_install {
-            if let Some(dependencies) = &pkginfo.depends {
-                for dependency in dependencies {
-                    if !&pkgdb.db.installed_packages.iter().any(|x| x.pkgname == dependency.pkgname && dependency.version.matches(&x.pkgver)) {
-                        if let Some(other_pkg) = &packages_to_install.iter().find(|(_, v)| v.pkgname == dependency.pkgname && dependency.version.matches(&v.pkgver)) {
-                            if !package_installation_queue.contains(&other_pkg.0.clone()) { package_installation_queue.push(other_pkg.0.clone()); }
-                            continue;
-                        }
-                        if filename.contains("DECRYPTED_TMP_PACKAGE_MM_pkg") {
-                            println!("Deleting temporary file {}...", filename);
-                            match fs::remove_file(filename) {
-                                Ok(_) => (),
-                                Err(e) => {
-                                    err(format!("error removing temporary dir: {}", e));
-                                    pkgdb_save(pkgdb, self.local)?;
-                                    return Ok(())
-                                }
-                            }
-                        }
-                        err(format!("{} has required dependency {}{} that is not installed", pkginfo.pkgname, dependency.pkgname, dependency.version).into());
-                        return Ok(())
-                    }
+        let resolution = match resolve(&specs_to_install, &local_packages, &pkgdb.db, arch.as_ref()) {
+            Ok(r) => r,
+            Err(e) => {
+                for problem in e.to_string().lines() {
+                    err(problem.to_string());
                 }
-                if !package_installation_queue.contains(&filename.clone()) { package_installation_queue.push(filename.clone()); }
+                err("the requested packages cannot be installed, try syncing with -S".into());
+                pkgdb_save(pkgdb, self.local)?;
+                return Ok(());
             }
-            if pkgdb.db.installed_packages.iter().any(|x| x.pkgname == pkginfo.pkgname && pkginfo.pkgver >= x.pkgver) {
-                warn(format!("{} is up to date - reinstalling", pkginfo.pkgname));
+        };
+
+        println!("Building transaction...");
+        let mut transaction = build_transaction(&resolution, &pkgdb.db);
+
+        println!("Checking transaction...");
+        if let Err(e) = transaction.validate(&pkgdb.db) {
+            for problem in e.to_string().lines() {
+                err(problem.to_string());
             }
+            err("please resolve these problems first".into());
+            pkgdb_save(pkgdb, self.local)?;
+            return Ok(());
         }
         println!();
 
-        pkgdb_save(pkgdb, self.local)?;
-
         println!("To install:");
-        let mut tw = TabWriter::new(stdout());
-        write!(&mut tw, "Number\tName\tVersion\tSize\n")?;
-        let mut total_size = 0;
-        let mut i = 1;
-        for package_name in &package_installation_queue {
-            let package = match packages_to_install.get(package_name) {
-                Some(p) => p,
-                None => {
-                    err(format!("failed to process installation queue: {}", package_name).into());
-                    return Ok(());
-                }
-            };
-            write!(&mut tw, "{}\t{}\t{}\t{}\n", i, package.pkgname, package.pkgver, human_bytes(package.installed_size as f64))?;
-            total_size += package.installed_size;
-            i+=1;
-        }
-        tw.flush()?;
-        println!("Total installed size: {}\n", human_bytes(total_size as f64));
+        print_transaction(&transaction, &resolution, &pkgdb.db)?;
 
         print!("Continue with installation: [Y/n] ");
         let _=stdout().flush();
@@ -313,72 +231,161 @@ impl ExecutableCommand for InstallCommand {
         let c = c[0] as char;
         if c == 'n' || c == 'N' {
             println!("Aborted by user");
+            pkgdb_save(pkgdb, self.local)?;
             return Ok(());
         }
+        let downloaded = match download_packages(&mut transaction, &resolution, &pkgdb.db, self.local) {
+            Ok(d) => d,
+            Err(e) => {
+                err(e.to_string());
+                err("please resolve these problems first".into());
+                pkgdb_save(pkgdb, self.local)?;
+                return Ok(());
+            }
+        };
         println!("Installing packages...");
 
-        let mut pkgdb = pkgdb_load(self.local)?;
-        for file in package_installation_queue {
-            let pkg = match packages_to_install.get(&*file.clone()) {
-                Some(p) => p,
-                None => {
-                    err(format!("missing in installation queue: {}", file));
-                    return Ok(());
-                }
-            };
-            let data = match fs::read(file.clone()) {
-                Ok(d) => d,
-                Err(e) => {
-                    err(format!("failed to read package: {}", e));
-                    if file.contains("DECRYPTED_TMP_PACKAGE_MM_pkg") {
-                        println!("Deleting temporary file {}...", file);
-                        match fs::remove_file(file) {
-                            Ok(_) => (),
-                            Err(e) => {
-                                err(format!("error removing temporary dir: {}", e));
-                                pkgdb_save(pkgdb, self.local)?;
-                                return Ok(())
-                            }
-                        }
-                    }
-                    pkgdb_save(pkgdb, self.local)?;
-                    return Ok(())
-                }
-            };
-            println!("Installing {}-{}...", pkg.pkgname, pkg.pkgver);
-            match install_pkg_to(&data, (&self.target).clone(), &mut pkgdb) {
-                Ok(_) => (),
-                Err(e) => {
-                    err(format!("error installing package: {}", e));
-                    if file.contains("DECRYPTED_TMP_PACKAGE_MM_pkg") {
-                        println!("Deleting temporary file {}...", file);
-                        match fs::remove_file(file) {
-                            Ok(_) => (),
-                            Err(e) => {
-                                err(format!("error removing temporary dir: {}", e));
-                                pkgdb_save(pkgdb, self.local)?;
-                                return Ok(())
-                            }
-                        }
-                    }
-                    pkgdb_save(pkgdb, self.local)?;
-                    return Ok(())
-                }
-            }
-            if file.contains("DECRYPTED_TMP_PACKAGE_MM_pkg") {
-                println!("Deleting temporary file {}...", file);
-                match fs::remove_file(file) {
-                    Ok(_) => (),
-                    Err(e) => {
-                        err(format!("error removing temporary dir: {}", e));
-                        pkgdb_save(pkgdb, self.local)?;
-                        return Ok(())
-                    }
-                }
-            }
+        let result = transaction.apply(self.target.clone(), &mut pkgdb);
+        remove_downloads(&downloaded);
+        if let Err(e) = result {
+            err(format!("error applying transaction: {e}"));
+            pkgdb_save(pkgdb, self.local)?;
+            return Ok(())
         }
         pkgdb_save(pkgdb, self.local)?;
 
         Ok(())
     }
-}
\ No newline at end of file
+}
+// sync_configured
+/// Sync every configured repository, reporting the result for each one
+//
+pub fn sync_configured(local: bool, allow_stale: bool) -> Result<(), Box<dyn Error>> {
+    info("syncing configured repositories...".into());
+    if allow_stale {
+        warn("accepting repository data that is older than the stored data or expired".into());
+    }
+    let trustcache = trustcache_load(local)?;
+    let mut pkgdb = pkgdb_load(local)?;
+    if pkgdb.db.repositories.is_empty() {
+        warn("no repositories are configured".into());
+    }
+    for configured in pkgdb.db.repositories.iter().filter(|x| x.pinned_key().is_none()) {
+        warn(format!("{} has no pinned signing key and will not sync, pin one with mgve repo pin", configured.baseurl));
+    }
+    for (baseurl, result) in sync_repositories(&mut pkgdb.db, &trustcache, allow_stale) {
+        match result {
+            Ok(()) => info(format!("synced {baseurl}")),
+            Err(e) => err(format!("failed to sync {baseurl}: {e}"))
+        }
+    }
+    pkgdb_save(pkgdb, local)?;
+    trustcache.lockfile.release()?;
+    Ok(())
+}
+
+// build_transaction
+/// Turn a resolution into a transaction. Packages from repositories have no package file until `download_packages` is called.
+//
+pub fn build_transaction(resolution: &Resolution, db: &Database) -> Transaction {
+    let mut transaction = Transaction::new();
+    for pkgname in &resolution.replaced {
+        transaction.remove(pkgname);
+    }
+    for resolved in &resolution.install {
+        let data = match &resolved.source {
+            PackageSource::Local(stored) => stored.clone(),
+            PackageSource::Repository(_) => StoredPackage::default()
+        };
+        let pkg = resolved.package.clone();
+        match db.installed_packages.iter().find(|x| x.pkgname == pkg.pkgname) {
+            Some(installed) if pkg.pkgver > installed.pkgver => transaction.actions.push(TransactionAction::Update(pkg, data)),
+            Some(_) => {
+                warn(format!("{} is up to date - reinstalling", pkg.pkgname));
+                transaction.actions.push(TransactionAction::Reinstall(pkg, data));
+            },
+            None => transaction.actions.push(TransactionAction::Install(pkg, data))
+        }
+    }
+    transaction
+}
+
+// print_transaction
+/// Print a table of everything a transaction built from `resolution` is going to do
+//
+pub fn print_transaction(transaction: &Transaction, resolution: &Resolution, db: &Database) -> Result<(), Box<dyn Error>> {
+    let mut tw = TabWriter::new(stdout());
+    writeln!(&mut tw, "Number\tAction\tName\tVersion\tSize\tReason")?;
+    let mut total_size = 0;
+    let mut i = 1;
+    for action in transaction.install_order() {
+        let Some(package) = action.package() else { continue };
+        #[allow(clippy::cast_precision_loss)] // Only used for display purposes
+        let size = human_bytes(package.installed_size as f64);
+        let required_by = resolution.install.iter().find(|x| x.package.pkgname == package.pkgname).and_then(|x| x.required_by.as_ref());
+        let replaced = package.replaces.iter().flatten().find(|x| resolution.replaced.contains(&x.pkgname));
+        let reason = match (required_by, replaced) {
+            (Some(parent), _) => format!("dependency of {parent}"),
+            (None, Some(replaced)) => format!("replaces {}", replaced.pkgname),
+            (None, None) => "requested".to_string()
+        };
+        let version = match db.installed_packages.iter().find(|x| x.pkgname == package.pkgname) {
+            Some(installed) if installed.pkgver != package.pkgver => format!("{} -> {}", installed.pkgver, package.pkgver),
+            _ => package.pkgver.to_string()
+        };
+        writeln!(&mut tw, "{}\t{}\t{}\t{}\t{}\t{}", i, action.action_str(), package.pkgname, version, size, reason)?;
+        total_size += package.installed_size;
+        i+=1;
+    }
+    tw.flush()?;
+    if !resolution.replaced.is_empty() {
+        println!("To remove (replaced): {}", resolution.replaced.join(", "));
+    }
+    #[allow(clippy::cast_precision_loss)] // Only used for display purposes
+    let total_size = human_bytes(total_size as f64);
+    println!("Total installed size: {total_size}\n");
+    Ok(())
+}
+
+// download_packages
+/// Download every package in `resolution` that comes from a repository into the package cache, and fill in its package file in the transaction,
+/// returning the paths of the downloaded files
+/// # Errors
+/// This function will error if a package could not be downloaded. Packages that were already downloaded are removed again.
+pub fn download_packages(transaction: &mut Transaction, resolution: &Resolution, db: &Database, local: bool) -> Result<Vec<PathBuf>, Box<dyn Error>> {
+    let cache_dir = get_package_cache_dir(local);
+    create_dir_all(&cache_dir)?;
+    let mut downloaded: Vec<PathBuf> = vec![];
+    for action in &mut transaction.actions {
+        let Some(resolved) = resolution.install.iter().find(|x| x.package.pkgname == action.pkgname()) else { continue };
+        let PackageSource::Repository(baseurl) = &resolved.source else { continue };
+        let Some(repository) = db.repositories.iter().find(|x| &x.baseurl == baseurl) else {
+            remove_downloads(&downloaded);
+            return Err(format!("{} could not be downloaded, repository {baseurl} is not configured", resolved.package.pkgname).into());
+        };
+        info(format!("downloading {} {} from {}", resolved.package.pkgname, resolved.package.pkgver, baseurl));
+        let stored = match fetch_package(repository, &resolved.package, Path::new(&cache_dir)) {
+            Ok(s) => s,
+            Err(e) => {
+                remove_downloads(&downloaded);
+                return Err(format!("{} could not be downloaded ({e})", resolved.package.pkgname).into());
+            }
+        };
+        downloaded.push(stored.path.clone());
+        if let TransactionAction::Install(_, data) | TransactionAction::Update(_, data) | TransactionAction::Reinstall(_, data) = action {
+            *data = stored;
+        }
+    }
+    Ok(downloaded)
+}
+
+// remove_downloads
+/// Remove packages downloaded by `download_packages` from the package cache, warning about any that could not be removed
+//
+pub fn remove_downloads(downloaded: &[PathBuf]) {
+    for path in downloaded {
+        if let Err(e) = fs::remove_file(path) {
+            warn(format!("failed to remove downloaded package {} ({e})", path.display()));
+        }
+    }
+}
@@ -0,0 +1,268 @@
+use std::error::Error;
+use std::fs;
+use std::io::{stdout, Write};
+use std::path::PathBuf;
+
+use clap::{ArgAction, Parser, Subcommand};
+use colored::Colorize;
+use tabwriter::TabWriter;
+
+use libmangrove::config::get_keystore_file;
+use libmangrove::crypt::{PrivateKey, PublicKey};
+use libmangrove::keyfile::{KeyFile, KeyFileKind, keyfile_load, keyfile_save};
+use libmangrove::keyformat::KeyFormat;
+use libmangrove::keystore::{EncryptedKey, KdfParams, keystore_load, keystore_save, migrate_privkeys};
+use libmangrove::trustcache::{allow_pk, is_pk_trusted, key_metadata, known_keys, set_key_metadata, Trustcache, trustcache_load, trustcache_save};
+
+use crate::{err, ExecutableCommand, warn};
+use crate::util::{get_passphrase, info};
+
+#[derive(Parser)]
+#[clap(name = "key", about = "Manage the private keys in the Mangrove key store", version, author)]
+pub struct KeyCommand {
+    #[clap(subcommand)]
+    pub command: KeyCommandOptions,
+}
+
+#[derive(Subcommand)]
+pub enum KeyCommandOptions {
+    #[clap(name = "list")]
+    List(KeyCommandList),
+    #[clap(name = "migrate")]
+    Migrate(KeyCommandMigrate),
+    #[clap(name = "generate")]
+    Generate(KeyCommandGenerate),
+    #[clap(name = "export-public")]
+    ExportPublic(KeyCommandExportPublic),
+    #[clap(name = "import")]
+    Import(KeyCommandImport)
+}
+
+#[derive(Parser)]
+#[clap(about = "List the private keys in the key store")]
+pub struct KeyCommandList {
+    #[clap(short = 'l', long = "local", action = ArgAction::SetTrue, default_value_t = false, help = "Use a local key store instead of the default system-wide one")]
+    pub local: bool
+}
+#[derive(Parser)]
+#[clap(about = "Move the plaintext private keys in the trustcache into the key store, encrypted with a passphrase")]
+pub struct KeyCommandMigrate {
+    #[clap(long = "passphrase-file", value_parser, help = "Read the passphrase to encrypt the keys with from this file, instead of MANGROVE_PASSPHRASE or asking for it")]
+    pub passphrase_file: Option<PathBuf>,
+    #[clap(short = 'l', long = "local", action = ArgAction::SetTrue, default_value_t = false, help = "Use a local trustcache and key store instead of the default system-wide ones")]
+    pub local: bool
+}
+
+#[derive(Parser)]
+#[clap(about = "Generate a new private key into the key store, encrypted with a passphrase")]
+pub struct KeyCommandGenerate {
+    #[clap(help = "A short name for the key, such as the name of its owner")]
+    pub name: String,
+    #[clap(short = 'c', long = "comment", default_value = "", help = "A comment about the key")]
+    pub comment: String,
+    #[clap(short = 'o', long = "output", value_parser, help = "Also write the public key to this key file")]
+    pub output: Option<PathBuf>,
+    #[clap(long = "private-output", value_parser, help = "Also write the encrypted private key to this key file, to import it into another key store")]
+    pub private_output: Option<PathBuf>,
+    #[clap(short = 't', long = "trust", action = ArgAction::SetTrue, default_value_t = false, help = "Also allow the new key in the trustcache")]
+    pub trust: bool,
+    #[clap(long = "passphrase-file", value_parser, help = "Read the passphrase to encrypt the key with from this file, instead of MANGROVE_PASSPHRASE or asking for it")]
+    pub passphrase_file: Option<PathBuf>,
+    #[clap(short = 'l', long = "local", action = ArgAction::SetTrue, default_value_t = false, help = "Use a local trustcache and key store instead of the default system-wide ones")]
+    pub local: bool
+}
+#[derive(Parser)]
+#[clap(about = "Write the public key of a key in the key store or the trustcache to a key file")]
+pub struct KeyCommandExportPublic {
+    #[clap(help = "The name, fingerprint prefix or public key prefix of the key")]
+    pub key: String,
+    #[clap(short = 'o', long = "output", value_parser, help = "The file to write the key to, or - to print it. Defaults to <fingerprint>.pub, or <fingerprint>.pem for PEM")]
+    pub output: Option<PathBuf>,
+    #[clap(short = 'f', long = "format", default_value_t = KeyFormat::Mangrove, value_parser, help = "The format to write the key in: a mangrove key file, an openssh public key, or an SPKI pem file")]
+    pub format: KeyFormat,
+    #[clap(short = 'l', long = "local", action = ArgAction::SetTrue, default_value_t = false, help = "Use a local trustcache and key store instead of the default system-wide ones")]
+    pub local: bool
+}
+#[derive(Parser)]
+#[clap(about = "Import a key file. Private keys are imported into the key store, and public keys into the trustcache")]
+pub struct KeyCommandImport {
+    #[clap(value_parser)]
+    pub file: PathBuf,
+    #[clap(short = 't', long = "trust", action = ArgAction::SetTrue, default_value_t = false, help = "Also allow the public key of a private key file in the trustcache")]
+    pub trust: bool,
+    #[clap(short = 'l', long = "local", action = ArgAction::SetTrue, default_value_t = false, help = "Use a local trustcache and key store instead of the default system-wide ones")]
+    pub local: bool
+}
+
+impl ExecutableCommand for KeyCommand {
+    fn execute(&self) -> Result<(), Box<dyn Error>> {
+        match &self.command {
+            KeyCommandOptions::List(list) => list.execute()?,
+            KeyCommandOptions::Migrate(migrate) => migrate.execute()?,
+            KeyCommandOptions::Generate(generate) => generate.execute()?,
+            KeyCommandOptions::ExportPublic(export) => export.execute()?,
+            KeyCommandOptions::Import(import) => import.execute()?
+        }
+        Ok(())
+    }
+}
+impl ExecutableCommand for KeyCommandList {
+    fn execute(&self) -> Result<(), Box<dyn Error>> {
+        let keystore = keystore_load(self.local)?;
+        let mut tw = TabWriter::new(stdout());
+        writeln!(&mut tw, "Name\tFingerprint\tPublic key\tEncryption")?;
+        for entry in &keystore.keys {
+            let name = if entry.name.is_empty() { "-" } else { &entry.name };
+            let fingerprint = entry.public_key().map_or_else(|_| "invalid key".red().to_string(), |x| x.fingerprint()[..16].to_string());
+            writeln!(&mut tw, "{name}\t{fingerprint}\t{}\t{} (m={}, t={}, p={})", entry.key, entry.kdf, entry.params.m_cost, entry.params.t_cost, entry.params.p_cost)?;
+        }
+        tw.flush()?;
+        let trustcache = trustcache_load(self.local)?;
+        let plaintext = trustcache.keydb.known_privkeys.len();
+        trustcache_save(trustcache, self.local)?;
+        if plaintext > 0 {
+            warn(format!("the trustcache still holds {plaintext} private keys in plaintext, run mgve key migrate to encrypt them"));
+        }
+        Ok(())
+    }
+}
+impl ExecutableCommand for KeyCommandMigrate {
+    fn execute(&self) -> Result<(), Box<dyn Error>> {
+        info("loading the trustcache".into());
+        let mut trustcache = trustcache_load(self.local)?;
+        if trustcache.keydb.known_privkeys.is_empty() {
+            info("there are no plaintext private keys in the trustcache".into());
+            trustcache_save(trustcache, self.local)?;
+            return Ok(());
+        }
+        info(format!("moving {} private keys into the key store", trustcache.keydb.known_privkeys.len()));
+        let res = (|| -> Result<usize, Box<dyn Error>> {
+            let passphrase = get_passphrase(self.passphrase_file.as_ref(), "Passphrase to encrypt the keys with:", true)?;
+            let mut keystore = keystore_load(self.local)?;
+            let moved = migrate_privkeys(&mut trustcache, &mut keystore, passphrase.as_bytes(), KdfParams::default())?;
+            keystore_save(&keystore, self.local)?;
+            Ok(moved)
+        })();
+        match res {
+            Ok(moved) => {
+                // only drop the keys from the trustcache once they are safely in the key store
+                trustcache_save(trustcache, self.local)?;
+                info(format!("moved {moved} private keys into {}", get_keystore_file(self.local).blue()));
+            },
+            Err(e) => {
+                trustcache.lockfile.release()?;
+                err(format!("failed to migrate the private keys, no changes made ({e})"));
+            }
+        }
+        Ok(())
+    }
+}
+impl ExecutableCommand for KeyCommandGenerate {
+    fn execute(&self) -> Result<(), Box<dyn Error>> {
+        let passphrase = match get_passphrase(self.passphrase_file.as_ref(), "Passphrase to encrypt the key with:", true) {
+            Ok(p) => p,
+            Err(e) => {
+                err(format!("{e}, no key generated"));
+                return Ok(());
+            }
+        };
+        let key = PrivateKey::generate(self.name.clone());
+        let pk = key.derive();
+        let entry = EncryptedKey::encrypt(&key, passphrase.as_bytes(), KdfParams::default())?;
+        let mut keystore = keystore_load(self.local)?;
+        keystore.add(entry.clone());
+        keystore_save(&keystore, self.local)?;
+        info(format!("generated key {} with fingerprint {} into {}", self.name.blue(), pk.fingerprint().blue(), get_keystore_file(self.local).blue()));
+        info(format!("its public key is {}", pk.to_anonymous().blue()));
+        if let Some(output) = &self.output {
+            keyfile_save(&KeyFile::public(&pk, &self.comment), output)?;
+            info(format!("wrote the public key to {}", output.display().to_string().blue()));
+        }
+        if let Some(output) = &self.private_output {
+            keyfile_save(&KeyFile::private(entry, &self.comment), output)?;
+            info(format!("wrote the encrypted private key to {}", output.display().to_string().blue()));
+        }
+        if self.trust {
+            info("loading the trustcache".into());
+            let mut trustcache = trustcache_load(self.local)?;
+            trust_key(&mut trustcache, &pk, &self.name, &self.comment)?;
+            trustcache_save(trustcache, self.local)?;
+        }
+        Ok(())
+    }
+}
+impl ExecutableCommand for KeyCommandExportPublic {
+    fn execute(&self) -> Result<(), Box<dyn Error>> {
+        let keystore = keystore_load(self.local)?;
+        let trustcache = trustcache_load(self.local)?;
+        let pk = if let Some(entry) = keystore.find(&self.key) {
+            Some(entry.public_key()?)
+        } else {
+            let keys = known_keys(&trustcache);
+            keys.iter().find(|x| x.name == self.key)
+                .or_else(|| keys.iter().find(|x| x.fingerprint().starts_with(&self.key) || x.to_anonymous().starts_with(&self.key)))
+                .cloned()
+        };
+        let comment = pk.as_ref().and_then(|pk| key_metadata(&trustcache, pk)).map(|x| x.comment.clone()).unwrap_or_default();
+        trustcache_save(trustcache, self.local)?;
+        let Some(pk) = pk else {
+            err(format!("there is no key {} in the key store or the trustcache", self.key.blue()));
+            return Ok(());
+        };
+        let (data, extension) = match self.format {
+            KeyFormat::Mangrove => (KeyFile::public(&pk, &comment).to_string()?, "pub"),
+            KeyFormat::OpenSsh => (format!("{}\n", pk.to_openssh()), "pub"),
+            KeyFormat::Pem => (pk.to_pem(), "pem")
+        };
+        match &self.output {
+            Some(output) if output.to_str() == Some("-") => print!("{data}"),
+            output => {
+                let output = output.clone().unwrap_or_else(|| PathBuf::from(format!("{}.{extension}", pk.fingerprint())));
+                fs::write(&output, data)?;
+                info(format!("wrote the public key {} to {}", pk.fingerprint().blue(), output.display().to_string().blue()));
+            }
+        }
+        Ok(())
+    }
+}
+impl ExecutableCommand for KeyCommandImport {
+    fn execute(&self) -> Result<(), Box<dyn Error>> {
+        let keyfile = match keyfile_load(&self.file) {
+            Ok(k) => k,
+            Err(e) => {
+                err(format!("failed to load the key file {}: {e}", self.file.display().to_string().blue()));
+                return Ok(());
+            }
+        };
+        let name = if keyfile.name.is_empty() { keyfile.fingerprint.clone() } else { keyfile.name.clone() };
+        if let Some(entry) = &keyfile.private {
+            let mut keystore = keystore_load(self.local)?;
+            keystore.add(entry.clone());
+            keystore_save(&keystore, self.local)?;
+            info(format!("imported the private key {} into {}", name.blue(), get_keystore_file(self.local).blue()));
+        }
+        if keyfile.kind == KeyFileKind::Public || self.trust {
+            info("loading the trustcache".into());
+            let mut trustcache = trustcache_load(self.local)?;
+            let res = trust_key(&mut trustcache, &keyfile.public_key()?, &keyfile.name, &keyfile.comment);
+            trustcache_save(trustcache, self.local)?;
+            if let Err(e) = res {
+                err(format!("failed to allow {} in the trustcache: {e}", name.blue()));
+            }
+        }
+        Ok(())
+    }
+}
+
+// trust_key
+// Allow a key in the trustcache, recording its name and comment if they are not empty
+fn trust_key(trustcache: &mut Trustcache, pk: &PublicKey, name: &str, comment: &str) -> Result<(), Box<dyn Error>> {
+    if is_pk_trusted(trustcache, pk)?.is_trusted() {
+        info(format!("{} is already trusted", pk.to_anonymous().blue()));
+    } else {
+        allow_pk(trustcache, pk)?;
+        info(format!("added its public key {} to the trustcache", pk.to_anonymous().blue()));
+    }
+    set_key_metadata(trustcache, pk, Some(name).filter(|x| !x.is_empty()), Some(comment).filter(|x| !x.is_empty()));
+    Ok(())
+}
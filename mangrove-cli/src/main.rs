@@ -16,14 +16,20 @@ use clap::{AppSettings, ArgAction, Parser, Subcommand};
 
 use libmangrove::{detailed_version, gitbranch, version};
 
+use crate::agent::AgentCommand;
 use crate::cli::ExecutableCommand;
 use crate::create::CreateCommand;
+use crate::hold::{HoldCommand, UnholdCommand};
 use crate::inspect::InspectCommand;
 use crate::install::InstallCommand;
+use crate::key::KeyCommand;
+use crate::remove::RemoveCommand;
+use crate::repo::RepoCommand;
 use crate::repogen::RepogenCommand;
 use crate::reportbug::ReportBugCommand;
 use crate::sign::SignCommand;
 use crate::trust::TrustCommand;
+use crate::upgrade::UpgradeCommand;
 use crate::util::{err, warn};
 
 mod inspect;
@@ -33,9 +39,15 @@ mod util;
 mod mgvetoml;
 mod trust;
 mod install;
+mod remove;
 mod sign;
 mod repogen;
 mod reportbug;
+mod upgrade;
+mod hold;
+mod key;
+mod agent;
+mod repo;
 
 #[derive(Parser)]
 #[clap(name = "mgve", about = "Mangrove CLI interface", version, author)]
@@ -65,10 +77,24 @@ pub enum MangroveCLIOptions {
     Create(CreateCommand),
     #[clap(name = "trust")]
     Trust(TrustCommand),
+    #[clap(name = "key")]
+    Key(KeyCommand),
+    #[clap(name = "agent")]
+    Agent(AgentCommand),
     #[clap(name = "install")]
     Install(InstallCommand),
+    #[clap(name = "remove")]
+    Remove(RemoveCommand),
+    #[clap(name = "upgrade")]
+    Upgrade(UpgradeCommand),
+    #[clap(name = "hold")]
+    Hold(HoldCommand),
+    #[clap(name = "unhold")]
+    Unhold(UnholdCommand),
     #[clap(name = "sign")]
     Sign(SignCommand),
+    #[clap(name = "repo")]
+    Repo(RepoCommand),
     #[clap(name = "repogen")]
     Repogen(RepogenCommand),
     #[clap(name = "reportbug")]
@@ -100,8 +126,15 @@ impl ExecutableCommand for MangroveCLI {
             MangroveCLIOptions::Inspect(inspect) => inspect.execute()?,
             MangroveCLIOptions::Create(create) => create.execute()?,
             MangroveCLIOptions::Trust(trust) => trust.execute()?,
+            MangroveCLIOptions::Key(key) => key.execute()?,
+            MangroveCLIOptions::Agent(agent) => agent.execute()?,
             MangroveCLIOptions::Install(install) => install.execute()?,
+            MangroveCLIOptions::Remove(remove) => remove.execute()?,
+            MangroveCLIOptions::Upgrade(upgrade) => upgrade.execute()?,
+            MangroveCLIOptions::Hold(hold) => hold.execute()?,
+            MangroveCLIOptions::Unhold(unhold) => unhold.execute()?,
             MangroveCLIOptions::Sign(sign) => sign.execute()?,
+            MangroveCLIOptions::Repo(repo) => repo.execute()?,
             MangroveCLIOptions::Repogen(repogen) => repogen.execute()?,
             MangroveCLIOptions::ReportBug(reportbug) => reportbug.execute()?
         };
@@ -114,7 +147,8 @@ fn main() {
     match args.execute() {
         Ok(_) => (),
         Err(e) => {
-            err(format!("error while executing subcommand: {}", e))
+            err(format!("error while executing subcommand: {e}"));
+            std::process::exit(1);
         }
     }
 }
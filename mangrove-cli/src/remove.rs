@@ -0,0 +1,118 @@
+use std::error::Error;
+use std::io::{Read, stdin, stdout, Write};
+
+use clap::{ArgAction, Parser};
+use human_bytes::human_bytes;
+use tabwriter::TabWriter;
+
+use libmangrove::pkg::{get_removal_queue, Package};
+use libmangrove::pkgdb::{pkgdb_load, pkgdb_save};
+use libmangrove::transaction::Transaction;
+
+use crate::{err, ExecutableCommand};
+use crate::util::info;
+
+#[derive(Parser)]
+#[clap(name = "remove", about = "Remove installed Mangrove packages", version, author)]
+pub struct RemoveCommand {
+    #[clap(name = "package", help = "Specify an installed package to remove")]
+    pub packages: Vec<String>,
+
+    #[clap(name = "cascade", short = 'c', long = "cascade", help = "Also remove installed packages that depend on the packages being removed", action = ArgAction::SetTrue, default_value_t = false)]
+    pub cascade: bool,
+
+    #[clap(name = "force", short = 'f', long = "force", help = "Remove the packages even if other installed packages depend on them", action = ArgAction::SetTrue, default_value_t = false)]
+    pub force: bool,
+
+    #[clap(name = "target", short = 'T', long = "target", help = "Installation target rootfs. Defaults to /.", default_value_t = String::from("/"))]
+    pub target: String,
+
+    #[clap(name = "local", short = 'l', long = "local", help = "Use a local database file", action = ArgAction::SetTrue, default_value_t = false)]
+    pub local: bool
+}
+
+impl ExecutableCommand for RemoveCommand {
+    fn execute(&self) -> Result<(), Box<dyn Error>> {
+        if self.packages.is_empty() {
+            err("no targets specified".into());
+            return Ok(());
+        }
+
+        info("loading package database...".into());
+        let mut pkgdb = pkgdb_load(self.local)?;
+
+        info("resolving packages...".into());
+        let queue: Vec<Package> = if self.force {
+            let mut queue = vec![];
+            for name in &self.packages {
+                if let Err(e) = pkgdb.db.check_not_held(name) {
+                    err(e.to_string());
+                    pkgdb_save(pkgdb, self.local)?;
+                    return Ok(());
+                }
+                if let Some(p) = pkgdb.db.installed_packages.iter().find(|x| &x.pkgname == name) {
+                    queue.push(p.clone());
+                } else {
+                    err(format!("{name} is not installed"));
+                    pkgdb_save(pkgdb, self.local)?;
+                    return Ok(());
+                }
+            }
+            queue
+        } else {
+            match get_removal_queue(&self.packages, &pkgdb.db, self.cascade) {
+                Ok(q) => q,
+                Err(e) => {
+                    err(format!("{e}"));
+                    if !self.cascade {
+                        err("use -c to also remove dependent packages, or -f to remove anyway".into());
+                    }
+                    pkgdb_save(pkgdb, self.local)?;
+                    return Ok(());
+                }
+            }
+        };
+
+        println!("To remove:");
+        let mut tw = TabWriter::new(stdout());
+        writeln!(&mut tw, "Number\tName\tVersion\tSize")?;
+        let mut total_size = 0;
+        for (i, package) in queue.iter().enumerate() {
+            #[allow(clippy::cast_precision_loss)] // Only used for display purposes
+            let size = human_bytes(package.installed_size as f64);
+            writeln!(&mut tw, "{}\t{}\t{}\t{}", i + 1, package.pkgname, package.pkgver, size)?;
+            total_size += package.installed_size;
+        }
+        tw.flush()?;
+        #[allow(clippy::cast_precision_loss)] // Only used for display purposes
+        let total_size = human_bytes(total_size as f64);
+        println!("Total freed size: {total_size}\n");
+
+        print!("Continue with removal: [Y/n] ");
+        let _=stdout().flush();
+
+        let mut c: [u8; 1] = [0];
+        stdin().read_exact(&mut c)?;
+        let c = c[0] as char;
+        if c == 'n' || c == 'N' {
+            println!("Aborted by user");
+            pkgdb_save(pkgdb, self.local)?;
+            return Ok(());
+        }
+        println!("Removing packages...");
+
+        // every package is removed in one transaction, so either all of them are removed or none are
+        let mut transaction = Transaction::new();
+        transaction.force = self.force;
+        for package in &queue {
+            transaction.remove(&package.pkgname);
+        }
+        if let Err(e) = transaction.apply(self.target.clone(), &mut pkgdb) {
+            pkgdb_save(pkgdb, self.local)?;
+            return Err(format!("error removing packages: {e}").into());
+        }
+        pkgdb_save(pkgdb, self.local)?;
+
+        Ok(())
+    }
+}
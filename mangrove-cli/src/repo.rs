@@ -0,0 +1,152 @@
+use std::error::Error;
+use std::io::{stdout, Write};
+
+use clap::{ArgAction, Parser, Subcommand};
+use colored::Colorize;
+use tabwriter::TabWriter;
+use url::Url;
+
+use libmangrove::crypt::PublicKey;
+use libmangrove::keyformat::ParsedKey;
+use libmangrove::pkgdb::{pkgdb_load, pkgdb_save};
+use libmangrove::repo::get_repository_name;
+use libmangrove::trustcache::trustcache_load;
+
+use crate::{err, ExecutableCommand, warn};
+use crate::trust::read_key;
+use crate::util::info;
+
+#[derive(Parser)]
+#[clap(name = "repo", about = "Manage the repositories configured in the package database, and the signing keys pinned for them", version, author)]
+pub struct RepoCommand {
+    #[clap(subcommand)]
+    pub command: RepoCommandOptions,
+}
+
+#[derive(Subcommand)]
+pub enum RepoCommandOptions {
+    #[clap(name = "add")]
+    Add(RepoCommandAdd),
+    #[clap(name = "pin")]
+    Pin(RepoCommandPin),
+    #[clap(name = "list")]
+    List(RepoCommandList)
+}
+
+#[derive(Parser)]
+#[clap(about = "Configure a repository, pinning the key its repository data must be signed with. The key must already be trusted")]
+pub struct RepoCommandAdd {
+    #[clap(help = "The baseurl of the repository, a http(s):// or file:// URL")]
+    pub baseurl: Url,
+    #[clap(short = 'k', long = "key", help = "The public key of the repository, or a file holding it, as obtained from its maintainer")]
+    pub key: String,
+    #[clap(short = 'l', long = "local", action = ArgAction::SetTrue, default_value_t = false, help = "Use a local database and trustcache instead of the default system-wide ones")]
+    pub local: bool
+}
+#[derive(Parser)]
+#[clap(about = "Pin another signing key for a configured repository. The key must already be trusted")]
+pub struct RepoCommandPin {
+    #[clap(help = "The baseurl of the repository")]
+    pub baseurl: Url,
+    #[clap(short = 'k', long = "key", required_unless_present = "stored", help = "The public key of the repository, or a file holding it, as obtained from its maintainer")]
+    pub key: Option<String>,
+    #[clap(long = "stored", action = ArgAction::SetTrue, default_value_t = false, conflicts_with = "key", help = "Pin the key of the stored repository data instead, for repositories configured before keys were pinned. This trusts whoever served the repository when it last synced")]
+    pub stored: bool,
+    #[clap(short = 'l', long = "local", action = ArgAction::SetTrue, default_value_t = false, help = "Use a local database and trustcache instead of the default system-wide ones")]
+    pub local: bool
+}
+#[derive(Parser)]
+#[clap(about = "List the configured repositories and their pinned keys")]
+pub struct RepoCommandList {
+    #[clap(short = 'l', long = "local", action = ArgAction::SetTrue, default_value_t = false, help = "Use a local database instead of the default system-wide one")]
+    pub local: bool
+}
+
+impl ExecutableCommand for RepoCommand {
+    fn execute(&self) -> Result<(), Box<dyn Error>> {
+        match &self.command {
+            RepoCommandOptions::Add(add) => add.execute()?,
+            RepoCommandOptions::Pin(pin) => pin.execute()?,
+            RepoCommandOptions::List(list) => list.execute()?
+        }
+        Ok(())
+    }
+}
+impl ExecutableCommand for RepoCommandAdd {
+    fn execute(&self) -> Result<(), Box<dyn Error>> {
+        let key = match read_public_key(&self.key) {
+            Ok(k) => k,
+            Err(e) => {
+                err(format!("{e}"));
+                return Ok(());
+            }
+        };
+        let trustcache = trustcache_load(self.local)?;
+        let mut pkgdb = pkgdb_load(self.local)?;
+        let res = pkgdb.db.add_repository(self.baseurl.clone(), key.clone(), &trustcache);
+        trustcache.lockfile.release()?;
+        match res {
+            Ok(()) => info(format!("added {}, pinning key {}. Sync it to fetch its packages", self.baseurl.to_string().blue(), key.fingerprint())),
+            Err(e) => err(format!("{e}"))
+        }
+        pkgdb_save(pkgdb, self.local)?;
+        Ok(())
+    }
+}
+impl ExecutableCommand for RepoCommandPin {
+    fn execute(&self) -> Result<(), Box<dyn Error>> {
+        let key = match self.key.as_deref().map(read_public_key).transpose() {
+            Ok(k) => k,
+            Err(e) => {
+                err(format!("{e}"));
+                return Ok(());
+            }
+        };
+        let trustcache = trustcache_load(self.local)?;
+        let mut pkgdb = pkgdb_load(self.local)?;
+        let res = if let Some(key) = key {
+            pkgdb.db.pin_repository_key(&self.baseurl, key.clone(), &trustcache).map(|()| key.fingerprint())
+        } else if let Some(configured) = pkgdb.db.repositories.iter_mut().find(|x| x.baseurl == self.baseurl) {
+            let res = configured.pin_stored_key(&trustcache).map(PublicKey::fingerprint);
+            if res.is_ok() {
+                warn("pinned the key of the stored repository data, which was taken from the repository itself rather than obtained out of band".into());
+            }
+            res
+        } else {
+            Err(format!("No repository is configured at {}", self.baseurl).into())
+        };
+        trustcache.lockfile.release()?;
+        match res {
+            Ok(fingerprint) => info(format!("pinned key {fingerprint} for {}", self.baseurl.to_string().blue())),
+            Err(e) => err(format!("{e}"))
+        }
+        pkgdb_save(pkgdb, self.local)?;
+        Ok(())
+    }
+}
+impl ExecutableCommand for RepoCommandList {
+    fn execute(&self) -> Result<(), Box<dyn Error>> {
+        let pkgdb = pkgdb_load(self.local)?;
+        let mut tw = TabWriter::new(stdout());
+        writeln!(&mut tw, "Name\tPinned key\tSerial\tBaseurl")?;
+        for configured in &pkgdb.db.repositories {
+            let pinned = configured.pinned_key().map_or_else(|| "none".red().to_string(), |x| x.fingerprint().chars().take(16).collect());
+            writeln!(&mut tw, "{}\t{pinned}\t{}\t{}", get_repository_name(configured), configured.repodata.serial, configured.baseurl)?;
+        }
+        tw.flush()?;
+        if pkgdb.db.repositories.iter().any(|x| x.pinned_key().is_none()) {
+            warn("repositories without a pinned key refuse to sync, pin one with mgve repo pin".into());
+        }
+        pkgdb_save(pkgdb, self.local)?;
+        Ok(())
+    }
+}
+
+// read_public_key
+// Read the public key of a repository given on the command line, see read_key. The public key of a private key is used.
+fn read_public_key(key: &str) -> Result<PublicKey, Box<dyn Error>> {
+    match read_key(key)? {
+        ParsedKey::Public(pk) => Ok(pk),
+        ParsedKey::Private(sk) => Ok(sk.derive())
+    }
+}
@@ -3,21 +3,23 @@ use std::error::Error;
 use std::fs;
 use std::fs::create_dir_all;
 use std::path::PathBuf;
+use std::time::SystemTime;
 
 use clap::{ArgAction, Parser};
 use url::Url;
 
-use libmangrove::crypt::{encrypt_package, is_signed_package, PrivateKey};
+use libmangrove::crypt::{encrypt_package, is_signed_package};
 use libmangrove::pkg::{get_pkg_filename, load_package, Package};
 use libmangrove::platform::Architecture;
-use libmangrove::repo::Repository;
-use libmangrove::trustcache::{trustcache_load, trustcache_save};
+use libmangrove::repo::{KeyRotation, PoolLayout, Repository};
+use libmangrove::sig::{get_sig_path, sign_detached};
 
 use crate::{err, ExecutableCommand, warn};
-use crate::util::info;
+use crate::util::{find_signing_key, info};
 
 #[derive(Parser)]
 #[clap(about = "Generate pool files for a package repository")]
+#[allow(clippy::struct_excessive_bools)] // Every bool is an independent command line flag
 pub struct RepogenCommand {
     #[clap(name = "input", value_parser, help = "The folder to get unsigned package files from")]
     input: PathBuf,
@@ -29,8 +31,18 @@ pub struct RepogenCommand {
     disable_export_index: bool,
     #[clap(name = "local", short = 'l', long = "local", value_parser, help = "Use a local trustcache", action = ArgAction::SetTrue, default_value_t = false)]
     local: bool,
-    #[clap(name = "key", short = 'k', long = "key", value_parser, help  = "Which private key to use. This may also be a prefix of a key, to use a key from a trustcache. If not provided, will use the first key found in the trustcache.")]
-    key: Option<String>
+    #[clap(name = "key", short = 'k', long = "key", value_parser, help  = "Which private key to use. This may also be a prefix of a key, to use a key from a trustcache, or the name or a fingerprint prefix of a key in the key store. If not provided, will use the first key found in the trustcache or the key store.")]
+    key: Option<String>,
+    #[clap(name = "passphrase_file", long = "passphrase-file", value_parser, help = "Read the passphrase of a key from the key store from this file, instead of MANGROVE_PASSPHRASE or asking for it")]
+    passphrase_file: Option<PathBuf>,
+    #[clap(name = "agent", short = 'a', long = "agent", value_parser, help = "Sign with a key held by the signing agent (see mgve agent), found by --key the same way as in the key store", action = ArgAction::SetTrue, default_value_t = false)]
+    agent: bool,
+    #[clap(name = "detached", short = 'd', long = "detached", value_parser, help = "Publish plain packages with detached signatures (<package>.sig) in the pool, instead of signed packages", action = ArgAction::SetTrue, default_value_t = false)]
+    detached: bool,
+    #[clap(name = "valid_for", long = "valid-for", value_parser, help = "How many days the repository data stays valid for. Clients refuse to sync expired repository data, so the repository has to be regenerated before then. 0 means it never expires", default_value_t = 30)]
+    valid_for: u64,
+    #[clap(name = "rotate_key", long = "rotate-key", value_parser, help = "Rotate the signing key of this repository to this private key, found the same way as --key. The current signing key (--key) signs the rotation, and the repository is signed with the successor from then on, so clients that pinned either key can sync it. Regenerate it with the successor as --key afterwards")]
+    rotate_key: Option<String>
 }
 
 impl ExecutableCommand for RepogenCommand {
@@ -44,110 +56,147 @@ impl ExecutableCommand for RepogenCommand {
             return Ok(());
         }
 
-        // keyfinding logic
-        let mut key: Option<PrivateKey> = None;
-        info("loading trustcache".into());
-        let trustcache = trustcache_load(self.local)?;
-        if let Some(ki) = &self.key {
-            if let Ok(key_i) = PrivateKey::from_anonymous(ki) {
-                if trustcache.keydb.deny_privkeys.contains(ki) {
-                    err("this private key has been explicitly blacklisted".into());
-                    trustcache_save(trustcache, self.local)?;
+        // the serial of the repository data increases every time it is generated, so clients can refuse older data
+        let repodata_path = self.output.join("repodata");
+        let previous = if repodata_path.is_file() {
+            match rmp_serde::from_slice::<Repository>(&fs::read(&repodata_path)?) {
+                Ok(r) => Some(r),
+                Err(e) => {
+                    err(format!("the existing repodata could not be read ({e}), remove it to start over at serial 1"));
                     return Ok(());
-                } else {
-                    info("loaded private key from cli".into());
-                    trustcache_save(trustcache, self.local)?;
-                    key = Some(key_i);
-                }
-            } else {
-                if let Some(key_ik) = trustcache.keydb.known_privkeys.iter().find(|k| k.starts_with(ki)) {
-                    let key_ikc = key_ik.to_owned();
-                    trustcache_save(trustcache, self.local)?;
-                    key = Some(PrivateKey::from_anonymous(&key_ikc)?);
                 }
             }
         } else {
-            if let Some(key_i) = trustcache.keydb.known_privkeys.get(0) {
-                let key_ic = key_i.to_owned();
-                trustcache_save(trustcache, self.local)?;
-                key = Some(PrivateKey::from_anonymous(&key_ic)?);
-            } else {
-                err("no keys available to sign".into());
-            }
-        }
+            None
+        };
+        let serial = previous.as_ref().map_or(1, |r| r.serial + 1);
+        let expires = match self.valid_for {
+            0 => None,
+            days => Some(SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs() + days * 24 * 60 * 60)
+        };
 
-        if let Some(kd) = key {
-            let files = fs::read_dir(&self.input)?;
-            let mut files_to_include: Vec<PathBuf> = vec![];
-            info("enumerating repository contents".into());
-            for file_r in files {
-                let file = file_r?;
-                if let Some(ext) = file.path().extension() {
-                    if ext != "mgve" { continue; }
-
-                    files_to_include.push(file.path());
-                } else {
-                    warn(format!("unable to get extension, skipping"));
-                    continue;
+        // keyfinding logic
+        let current = match find_signing_key(self.key.as_ref(), self.local, self.passphrase_file.as_ref(), self.agent) {
+            Ok(k) => k,
+            Err(e) => {
+                err(format!("{e}"));
+                return Ok(());
+            }
+        };
+        // when rotating, the current key only signs the rotation, and the successor signs everything else
+        let successor = match &self.rotate_key {
+            Some(k) => match find_signing_key(Some(k), self.local, self.passphrase_file.as_ref(), self.agent) {
+                Ok(sk) => Some(sk),
+                Err(e) => {
+                    err(format!("the key to rotate to could not be found ({e})"));
+                    return Ok(());
                 }
+            },
+            None => None
+        };
+        let kd = successor.as_deref().unwrap_or_else(|| current.as_ref());
+        let signing_key = kd.public_key();
+        if successor.is_some() && signing_key.key_data == current.public_key().key_data {
+            err("the key to rotate to is already the signing key".into());
+            return Ok(());
+        }
+        let files = fs::read_dir(&self.input)?;
+        let mut files_to_include: Vec<PathBuf> = vec![];
+        info("enumerating repository contents".into());
+        for file_r in files {
+            let file = file_r?;
+            if let Some(ext) = file.path().extension() {
+                if ext != "mgve" { continue; }
+
+                files_to_include.push(file.path());
+            } else {
+                warn("unable to get extension, skipping".to_string());
             }
+        }
 
-            // create output directory: make pool
-            let pool = self.output.join("pool/");
-            create_dir_all(pool.clone())?;
+        // create output directory: make pool
+        let pool = self.output.join("pool/");
+        create_dir_all(pool.clone())?;
 
-            // process files: sign and write to pool
-            info(format!("processing {} packages", files_to_include.len()));
+        // process files: sign and write to pool
+        info(format!("processing {} packages", files_to_include.len()));
 
-            let mut supported_architectures: Vec<Architecture> = vec![];
-            let mut packages: HashMap<Architecture, Vec<Package>> = HashMap::new();
+        let mut supported_architectures: Vec<Architecture> = vec![];
+        let mut packages: HashMap<Architecture, Vec<Package>> = HashMap::new();
 
-            for f in files_to_include {
-                let data = fs::read(f.clone())?;
-                if is_signed_package(data.clone()) {
-                    warn(format!("skipping already-signed package {}", f.display()));
-                    continue;
-                }
+        for f in files_to_include {
+            let data = fs::read(f.clone())?;
+            if is_signed_package(data.clone()) {
+                warn(format!("skipping already-signed package {}", f.display()));
+                continue;
+            }
 
-                let pkg = load_package(&data)?;
+            let pkg = load_package(&data)?;
 
-                if !supported_architectures.contains(&pkg.arch) { supported_architectures.push((&pkg).arch.clone()) }
-                if packages.get(&pkg.arch).is_none() { packages.insert(pkg.arch.clone(), vec![] ); }
+            if !supported_architectures.contains(&pkg.arch) { supported_architectures.push(pkg.arch.clone()) }
+            if !packages.contains_key(&pkg.arch) { packages.insert(pkg.arch.clone(), vec![] ); }
 
-                if let Some(pkgarr) = packages.get(&pkg.arch) {
-                    let mut pkar = pkgarr.clone();
-                    pkar.push(pkg.clone());
-                    packages.insert((&pkg.arch).clone(), pkar);
-                } else {
-                    return Err("md missing vinbinfo".into());
-                }
+            if let Some(pkgarr) = packages.get(&pkg.arch) {
+                let mut pkar = pkgarr.clone();
+                pkar.push(pkg.clone());
+                packages.insert(pkg.arch.clone(), pkar);
+            } else {
+                return Err("md missing vinbinfo".into());
+            }
 
-                // sign the package
-                info(format!("signing {}", f.display()));
+            // sign the package
+            info(format!("signing {}", f.display()));
 
-                let enc_data = encrypt_package(&kd, &data)?;
-                let outfile = (&pool).clone().join(get_pkg_filename(&pkg));
+            let outfile = pool.join(get_pkg_filename(&pkg));
+            if self.detached {
+                fs::write(get_sig_path(&outfile), sign_detached(kd, &data)?.to_bytes())?;
+                fs::write(outfile, data)?;
+            } else {
+                let enc_data = encrypt_package(kd, &data)?;
                 fs::write(outfile, enc_data)?;
             }
+        }
 
-            let repo = Repository {
-                baseurl: (&self.baseurl).clone(),
-                signing_key: kd.derive(),
-                avaliable_architectures: supported_architectures,
-                packages
-            };
+        // every rotation is kept, so clients that missed one can still follow the keys from the key they pinned
+        let mut key_rotations = previous.as_ref().map_or(vec![], |r| r.key_rotations.clone());
+        if successor.is_some() {
+            info(format!("announcing {} as the successor of {}", signing_key.fingerprint(), current.public_key().fingerprint()));
+            key_rotations.push(KeyRotation::new(current.as_ref(), &signing_key)?);
+        }
+        if let Some(p) = &previous {
+            let known = key_rotations.iter().any(|x| x.successor.key_data == signing_key.key_data);
+            if p.signing_key.key_data != signing_key.key_data && !known {
+                warn(format!("the repository was signed by {}, which has not been rotated to this key, clients will refuse to sync it", p.signing_key.fingerprint()));
+            }
+        }
 
-            info("writing repodata".into());
-            fs::write(self.output.join("repodata"), rmp_serde::to_vec(&repo)?)?;
+        let repo = Repository {
+            baseurl: self.baseurl.clone(),
+            signing_key: signing_key.clone(),
+            avaliable_architectures: supported_architectures,
+            packages,
+            pool_layout: if self.detached { PoolLayout::Detached } else { PoolLayout::Signed },
+            serial,
+            expires,
+            key_rotations
+        };
+
+        info(format!("writing repodata, serial {serial}"));
+        let repodata = rmp_serde::to_vec(&repo)?;
+        fs::write(self.output.join("repodata.sig"), sign_detached(kd, &repodata)?.to_bytes())?;
+        fs::write(repodata_path, repodata)?;
+        if successor.is_some() {
+            info(format!("repodata signed with the successor {}, clients that pinned {} follow the rotation to it", signing_key.fingerprint(), current.public_key().fingerprint()));
+            info("from now on, regenerate the repository with the successor as --key".into());
+        } else {
+            info(format!("repodata signed with key {}, clients must pin this key", signing_key.fingerprint()));
+        }
 
-            if self.disable_export_index {
-                info("skipping index.json".into());
-            } else {
-                info("exporting index.json".into());
-                fs::write(self.output.join("index.json"), serde_json::to_string(&repo)?)?;
-            }
+        if self.disable_export_index {
+            info("skipping index.json".into());
         } else {
-            err("no keys avaliable to sign".into());
+            info("exporting index.json".into());
+            fs::write(self.output.join("index.json"), serde_json::to_string(&repo)?)?;
         }
 
         Ok(())
@@ -3,11 +3,11 @@ use std::path::PathBuf;
 
 use clap::{ArgAction, Parser};
 
-use libmangrove::crypt::PrivateKey;
-use libmangrove::trustcache::{trustcache_load, trustcache_save};
+use libmangrove::crypt::SpfVersion;
+use libmangrove::sig::get_sig_path;
 
 use crate::{err, ExecutableCommand};
-use crate::util::{info, sign_pkg};
+use crate::util::{find_signing_key, info, sign_pkg, sign_pkg_detached};
 
 #[derive(Parser)]
 #[clap(name = "sign", about = "Taking an unsigned package, sign it using the provided private key", version, author)]
@@ -15,12 +15,24 @@ pub struct SignCommand {
     #[clap(name = "file", value_parser, help = "Unsigned package file to sign")]
     pub file: PathBuf,
 
-    #[clap(name = "key", short = 'k', long = "key", help = "Which private key to use. This may also be a prefix of a key, to use a key from a trustcache. If not provided, will use the first key found in the trustcache.", value_parser)]
+    #[clap(name = "key", short = 'k', long = "key", help = "Which private key to use. This may also be a prefix of a key, to use a key from a trustcache, or the name or a fingerprint prefix of a key in the key store. If not provided, will use the first key found in the trustcache or the key store.", value_parser)]
     pub key: Option<String>,
 
-    #[clap(name = "output", short = 'o', long = "output", help = "The file to output the signed package to. Defaults to the same file as the unsigned package.", value_parser)]
+    #[clap(name = "passphrase_file", long = "passphrase-file", help = "Read the passphrase of a key from the key store from this file, instead of MANGROVE_PASSPHRASE or asking for it", value_parser)]
+    pub passphrase_file: Option<PathBuf>,
+
+    #[clap(name = "agent", short = 'a', long = "agent", help = "Sign with a key held by the signing agent (see mgve agent), found by --key the same way as in the key store", action = ArgAction::SetTrue, default_value_t = false, value_parser)]
+    pub agent: bool,
+
+    #[clap(name = "output", short = 'o', long = "output", help = "The file to output the signed package to. Defaults to the same file as the unsigned package, or <file>.sig with --detached.", value_parser)]
     pub output_file: Option<PathBuf>,
 
+    #[clap(name = "format", short = 'f', long = "format", help = "Which version of the signed package format to produce, v2 or legacy. Only use legacy for systems that cannot read v2 packages yet.", default_value_t = SpfVersion::V2, value_parser)]
+    pub format: SpfVersion,
+
+    #[clap(name = "detached", short = 'd', long = "detached", help = "Leave the package as it is, and write a detached signature for it instead", action = ArgAction::SetTrue, default_value_t = false, value_parser)]
+    pub detached: bool,
+
     #[clap(name = "local", short = 'l', long = "local", help = "Use a local trustcache", action = ArgAction::SetTrue, default_value_t = false, value_parser)]
     pub local: bool
 }
@@ -29,52 +41,24 @@ impl ExecutableCommand for SignCommand {
     fn execute(&self) -> Result<(), Box<dyn Error>> {
         let infile = &self.file;
 
-        let outfile = match &self.output_file {
-            Some(o) => o,
-            None => infile
-        };
-
         // keyfinding logic
-        let mut key: Option<PrivateKey> = None;
-        info("loading trustcache".into());
-        let trustcache = trustcache_load(self.local)?;
-        if let Some(ki) = &self.key {
-            if let Ok(key_i) = PrivateKey::from_anonymous(ki) {
-                if trustcache.keydb.deny_privkeys.contains(ki) {
-                    err("this private key has been explicitly blacklisted".into());
-                    trustcache_save(trustcache, self.local)?;
-                    return Ok(());
-                } else {
-                    info("loaded private key from cli".into());
-                    trustcache_save(trustcache, self.local)?;
-                    key = Some(key_i);
-                }
-            } else {
-                if let Some(key_ik) = trustcache.keydb.known_privkeys.iter().find(|k| k.starts_with(ki)) {
-                    let key_ikc = key_ik.to_owned();
-                    trustcache_save(trustcache, self.local)?;
-                    key = Some(PrivateKey::from_anonymous(&key_ikc)?);
-                }
+        let kd = match find_signing_key(self.key.as_ref(), self.local, self.passphrase_file.as_ref(), self.agent) {
+            Ok(k) => k,
+            Err(e) => {
+                err(format!("{e}"));
+                return Ok(());
             }
+        };
+        if self.detached {
+            let outfile = self.output_file.clone().unwrap_or_else(|| get_sig_path(infile));
+            info(format!("creating detached signature {}", outfile.display()));
+            sign_pkg_detached(infile, &outfile, kd.as_ref())?;
         } else {
-            if let Some(key_i) = trustcache.keydb.known_privkeys.get(0) {
-                let key_ic = key_i.to_owned();
-                trustcache_save(trustcache, self.local)?;
-                key = Some(PrivateKey::from_anonymous(&key_ic)?);
-            } else {
-                err("no keys available to sign".into());
-            }
-        }
-
-        info("creating encrypted package file".into());
-
-        if let Some(kd) = key {
-            sign_pkg(infile, outfile, &kd)?;
-        } else {
-            err("no keys available to sign".into());
+            let outfile = self.output_file.as_ref().unwrap_or(infile);
+            info(format!("creating encrypted package file ({} format)", self.format));
+            sign_pkg(infile, outfile, kd.as_ref(), self.format)?;
         }
 
-
         Ok(())
     }
 }
\ No newline at end of file
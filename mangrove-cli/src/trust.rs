@@ -1,13 +1,20 @@
 use std::error::Error;
+use std::fs;
+use std::io::{stdout, Write};
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 use clap::{ArgAction, Parser, Subcommand};
 use colored::Colorize;
+use tabwriter::TabWriter;
 
 use libmangrove::crypt::{PrivateKey, PublicKey};
-use libmangrove::trustcache::{allow_pk, allow_sk, clear_pk, clear_sk, deny_pk, deny_sk, is_pk_blacklisted, is_pk_trusted, is_sk_blacklisted, is_sk_trusted, trustcache_load, trustcache_save};
+use libmangrove::keyformat::{parse_key, ParsedKey};
+use libmangrove::keystore::{EncryptedKey, KdfParams, keystore_load, keystore_save};
+use libmangrove::trustcache::{allow_pk, clear_pk, clear_sk, deny_pk, deny_sk, import_revocation, is_pk_blacklisted, is_pk_trusted, is_sk_blacklisted, is_sk_trusted, key_metadata, RevocationCertificate, set_key_metadata, set_pk_validity, Trustcache, trustcache_load, trustcache_save};
 
 use crate::{err, ExecutableCommand};
-use crate::util::info;
+use crate::util::{format_date, get_passphrase, info};
 
 #[derive(Parser)]
 #[clap(name = "trust", about = "Manage the Mangrove trustcache", version, author)]
@@ -25,26 +32,45 @@ pub enum TrustCommandOptions {
     #[clap(name = "clear")]
     Clear(TrustCommandClear),
     #[clap(name = "query")]
-    Query(TrustCommandQuery)
+    Query(TrustCommandQuery),
+    #[clap(name = "list")]
+    List(TrustCommandList),
+    #[clap(name = "revoke")]
+    Revoke(TrustCommandRevoke),
+    #[clap(name = "import-revocation")]
+    ImportRevocation(TrustCommandImportRevocation)
 }
 
 #[derive(Parser)]
-#[clap(about = "Allow a public or private key in the trustcache")]
+#[clap(about = "Allow a public or private key in the trustcache. Private keys are encrypted into the key store, and their public key is allowed")]
 pub struct TrustCommandAllow {
+    #[clap(help = "The key, or a file holding it: an anonymous key, an OpenSSH public or private key, or a PKCS#8 or SPKI PEM file")]
     pub key: String,
+    #[clap(long = "passphrase-file", value_parser, help = "Read the passphrase to encrypt a private key with from this file, instead of MANGROVE_PASSPHRASE or asking for it")]
+    pub passphrase_file: Option<PathBuf>,
+    #[clap(short = 'n', long = "name", help = "A short name for the key, such as the name of its owner")]
+    pub name: Option<String>,
+    #[clap(short = 'c', long = "comment", help = "A comment about the key")]
+    pub comment: Option<String>,
+    #[clap(long = "not-before", help = "Only trust the key from this unix timestamp on")]
+    pub not_before: Option<u64>,
+    #[clap(long = "not-after", help = "Stop trusting the key at this unix timestamp")]
+    pub not_after: Option<u64>,
     #[clap(short = 'l', long = "local", action = ArgAction::SetTrue, default_value_t = false, help = "Use a local trustcache instead of the default system-wide one")]
     pub local: bool
 }
 #[derive(Parser)]
 #[clap(about = "Blacklist a public or private key in the trustcache")]
 pub struct TrustCommandDeny {
+    #[clap(help = "The key, or a file holding it")]
     pub key: String,
     #[clap(short = 'l', long = "local", action = ArgAction::SetTrue, default_value_t = false, help = "Use a local trustcache instead of the default system-wide one")]
     pub local: bool
 }
 #[derive(Parser)]
-#[clap(about = "Remove a public or private key from the allow/blocklists if it is present in either")]
+#[clap(about = "Remove a public or private key from the allow/blocklists if it is present in either, and a private key from the key store")]
 pub struct TrustCommandClear {
+    #[clap(help = "The key, or a file holding it")]
     pub key: String,
     #[clap(short = 'l', long = "local", action = ArgAction::SetTrue, default_value_t = false, help = "Use a local trustcache instead of the default system-wide one")]
     pub local: bool
@@ -52,10 +78,37 @@ pub struct TrustCommandClear {
 #[derive(Parser)]
 #[clap(about = "Query the current state of a key")]
 pub struct TrustCommandQuery {
+    #[clap(help = "The key, or a file holding it")]
     pub key: String,
     #[clap(short = 'l', long = "local", action = ArgAction::SetTrue, default_value_t = false, help = "Use a local trustcache instead of the default system-wide one")]
     pub local: bool
 }
+#[derive(Parser)]
+#[clap(about = "List the keys in the trustcache")]
+pub struct TrustCommandList {
+    #[clap(short = 'l', long = "local", action = ArgAction::SetTrue, default_value_t = false, help = "Use a local trustcache instead of the default system-wide one")]
+    pub local: bool
+}
+#[derive(Parser)]
+#[clap(about = "Create a revocation certificate for a private key, which can be imported into any trustcache to stop trusting the key")]
+pub struct TrustCommandRevoke {
+    #[clap(help = "The private key, or a file holding it")]
+    pub key: String,
+    #[clap(short = 'r', long = "reason", default_value = "", help = "Why the key is being revoked, such as compromised or superseded")]
+    pub reason: String,
+    #[clap(short = 't', long = "at", help = "The unix timestamp the key is revoked from. Defaults to now")]
+    pub revoked_at: Option<u64>,
+    #[clap(short = 'o', long = "output", value_parser, help = "The file to write the certificate to. Defaults to <fingerprint>.rev")]
+    pub output: Option<PathBuf>
+}
+#[derive(Parser)]
+#[clap(about = "Import a revocation certificate into the trustcache")]
+pub struct TrustCommandImportRevocation {
+    #[clap(value_parser)]
+    pub file: PathBuf,
+    #[clap(short = 'l', long = "local", action = ArgAction::SetTrue, default_value_t = false, help = "Use a local trustcache instead of the default system-wide one")]
+    pub local: bool
+}
 
 impl ExecutableCommand for TrustCommand {
     fn execute(&self) -> Result<(), Box<dyn Error>> {
@@ -63,7 +116,10 @@ impl ExecutableCommand for TrustCommand {
             TrustCommandOptions::Allow(allow) => allow.execute()?,
             TrustCommandOptions::Deny(deny) => deny.execute()?,
             TrustCommandOptions::Clear(clear) => clear.execute()?,
-            TrustCommandOptions::Query(query) => query.execute()?
+            TrustCommandOptions::Query(query) => query.execute()?,
+            TrustCommandOptions::List(list) => list.execute()?,
+            TrustCommandOptions::Revoke(revoke) => revoke.execute()?,
+            TrustCommandOptions::ImportRevocation(import) => import.execute()?
         }
         Ok(())
     }
@@ -74,29 +130,60 @@ impl ExecutableCommand for TrustCommandAllow {
         let mut trustcache = trustcache_load(self.local)?;
         info(format!("adding {} to the trustcache", self.key.blue()));
         // Attempt to determine what the key is
-        if let Ok(sk) = PrivateKey::from_anonymous(&self.key) {
-            if is_sk_trusted(&trustcache, &sk)? {
-                info(format!("{} is already trusted", self.key.blue()));
-                trustcache_save(trustcache, self.local)?;
-                return Ok(());
-            }
-            allow_sk(&mut trustcache, &sk)?;
-            trustcache_save(trustcache, self.local)?;
-            info(format!("added new private key {} to the trustcache", self.key.blue()));
-            return Ok(());
-        } else if let Ok(pk) = PublicKey::from_anonymous(&self.key) {
-            if is_pk_trusted(&trustcache, &pk)? {
-                info(format!("{} is already trusted", self.key.blue()));
+        let pk = match read_key(&self.key) {
+            Ok(ParsedKey::Private(mut sk)) => {
+                // private keys are kept encrypted in the key store, and only their public key goes into the trustcache
+                if let Some(name) = &self.name {
+                    sk.name.clone_from(name);
+                }
+                let pk = sk.derive();
+                let mut keystore = keystore_load(self.local)?;
+                if keystore.keys.iter().any(|x| x.key == pk.to_anonymous()) {
+                    info(format!("{} is already in the key store", self.key.blue()));
+                } else {
+                    let passphrase = match get_passphrase(self.passphrase_file.as_ref(), "Passphrase to encrypt the key with:", true) {
+                        Ok(p) => p,
+                        Err(e) => {
+                            err(format!("{e}, no changes made"));
+                            trustcache_save(trustcache, self.local)?;
+                            return Ok(());
+                        }
+                    };
+                    keystore.add(EncryptedKey::encrypt(&sk, passphrase.as_bytes(), KdfParams::default())?);
+                    keystore_save(&keystore, self.local)?;
+                    info(format!("added new private key {} to the key store", self.key.blue()));
+                }
+                if is_pk_trusted(&trustcache, &pk)?.is_trusted() {
+                    info(format!("{} is already trusted", pk.to_anonymous().blue()));
+                } else {
+                    allow_pk(&mut trustcache, &pk)?;
+                    info(format!("added its public key {} to the trustcache", pk.to_anonymous().blue()));
+                }
+                pk
+            },
+            Ok(ParsedKey::Public(pk)) => {
+                if is_pk_trusted(&trustcache, &pk)?.is_trusted() {
+                    info(format!("{} is already trusted", self.key.blue()));
+                } else {
+                    allow_pk(&mut trustcache, &pk)?;
+                    info(format!("added new public key {} to the trustcache", self.key.blue()));
+                }
+                pk
+            },
+            Err(e) => {
+                err(format!("could not interpret {} as a public or private key ({e}), no changes made", self.key.blue()));
                 trustcache_save(trustcache, self.local)?;
                 return Ok(());
             }
-            allow_pk(&mut trustcache, &pk)?;
-            trustcache_save(trustcache, self.local)?;
-            info(format!("added new public key {} to the trustcache", self.key.blue()));
-        } else {
-            err(format!("could not interpret {} as a public or private key, no changes made", self.key.blue()));
-            trustcache_save(trustcache, self.local)?;
+        };
+        if self.name.is_some() || self.comment.is_some() {
+            set_key_metadata(&mut trustcache, &pk, self.name.as_deref(), self.comment.as_deref());
+        }
+        if self.not_before.is_some() || self.not_after.is_some() {
+            set_pk_validity(&mut trustcache, &pk, self.not_before, self.not_after)?;
+            info(format!("set the validity window of {}", self.key.blue()));
         }
+        trustcache_save(trustcache, self.local)?;
         Ok(())
     }
 }
@@ -106,7 +193,8 @@ impl ExecutableCommand for TrustCommandDeny {
         let mut trustcache = trustcache_load(self.local)?;
         info(format!("blocking {} in the trustcache", self.key.blue()));
         // Attempt to determine what the key is
-        if let Ok(sk) = PrivateKey::from_anonymous(&self.key) {
+        let key = read_key(&self.key);
+        if let Ok(ParsedKey::Private(sk)) = key {
             if is_sk_blacklisted(&trustcache, &sk)? {
                 info(format!("{} is already blacklisted", self.key.blue()));
                 trustcache_save(trustcache, self.local)?;
@@ -116,7 +204,7 @@ impl ExecutableCommand for TrustCommandDeny {
             trustcache_save(trustcache, self.local)?;
             info(format!("added new private key {} to the blacklist", self.key.blue()));
             return Ok(());
-        } else if let Ok(pk) = PublicKey::from_anonymous(&self.key) {
+        } else if let Ok(ParsedKey::Public(pk)) = key {
             if is_pk_blacklisted(&trustcache, &pk)? {
                 info(format!("{} is already blacklisted", self.key.blue()));
                 trustcache_save(trustcache, self.local)?;
@@ -138,12 +226,18 @@ impl ExecutableCommand for TrustCommandClear {
         let mut trustcache = trustcache_load(self.local)?;
         info(format!("clearing {} from the trustcache", self.key.blue()));
         // Attempt to determine what the key is
-        if let Ok(sk) = PrivateKey::from_anonymous(&self.key) {
+        let key = read_key(&self.key);
+        if let Ok(ParsedKey::Private(sk)) = key {
             clear_sk(&mut trustcache, &sk)?;
+            let mut keystore = keystore_load(self.local)?;
+            if keystore.remove(&sk.derive()) {
+                keystore_save(&keystore, self.local)?;
+                info(format!("removed key {} from the key store", self.key.blue()));
+            }
             trustcache_save(trustcache, self.local)?;
             info(format!("removed key {} from the trustcache", self.key.blue()));
             return Ok(());
-        } else if let Ok(pk) = PublicKey::from_anonymous(&self.key) {
+        } else if let Ok(ParsedKey::Public(pk)) = key {
             clear_pk(&mut trustcache, &pk)?;
             trustcache_save(trustcache, self.local)?;
             info(format!("removed key {} from the trustcache", self.key.blue()));
@@ -158,30 +252,99 @@ impl ExecutableCommand for TrustCommandQuery {
     fn execute(&self) -> Result<(), Box<dyn Error>> {
         info("loading the trustcache".into());
         let trustcache = trustcache_load(self.local)?;
-        return if let Ok(sk) = PrivateKey::from_anonymous(&self.key) {
-            if is_sk_trusted(&trustcache, &sk)? {
-                info(format!("{} is trusted", self.key.blue()));
-            } else if is_sk_blacklisted(&trustcache, &sk)? {
-                info(format!("{} is blacklisted", self.key.blue()));
-            } else {
-                info(format!("{} is not present in the trustcache", self.key.blue()));
-            }
-            trustcache_save(trustcache, self.local)?;
-            Ok(())
-        } else if let Ok(pk) = PublicKey::from_anonymous(&self.key) {
-            if is_pk_trusted(&trustcache, &pk)? {
-                info(format!("{} is trusted", self.key.blue()));
-            } else if is_pk_blacklisted(&trustcache, &pk)? {
-                info(format!("{} is blacklisted", self.key.blue()));
-            } else {
-                info(format!("{} is not present in the trustcache", self.key.blue()));
-            }
-            trustcache_save(trustcache, self.local)?;
-            Ok(())
+        let key = read_key(&self.key);
+        let trust = if let Ok(ParsedKey::Private(sk)) = key {
+            is_sk_trusted(&trustcache, &sk)
+        } else if let Ok(ParsedKey::Public(pk)) = key {
+            is_pk_trusted(&trustcache, &pk)
         } else {
             err(format!("could not interpret {} as a public or private key", self.key.blue()));
             trustcache_save(trustcache, self.local)?;
-            Ok(())
+            return Ok(());
+        };
+        trustcache_save(trustcache, self.local)?;
+        info(format!("{} is {}", self.key.blue(), trust?));
+        Ok(())
+    }
+}
+impl ExecutableCommand for TrustCommandList {
+    fn execute(&self) -> Result<(), Box<dyn Error>> {
+        info("loading the trustcache".into());
+        let trustcache = trustcache_load(self.local)?;
+        let res = list_keys(&trustcache);
+        trustcache_save(trustcache, self.local)?;
+        res
+    }
+}
+fn list_keys(trustcache: &Trustcache) -> Result<(), Box<dyn Error>> {
+    let keydb = &trustcache.keydb;
+    let lists = [(&keydb.known_pubkeys, "public"), (&keydb.known_privkeys, "private"), (&keydb.deny_pubkeys, "public"), (&keydb.deny_privkeys, "private")];
+    let mut tw = TabWriter::new(stdout());
+    writeln!(&mut tw, "Name\tFingerprint\tType\tStatus\tAdded\tComment")?;
+    for (list, kind) in lists {
+        for anonymous in list {
+            let (pk, trust) = if kind == "private" {
+                let sk = PrivateKey::from_anonymous(anonymous)?;
+                let trust = is_sk_trusted(trustcache, &sk)?;
+                (sk.derive(), trust)
+            } else {
+                let pk = PublicKey::from_anonymous(anonymous)?;
+                let trust = is_pk_trusted(trustcache, &pk)?;
+                (pk, trust)
+            };
+            let metadata = key_metadata(trustcache, &pk);
+            let name = metadata.map(|x| x.name.as_str()).filter(|x| !x.is_empty()).unwrap_or("-");
+            let added = metadata.and_then(|x| x.added).map_or_else(|| "-".to_string(), format_date);
+            let comment = metadata.map_or("", |x| x.comment.as_str());
+            writeln!(&mut tw, "{name}\t{}\t{kind}\t{trust}\t{added}\t{comment}", &pk.fingerprint()[..16])?;
         }
     }
-}
\ No newline at end of file
+    tw.flush()?;
+    Ok(())
+}
+impl ExecutableCommand for TrustCommandRevoke {
+    fn execute(&self) -> Result<(), Box<dyn Error>> {
+        let Ok(ParsedKey::Private(sk)) = read_key(&self.key) else {
+            err(format!("could not interpret {} as a private key, only the owner of a key can revoke it", self.key.blue()));
+            return Ok(());
+        };
+        let revoked_at = match self.revoked_at {
+            Some(t) => t,
+            None => SystemTime::now().duration_since(SystemTime::UNIX_EPOCH)?.as_secs()
+        };
+        let certificate = RevocationCertificate::new(&sk, revoked_at, &self.reason)?;
+        let output = self.output.clone().unwrap_or_else(|| PathBuf::from(format!("{}.rev", sk.derive().fingerprint())));
+        fs::write(&output, certificate.to_bytes())?;
+        info(format!("wrote the revocation certificate for {} to {}", sk.derive().fingerprint().blue(), output.display()));
+        info("keep it somewhere safe, anyone who imports it will stop trusting the key".into());
+        Ok(())
+    }
+}
+impl ExecutableCommand for TrustCommandImportRevocation {
+    fn execute(&self) -> Result<(), Box<dyn Error>> {
+        let data = fs::read(&self.file)?;
+        info("loading the trustcache".into());
+        let mut trustcache = trustcache_load(self.local)?;
+        let key = match import_revocation(&mut trustcache, &data) {
+            Ok(k) => k,
+            Err(e) => {
+                trustcache_save(trustcache, self.local)?;
+                return Err(e);
+            }
+        };
+        trustcache_save(trustcache, self.local)?;
+        info(format!("imported the revocation of {}, it will no longer be trusted", key.to_anonymous().blue()));
+        Ok(())
+    }
+}
+
+// read_key
+// Read a key given on the command line, either as it is or as a path to a file holding the key, in any format parse_key understands
+pub fn read_key(key: &str) -> Result<ParsedKey, Box<dyn Error>> {
+    let path = Path::new(key);
+    if path.is_file() {
+        parse_key(&fs::read_to_string(path)?)
+    } else {
+        parse_key(key)
+    }
+}
@@ -0,0 +1,140 @@
+use std::error::Error;
+use std::io::{Read, stdin, stdout, Write};
+
+use clap::{ArgAction, Parser};
+
+use libmangrove::pkgdb::{pkgdb_load, pkgdb_save};
+use libmangrove::platform::host_arch;
+use libmangrove::resolver::resolve_upgrades;
+use libmangrove::transaction::MANGROVE_PKGNAME;
+
+use crate::{err, ExecutableCommand};
+use crate::install::{build_transaction, download_packages, print_transaction, remove_downloads, sync_configured};
+use crate::util::{info, warn};
+
+#[derive(Parser)]
+#[clap(name = "upgrade", about = "Upgrade installed packages to the newest versions in the synced repositories", version, author)]
+#[allow(clippy::struct_excessive_bools)] // Every bool is an independent command line flag
+pub struct UpgradeCommand {
+    #[clap(name = "sync", short = 'S', long = "sync", help = "Sync remote repositories before upgrading", action = ArgAction::SetTrue, default_value_t = false)]
+    pub sync: bool,
+
+    #[clap(name = "ignore", short = 'i', long = "ignore", help = "Do not upgrade or replace this package. Can be given more than once", action = ArgAction::Append)]
+    pub ignore: Vec<String>,
+
+    #[clap(name = "dry-run", short = 'n', long = "dry-run", help = "Only list the packages that would be upgraded", action = ArgAction::SetTrue, default_value_t = false)]
+    pub dry_run: bool,
+
+    #[clap(name = "target", short = 'T', long = "target", help = "Installation target rootfs. Defaults to /.", default_value_t = String::from("/"))]
+    pub target: String,
+
+    #[clap(name = "local", short = 'l', long = "local", help = "Use a local database file", action = ArgAction::SetTrue, default_value_t = false)]
+    pub local: bool,
+
+    #[clap(name = "allow-stale", long = "allow-stale", help = "When syncing, accept repository data that is older than the synced data or has expired. This can hide updates, only use it if you know why the repository data is stale", action = ArgAction::SetTrue, default_value_t = false)]
+    pub allow_stale: bool
+}
+
+impl ExecutableCommand for UpgradeCommand {
+    fn execute(&self) -> Result<(), Box<dyn Error>> {
+        if self.sync {
+            sync_configured(self.local, self.allow_stale)?;
+        }
+
+        info("loading package database...".into());
+        let mut pkgdb = pkgdb_load(self.local)?;
+        for pkgname in &self.ignore {
+            if !pkgdb.db.installed_packages.iter().any(|x| &x.pkgname == pkgname) {
+                warn(format!("{pkgname} is ignored, but it is not installed"));
+            }
+        }
+
+        if !pkgdb.db.held_packages.is_empty() {
+            info(format!("skipping held packages: {}", pkgdb.db.held_packages.join(", ")));
+        }
+
+        let Some(arch) = host_arch() else {
+            err("this architecture is not supported by mangrove repositories".into());
+            pkgdb_save(pkgdb, self.local)?;
+            return Ok(());
+        };
+
+        println!("Resolving upgrades...");
+        let resolution = match resolve_upgrades(&self.ignore, &pkgdb.db, &arch) {
+            Ok(r) => r,
+            Err(e) => {
+                for problem in e.to_string().lines() {
+                    err(problem.to_string());
+                }
+                err("the installed packages cannot be upgraded, use -i to ignore packages".into());
+                pkgdb_save(pkgdb, self.local)?;
+                return Ok(());
+            }
+        };
+        if resolution.install.is_empty() && resolution.replaced.is_empty() {
+            info("all packages are up to date".into());
+            pkgdb_save(pkgdb, self.local)?;
+            return Ok(());
+        }
+
+        println!("Building transaction...");
+        let mut transaction = build_transaction(&resolution, &pkgdb.db);
+        if transaction.actions.len() > 1 && transaction.actions.iter().any(|x| x.pkgname() == MANGROVE_PKGNAME) {
+            warn(format!("{MANGROVE_PKGNAME} must be upgraded on its own, run mgve upgrade again afterwards to upgrade everything else"));
+            transaction.actions.retain(|x| x.pkgname() == MANGROVE_PKGNAME);
+        }
+
+        println!("Checking transaction...");
+        if let Err(e) = transaction.validate(&pkgdb.db) {
+            for problem in e.to_string().lines() {
+                err(problem.to_string());
+            }
+            err("please resolve these problems first".into());
+            pkgdb_save(pkgdb, self.local)?;
+            return Ok(());
+        }
+        println!();
+
+        println!("To upgrade:");
+        print_transaction(&transaction, &resolution, &pkgdb.db)?;
+
+        if self.dry_run {
+            pkgdb_save(pkgdb, self.local)?;
+            return Ok(());
+        }
+
+        print!("Continue with upgrade: [Y/n] ");
+        let _=stdout().flush();
+
+        let mut c: [u8; 1] = [0];
+        stdin().read_exact(&mut c)?;
+        let c = c[0] as char;
+        if c == 'n' || c == 'N' {
+            println!("Aborted by user");
+            pkgdb_save(pkgdb, self.local)?;
+            return Ok(());
+        }
+
+        let downloaded = match download_packages(&mut transaction, &resolution, &pkgdb.db, self.local) {
+            Ok(d) => d,
+            Err(e) => {
+                err(e.to_string());
+                err("please resolve these problems first".into());
+                pkgdb_save(pkgdb, self.local)?;
+                return Ok(());
+            }
+        };
+        println!("Upgrading packages...");
+
+        let result = transaction.apply(self.target.clone(), &mut pkgdb);
+        remove_downloads(&downloaded);
+        if let Err(e) = result {
+            err(format!("error applying transaction: {e}"));
+            pkgdb_save(pkgdb, self.local)?;
+            return Ok(())
+        }
+        pkgdb_save(pkgdb, self.local)?;
+
+        Ok(())
+    }
+}
@@ -1,10 +1,18 @@
+use std::env;
 use std::error::Error;
-use std::fs;
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter};
 use std::path::PathBuf;
 
 use colored::Colorize;
+use inquire::Password;
 
-use libmangrove::crypt::{encrypt_package, PrivateKey};
+use libmangrove::agent::{AgentClient, AgentKey};
+use libmangrove::config::get_agent_socket;
+use libmangrove::crypt::{encrypt_package_as, encrypt_package_stream, KeySigner, PrivateKey, SpfVersion};
+use libmangrove::keystore::{EncryptedKey, keystore_load};
+use libmangrove::sig::sign_detached_stream;
+use libmangrove::trustcache::{is_pk_trusted, is_sk_trusted, KeyTrust, trustcache_load, trustcache_save};
 
 // info, warn, err
 
@@ -20,11 +28,137 @@ pub fn err(text: String) {
     println!("{} {}", "err:".bold().red(), text.bold());
 }
 
-pub fn sign_pkg(file: &PathBuf, out: &PathBuf, key: &PrivateKey) -> Result<(), Box<dyn Error>> {
+pub fn sign_pkg(file: &PathBuf, out: &PathBuf, key: &dyn KeySigner, version: SpfVersion) -> Result<(), Box<dyn Error>> {
+    if version == SpfVersion::V2 {
+        // v2 packages are signed a chunk at a time, without reading the whole package into memory.
+        // The output may be the input file, so it is written next to it and moved into place.
+        let mut staged = out.clone().into_os_string();
+        staged.push(".spf");
+        encrypt_package_stream(key, File::open(file)?, BufWriter::new(File::create(&staged)?))?;
+        fs::rename(&staged, out)?;
+        return Ok(());
+    }
     let data = fs::read(file)?;
-    let out_data = encrypt_package(key, &data)?;
+    let out_data = encrypt_package_as(key, &data, version)?;
 
     fs::write(out, out_data)?;
 
     Ok(())
-}
\ No newline at end of file
+}
+
+pub fn sign_pkg_detached(file: &PathBuf, out: &PathBuf, key: &dyn KeySigner) -> Result<(), Box<dyn Error>> {
+    let signature = sign_detached_stream(key, BufReader::new(File::open(file)?))?;
+    fs::write(out, signature.to_bytes())?;
+
+    Ok(())
+}
+
+// format_date
+// Format a unix timestamp as a UTC date, YYYY-MM-DD
+#[allow(clippy::cast_possible_wrap)] // Timestamps are nowhere near i64::MAX
+pub fn format_date(timestamp: u64) -> String {
+    // days since 1970-01-01 to a civil date, see http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let days = (timestamp / 86400) as i64 + 719_468;
+    let era = days.div_euclid(146_097);
+    let doe = days.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!("{year:04}-{month:02}-{day:02}")
+}
+
+// get_passphrase
+// Get a key store passphrase from a file, the MANGROVE_PASSPHRASE environment variable, or by asking for it, in that order.
+// New passphrases are asked for twice, and must not be empty.
+pub fn get_passphrase(passphrase_file: Option<&PathBuf>, prompt: &str, new: bool) -> Result<String, Box<dyn Error>> {
+    let passphrase = if let Some(file) = passphrase_file {
+        fs::read_to_string(file)?.trim_end_matches(&['\r', '\n'][..]).to_string()
+    } else if let Ok(passphrase) = env::var("MANGROVE_PASSPHRASE") {
+        passphrase
+    } else {
+        let passphrase = Password::new(prompt).prompt()?;
+        if new && Password::new("Repeat the passphrase:").prompt()? != passphrase {
+            return Err("the passphrases do not match".into());
+        }
+        passphrase
+    };
+    if new && passphrase.is_empty() {
+        return Err("the passphrase must not be empty".into());
+    }
+    Ok(passphrase)
+}
+
+// SigningKey
+// A private key found to sign with, which may still have to be decrypted, or a key held by the signing agent
+enum SigningKey {
+    Plain(PrivateKey),
+    Encrypted(EncryptedKey),
+    Agent(AgentKey)
+}
+
+// find_signing_key
+// Find the private key to sign with, for sign and repogen. The key may be given as a private key, or looked up by a prefix of a
+// private key in the trustcache, or by the name, fingerprint prefix or public key prefix of a key in the key store.
+// Without a key, the first key in the trustcache or the key store is used. Keys from the key store are decrypted with their passphrase.
+// With use_agent, the key is looked up the same way among the keys the signing agent holds, and the agent signs with it.
+pub fn find_signing_key(key: Option<&String>, local: bool, passphrase_file: Option<&PathBuf>, use_agent: bool) -> Result<Box<dyn KeySigner>, Box<dyn Error>> {
+    info("loading trustcache".into());
+    let trustcache = trustcache_load(local)?;
+    let found = (|| -> Result<SigningKey, Box<dyn Error>> {
+        let keystore = keystore_load(local)?;
+        let found = if use_agent {
+            let socket = get_agent_socket(local);
+            info(format!("using the signing agent at {socket}"));
+            SigningKey::Agent(AgentClient::connect(&socket)?.key(key.map(String::as_str))?)
+        } else {
+            match key {
+                Some(k) => if let Ok(sk) = PrivateKey::from_anonymous(k) {
+                    info("loaded private key from cli".into());
+                    warn("private keys given on the command line show up in shell history and process listings, consider importing it into the key store".into());
+                    SigningKey::Plain(sk)
+                } else if let Some(sk) = trustcache.keydb.known_privkeys.iter().find(|x| x.starts_with(k.as_str())) {
+                    SigningKey::Plain(PrivateKey::from_anonymous(sk)?)
+                } else if let Some(entry) = keystore.find(k) {
+                    SigningKey::Encrypted(entry.clone())
+                } else {
+                    return Err(format!("no key matching {k} in the trustcache or the key store").into());
+                },
+                None => if let Some(sk) = trustcache.keydb.known_privkeys.first() {
+                    SigningKey::Plain(PrivateKey::from_anonymous(sk)?)
+                } else if let Some(entry) = keystore.keys.first() {
+                    SigningKey::Encrypted(entry.clone())
+                } else {
+                    return Err("no keys available to sign".into());
+                }
+            }
+        };
+        let trust = match &found {
+            SigningKey::Plain(sk) => {
+                if trustcache.keydb.known_privkeys.contains(&sk.to_anonymous()) {
+                    warn("this private key is stored in plaintext in the trustcache, run mgve key migrate to encrypt it".into());
+                }
+                is_sk_trusted(&trustcache, sk)?
+            },
+            SigningKey::Encrypted(entry) => is_pk_trusted(&trustcache, &entry.public_key()?)?,
+            SigningKey::Agent(agent) => is_pk_trusted(&trustcache, &agent.public_key())?
+        };
+        // keys that are not in the trustcache may be used, keys that it refuses may not
+        if !matches!(trust, KeyTrust::Trusted | KeyTrust::Unknown) {
+            return Err(format!("this private key is {trust}").into());
+        }
+        Ok(found)
+    })();
+    trustcache_save(trustcache, local)?;
+    match found? {
+        SigningKey::Plain(sk) => Ok(Box::new(sk)),
+        SigningKey::Encrypted(entry) => {
+            let name = if entry.name.is_empty() { &entry.fingerprint } else { &entry.name };
+            let passphrase = get_passphrase(passphrase_file, &format!("Passphrase for {name}:"), false)?;
+            Ok(Box::new(entry.decrypt(passphrase.as_bytes())?))
+        },
+        SigningKey::Agent(agent) => Ok(Box::new(agent))
+    }
+}